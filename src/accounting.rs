@@ -0,0 +1,479 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, Days, Local, Months, NaiveDate};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::UserNameLabel;
+use crate::nvml_metrics::{cards_by_user, NvmlUserUtilization};
+
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccountingConfig {
+    /// File keeping the totals and the daily usage across restarts
+    pub state_file: PathBuf,
+    /// Seconds between two saves of the state file
+    pub save_interval: f64,
+    /// Days of daily usage kept for the export, totals are kept forever
+    pub retention_days: u64,
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        AccountingConfig {
+            state_file: PathBuf::from("/var/lib/syswatch/accounting.toml"),
+            save_interval: 300.,
+            retention_days: 400,
+        }
+    }
+}
+
+/// GPU usage of a user integrated over time
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct Usage {
+    pub gpu_seconds: f64,
+    pub memory_byte_seconds: f64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.gpu_seconds += other.gpu_seconds;
+        self.memory_byte_seconds += other.memory_byte_seconds;
+    }
+}
+
+/// Content of the state file
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct State {
+    /// By user name
+    totals: BTreeMap<String, Usage>,
+    /// By local date, then user name
+    days: BTreeMap<String, BTreeMap<String, Usage>>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    /// Weeks start on Monday
+    Week,
+    Month,
+    /// A single bucket over the whole range
+    Total,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccountingQuery {
+    /// First day included, `YYYY-MM-DD`
+    pub from: Option<String>,
+    /// Last day included, `YYYY-MM-DD`
+    pub to: Option<String>,
+    #[serde(default)]
+    pub bucket: Bucket,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// Usage of a user over a bucket, clipped to the requested range
+#[derive(Serialize, Debug)]
+pub struct AccountingRow {
+    pub start: String,
+    pub end: String,
+    pub user_name: String,
+    pub gpu_seconds: f64,
+    pub memory_byte_seconds: f64,
+}
+
+/// Cumulative usage of the users, shared with the HTTP handlers
+pub struct Accounting {
+    pub gpu_seconds: Family<UserNameLabel, Counter<f64, AtomicU64>>,
+    pub memory_byte_seconds: Family<UserNameLabel, Counter<f64, AtomicU64>>,
+    state_file: PathBuf,
+    retention_days: u64,
+    state: Mutex<State>,
+}
+
+impl Accounting {
+    /// Restores the counters from the state file, a missing file starts from zero
+    pub fn load(config: &AccountingConfig) -> Result<Accounting> {
+        let path = config.state_file.to_string_lossy();
+        let state: State = match std::fs::read_to_string(&config.state_file) {
+            Ok(content) => toml::from_str(&content).with_context(|| format!("Parsing {}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path)),
+        };
+        let accounting = Accounting {
+            gpu_seconds: Family::default(),
+            memory_byte_seconds: Family::default(),
+            state_file: config.state_file.clone(),
+            retention_days: config.retention_days,
+            state: Mutex::new(State::default()),
+        };
+        for (user_name, usage) in state.totals.iter() {
+            accounting.inc_counters(user_name, usage);
+        }
+        *accounting.state.lock().unwrap() = state;
+        Ok(accounting)
+    }
+
+    fn inc_counters(&self, user_name: &str, usage: &Usage) {
+        let label = UserNameLabel {
+            user_name: user_name.to_string(),
+        };
+        self.gpu_seconds
+            .get_or_create(&label)
+            .inc_by(usage.gpu_seconds);
+        self.memory_byte_seconds
+            .get_or_create(&label)
+            .inc_by(usage.memory_byte_seconds);
+    }
+
+    /// Adds the usage of the users to the totals and to the bucket of `date`
+    pub fn add(&self, date: NaiveDate, usage: &HashMap<String, Usage>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let day = state
+            .days
+            .entry(date.format(DATE_FORMAT).to_string())
+            .or_default();
+        for (user_name, usage) in usage.iter() {
+            day.entry(user_name.clone()).or_default().add(usage);
+        }
+        for (user_name, usage) in usage.iter() {
+            state
+                .totals
+                .entry(user_name.clone())
+                .or_default()
+                .add(usage);
+            self.inc_counters(user_name, usage);
+        }
+    }
+
+    /// Drops the days past the retention and writes the state file. The file
+    /// is replaced at once, so that a crash never leaves it truncated.
+    pub fn save(&self) -> Result<()> {
+        let content = {
+            let mut state = self.state.lock().unwrap();
+            let oldest = Local::now().date_naive() - Days::new(self.retention_days);
+            let oldest = oldest.format(DATE_FORMAT).to_string();
+            state.days = state.days.split_off(&oldest);
+            toml::to_string(&*state)?
+        };
+        let path = self.state_file.to_string_lossy();
+        if let Some(parent) = self.state_file.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Creating the directory of {}", path))?;
+        }
+        let temporary = self.state_file.with_extension("tmp");
+        std::fs::write(&temporary, content).with_context(|| format!("Writing {}", path))?;
+        std::fs::rename(&temporary, &self.state_file)
+            .with_context(|| format!("Replacing {}", path))?;
+        Ok(())
+    }
+
+    /// Sums the daily usage between `from` and `to` included into buckets
+    pub fn report(
+        &self,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        bucket: Bucket,
+    ) -> Vec<AccountingRow> {
+        let state = self.state.lock().unwrap();
+        let days: Vec<(NaiveDate, &BTreeMap<String, Usage>)> = state
+            .days
+            .iter()
+            .filter_map(|(date, users)| {
+                let date = NaiveDate::parse_from_str(date, DATE_FORMAT).ok()?;
+                let included = from.is_none_or(|x| date >= x) && to.is_none_or(|x| date <= x);
+                included.then_some((date, users))
+            })
+            .collect();
+        let (Some(first), Some(last)) = (days.first(), days.last()) else {
+            return Vec::new();
+        };
+        let range = (from.unwrap_or(first.0), to.unwrap_or(last.0));
+
+        let mut buckets: BTreeMap<(NaiveDate, NaiveDate, &str), Usage> = BTreeMap::new();
+        for (date, users) in days.iter() {
+            let (start, end) = match bucket {
+                Bucket::Day => (*date, *date),
+                Bucket::Week => {
+                    let start = *date - Days::new(date.weekday().num_days_from_monday() as u64);
+                    (start, start + Days::new(6))
+                }
+                Bucket::Month => {
+                    let start = date.with_day(1).unwrap_or(*date);
+                    (start, start + Months::new(1) - Days::new(1))
+                }
+                Bucket::Total => range,
+            };
+            let (start, end) = (start.max(range.0), end.min(range.1));
+            for (user_name, usage) in users.iter() {
+                buckets
+                    .entry((start, end, user_name))
+                    .or_default()
+                    .add(usage);
+            }
+        }
+        buckets
+            .into_iter()
+            .map(|((start, end, user_name), usage)| AccountingRow {
+                start: start.format(DATE_FORMAT).to_string(),
+                end: end.format(DATE_FORMAT).to_string(),
+                user_name: user_name.to_string(),
+                gpu_seconds: usage.gpu_seconds,
+                memory_byte_seconds: usage.memory_byte_seconds,
+            })
+            .collect()
+    }
+}
+
+pub fn to_csv(rows: &[AccountingRow]) -> String {
+    let mut csv = String::from("start,end,user_name,gpu_seconds,memory_byte_seconds\n");
+    for row in rows.iter() {
+        csv += &format!(
+            "{},{},{},{},{}\n",
+            row.start,
+            row.end,
+            csv_field(&row.user_name),
+            row.gpu_seconds,
+            row.memory_byte_seconds
+        );
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Integrates the cards and memory held by the users between collections
+pub struct AccountingMeter {
+    accounting: Arc<Accounting>,
+    save_interval: Duration,
+    last_save: Instant,
+    /// What the users held at the previous collection
+    previous: Option<(Instant, HashMap<String, Held>)>,
+}
+
+#[derive(Default)]
+struct Held {
+    cards: u32,
+    memory: u64,
+}
+
+impl AccountingMeter {
+    pub fn new(config: &AccountingConfig, accounting: Arc<Accounting>) -> AccountingMeter {
+        AccountingMeter {
+            accounting,
+            save_interval: Duration::from_secs_f64(config.save_interval),
+            last_save: Instant::now(),
+            previous: None,
+        }
+    }
+
+    /// Counts what the users held at the previous collection over the time
+    /// since, and saves the state file when due
+    pub fn observe(&mut self, at: Instant, users: &[NvmlUserUtilization]) {
+        let mut held: HashMap<String, Held> = HashMap::new();
+        for (user_name, cards) in cards_by_user(users) {
+            held.entry(user_name).or_default().cards = cards;
+        }
+        for user in users.iter().filter(|x| x.used_gpu_memory != 0) {
+            held.entry(user.user_name.clone()).or_default().memory += user.used_gpu_memory;
+        }
+        if let Some((previous_at, previous)) = self.previous.replace((at, held)) {
+            let seconds = at.duration_since(previous_at).as_secs_f64();
+            let usage = previous
+                .into_iter()
+                .map(|(user_name, held)| {
+                    let usage = Usage {
+                        gpu_seconds: held.cards as f64 * seconds,
+                        memory_byte_seconds: held.memory as f64 * seconds,
+                    };
+                    (user_name, usage)
+                })
+                .collect();
+            self.accounting.add(Local::now().date_naive(), &usage);
+        }
+        if at.duration_since(self.last_save) >= self.save_interval {
+            if let Err(e) = self.accounting.save() {
+                eprintln!("Accounting: {:#}", e);
+            }
+            self.last_save = at;
+        }
+    }
+
+    /// Forgets the previous collection, so that the time without data is not counted
+    pub fn clear(&mut self) {
+        self.previous = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, DATE_FORMAT).unwrap()
+    }
+
+    fn usage(user_name: &str, gpu_seconds: f64) -> HashMap<String, Usage> {
+        let usage = Usage {
+            gpu_seconds,
+            memory_byte_seconds: gpu_seconds * 1024.,
+        };
+        HashMap::from([(user_name.to_string(), usage)])
+    }
+
+    /// Alice on Saturday 2023-12-30, Tuesday 2024-01-02, Monday 2024-01-08
+    /// and Thursday 2024-02-01, Bob on 2024-01-08
+    fn accounting() -> Accounting {
+        let config = AccountingConfig {
+            state_file: PathBuf::from("/nonexistent/syswatch/accounting.toml"),
+            ..Default::default()
+        };
+        let accounting = Accounting::load(&config).unwrap();
+        accounting.add(date("2023-12-30"), &usage("alice", 1.));
+        accounting.add(date("2024-01-02"), &usage("alice", 2.));
+        accounting.add(date("2024-01-08"), &usage("alice", 4.));
+        accounting.add(date("2024-01-08"), &usage("bob", 16.));
+        accounting.add(date("2024-02-01"), &usage("alice", 8.));
+        accounting
+    }
+
+    fn report(
+        from: Option<&str>,
+        to: Option<&str>,
+        bucket: Bucket,
+    ) -> Vec<(String, String, String, f64)> {
+        accounting()
+            .report(from.map(date), to.map(date), bucket)
+            .into_iter()
+            .map(|x| (x.start, x.end, x.user_name, x.gpu_seconds))
+            .collect()
+    }
+
+    fn row(
+        start: &str,
+        end: &str,
+        user_name: &str,
+        gpu_seconds: f64,
+    ) -> (String, String, String, f64) {
+        (
+            start.to_string(),
+            end.to_string(),
+            user_name.to_string(),
+            gpu_seconds,
+        )
+    }
+
+    #[test]
+    fn days_are_filtered_by_range() {
+        assert_eq!(
+            report(Some("2024-01-01"), Some("2024-01-08"), Bucket::Day),
+            [
+                row("2024-01-02", "2024-01-02", "alice", 2.),
+                row("2024-01-08", "2024-01-08", "alice", 4.),
+                row("2024-01-08", "2024-01-08", "bob", 16.),
+            ]
+        );
+        assert!(report(Some("2024-03-01"), None, Bucket::Day).is_empty());
+    }
+
+    #[test]
+    fn weeks_start_on_monday_and_are_clipped() {
+        assert_eq!(
+            report(Some("2024-01-02"), Some("2024-01-09"), Bucket::Week),
+            [
+                row("2024-01-02", "2024-01-07", "alice", 2.),
+                row("2024-01-08", "2024-01-09", "alice", 4.),
+                row("2024-01-08", "2024-01-09", "bob", 16.),
+            ]
+        );
+        // Without a range, it spans the recorded days
+        assert_eq!(
+            report(None, Some("2024-01-07"), Bucket::Week),
+            [
+                row("2023-12-30", "2023-12-31", "alice", 1.),
+                row("2024-01-01", "2024-01-07", "alice", 2.),
+            ]
+        );
+    }
+
+    #[test]
+    fn months_are_clipped() {
+        assert_eq!(
+            report(None, None, Bucket::Month),
+            [
+                row("2023-12-30", "2023-12-31", "alice", 1.),
+                row("2024-01-01", "2024-01-31", "alice", 6.),
+                row("2024-01-01", "2024-01-31", "bob", 16.),
+                row("2024-02-01", "2024-02-01", "alice", 8.),
+            ]
+        );
+        assert_eq!(
+            report(Some("2024-01-05"), Some("2024-03-31"), Bucket::Month),
+            [
+                row("2024-01-05", "2024-01-31", "alice", 4.),
+                row("2024-01-05", "2024-01-31", "bob", 16.),
+                row("2024-02-01", "2024-02-29", "alice", 8.),
+            ]
+        );
+    }
+
+    #[test]
+    fn total_covers_the_range() {
+        assert_eq!(
+            report(Some("2023-12-01"), None, Bucket::Total),
+            [
+                row("2023-12-01", "2024-02-01", "alice", 15.),
+                row("2023-12-01", "2024-02-01", "bob", 16.),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        let rows = ["alice", "smith, john", "the \"admin\"", "two\nlines"].map(|user_name| {
+            AccountingRow {
+                start: "2024-01-01".to_string(),
+                end: "2024-01-07".to_string(),
+                user_name: user_name.to_string(),
+                gpu_seconds: 1.5,
+                memory_byte_seconds: 1536.,
+            }
+        });
+        assert_eq!(
+            to_csv(&rows),
+            "start,end,user_name,gpu_seconds,memory_byte_seconds\n\
+             2024-01-01,2024-01-07,alice,1.5,1536\n\
+             2024-01-01,2024-01-07,\"smith, john\",1.5,1536\n\
+             2024-01-01,2024-01-07,\"the \"\"admin\"\"\",1.5,1536\n\
+             2024-01-01,2024-01-07,\"two\nlines\",1.5,1536\n"
+        );
+    }
+}
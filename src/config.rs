@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::accounting::AccountingConfig;
+use crate::idle::IdleConfig;
+use crate::notifications::NotificationConfig;
+use crate::processes::ProcessConfig;
+use crate::quotas::QuotaConfig;
+use crate::relabel::RelabelConfig;
+use crate::sampler::SamplerConfig;
+use crate::teams::{TeamConfig, TeamSource};
+use crate::textfile::TextfileConfig;
+use crate::utils::UserPolicyConfig;
+use crate::workloads::WorkloadConfig;
+use crate::xid::XidConfig;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Scrape timeout in seconds, used when Prometheus does not send one
+    pub scrape_timeout: f64,
+    /// Labels added to every exposed sample
+    pub static_labels: BTreeMap<String, String>,
+    pub metric_relabel_configs: Vec<RelabelConfig>,
+    pub textfile: Option<TextfileConfig>,
+    pub sampler: SamplerConfig,
+    pub xid: Option<XidConfig>,
+    /// Per-process series are exported when set
+    pub processes: Option<ProcessConfig>,
+    pub workloads: WorkloadConfig,
+    /// Which users are shown in the per-user metrics
+    pub users: UserPolicyConfig,
+    /// Processes holding GPU memory while idle are tracked when set
+    pub idle: Option<IdleConfig>,
+    /// GPU time and memory of the users are accumulated when set
+    pub accounting: Option<AccountingConfig>,
+    /// Usage is aggregated by team when set
+    pub teams: Option<TeamConfig>,
+    /// Users are checked against the rules when set
+    pub quotas: Option<QuotaConfig>,
+    /// Users hoarding GPUs are notified on their terminals when set
+    pub notifications: Option<NotificationConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scrape_timeout: 10.,
+            static_labels: BTreeMap::new(),
+            metric_relabel_configs: Vec::new(),
+            textfile: None,
+            sampler: SamplerConfig::default(),
+            xid: None,
+            processes: None,
+            workloads: WorkloadConfig::default(),
+            users: UserPolicyConfig::default(),
+            idle: None,
+            accounting: None,
+            teams: None,
+            quotas: None,
+            notifications: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file. A missing file yields the default configuration.
+    pub fn load(path: &Path) -> Result<Config> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Reading config {}", path.to_string_lossy()))
+            }
+        };
+        let config: Config = toml::from_str(&content)
+            .with_context(|| format!("Parsing config {}", path.to_string_lossy()))?;
+        if !(config.scrape_timeout.is_finite() && config.scrape_timeout > 0.) {
+            anyhow::bail!("Configuration error: scrape_timeout should be larger than 0");
+        }
+        if !(config.sampler.interval.is_finite() && config.sampler.interval > 0.) {
+            anyhow::bail!("Configuration error: sampler.interval should be larger than 0");
+        }
+        if !(config.sampler.window.is_finite() && config.sampler.window >= config.sampler.interval)
+        {
+            anyhow::bail!(
+                "Configuration error: sampler.window should not be shorter than sampler.interval"
+            );
+        }
+        let uid_ranges = config.users.uid_ranges.iter();
+        if let Some([min, max]) = uid_ranges
+            .chain(std::iter::once(&config.users.fallback_uid_range))
+            .find(|[min, max]| min > max)
+        {
+            anyhow::bail!(
+                "Configuration error: invalid UID range [{}, {}] in users, the first UID \
+                 should not be larger than the last",
+                min,
+                max
+            );
+        }
+        if let Some(idle) = &config.idle {
+            if !(idle.max_utilization.is_finite() && (0. ..=1.).contains(&idle.max_utilization)) {
+                anyhow::bail!(
+                    "Configuration error: idle.max_utilization should be a ratio between 0 and 1"
+                );
+            }
+            if !(idle.min_duration.is_finite() && idle.min_duration >= 0.) {
+                anyhow::bail!("Configuration error: idle.min_duration should not be negative");
+            }
+        }
+        if let Some(teams) = &config.teams {
+            if teams.source == TeamSource::Static && teams.members.is_empty() {
+                anyhow::bail!(
+                    "Configuration error: teams.members should not be empty with the static source"
+                );
+            }
+        }
+        if let Some(notifications) = &config.notifications {
+            if !(notifications.min_interval.is_finite() && notifications.min_interval >= 0.) {
+                anyhow::bail!(
+                    "Configuration error: notifications.min_interval should not be negative"
+                );
+            }
+        }
+        if let Some(accounting) = &config.accounting {
+            if !(accounting.save_interval.is_finite() && accounting.save_interval > 0.) {
+                anyhow::bail!(
+                    "Configuration error: accounting.save_interval should be larger than 0"
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    /// Settings which are valid but most likely not what was meant
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(notifications) = &self.notifications {
+            match &self.idle {
+                None => warnings.push(
+                    "notifications are enabled without [idle], idle processes will not be \
+                     notified"
+                        .to_string(),
+                ),
+                Some(idle) if idle.min_duration > notifications.idle_after => {
+                    warnings.push(format!(
+                        "idle.min_duration ({} s) is longer than notifications.idle_after \
+                         ({} s), idle processes will only be notified after {} s",
+                        idle.min_duration, notifications.idle_after, idle.min_duration
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn load(content: &str) -> Result<Config> {
+        // Tests run in parallel threads of the same process
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "syswatch-config-{}-{}.toml",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, content).unwrap();
+        let config = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn idle_notifications_are_checked() {
+        let config = load("[notifications]\n").unwrap();
+        assert!(config.warnings()[0].contains("without [idle]"));
+        let config =
+            load("[idle]\nmin_duration = 3600\n[notifications]\nidle_after = 1800\n").unwrap();
+        assert!(config.warnings()[0].contains("idle.min_duration (3600 s)"));
+        let config =
+            load("[idle]\nmin_duration = 600\n[notifications]\nidle_after = 1800\n").unwrap();
+        assert!(config.warnings().is_empty());
+    }
+
+    #[test]
+    fn idle_max_utilization_is_a_ratio() {
+        assert!(load("[idle]\nmax_utilization = 0.05\n").is_ok());
+        assert!(load("[idle]\nmax_utilization = 1.0\n").is_ok());
+        let error = load("[idle]\nmax_utilization = 5\n").unwrap_err();
+        assert!(error.to_string().contains("idle.max_utilization"));
+        assert!(load("[idle]\nmax_utilization = -0.1\n").is_err());
+    }
+}
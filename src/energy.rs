@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::nvml_metrics::{NvmlDevice, NvmlMetrics, NvmlUserUtilization};
+
+/// Energy used between two collections, in joules
+#[derive(Default)]
+pub struct EnergyUse {
+    /// By device minor number
+    pub devices: Vec<(u32, f64)>,
+    /// By device index and user name
+    pub users: Vec<(u32, String, f64)>,
+}
+
+struct Reading {
+    at: Instant,
+    energy_consumption: Option<u64>,
+    power_usage: Option<u32>,
+}
+
+/// Turns successive readings into energy increments. The NVML energy counter
+/// is preferred; devices without it fall back to integrating the sampled power.
+#[derive(Default)]
+pub struct EnergyMeter {
+    readings: HashMap<String, Reading>,
+}
+
+impl EnergyMeter {
+    pub fn observe(&mut self, at: Instant, state: &NvmlMetrics) -> EnergyUse {
+        let mut energy = EnergyUse::default();
+        let mut readings = HashMap::with_capacity(state.devices.len());
+        for device in state.devices.iter() {
+            let reading = Reading {
+                at,
+                energy_consumption: device.energy_consumption,
+                power_usage: device.power_usage,
+            };
+            let joules = self
+                .readings
+                .get(&device.uuid)
+                .and_then(|previous| energy_between(previous, &reading));
+            if let Some(joules) = joules {
+                energy.devices.push((device.minor_number, joules));
+                attribute(device, joules, &state.users_utilization, &mut energy.users);
+            }
+            readings.insert(device.uuid.clone(), reading);
+        }
+        self.readings = readings;
+        energy
+    }
+
+    /// Forgets the readings, e.g. after a failed collection
+    pub fn clear(&mut self) {
+        self.readings.clear();
+    }
+}
+
+fn energy_between(previous: &Reading, current: &Reading) -> Option<f64> {
+    if let (Some(previous), Some(current)) =
+        (previous.energy_consumption, current.energy_consumption)
+    {
+        // The counter restarts when the driver is reloaded
+        return current.checked_sub(previous).map(|x| x as f64 / 1000.);
+    }
+    let (Some(previous_power), Some(current_power)) = (previous.power_usage, current.power_usage)
+    else {
+        return None;
+    };
+    let seconds = current.at.duration_since(previous.at).as_secs_f64();
+    Some((previous_power as f64 + current_power as f64) / 2. / 1000. * seconds)
+}
+
+/// Splits the energy of a device among its users by their share of the SM
+/// utilization, or of the used memory when no process utilization is known.
+/// Shares are relative to all processes on the device, so the part of users
+/// which are not listed stays unattributed.
+fn attribute(
+    device: &NvmlDevice,
+    joules: f64,
+    users: &[NvmlUserUtilization],
+    result: &mut Vec<(u32, String, f64)>,
+) {
+    let by_utilization = device.process_sm_utilization.filter(|x| *x > 0);
+    let by_memory = device.process_used_memory.filter(|x| *x > 0);
+    for user in users.iter().filter(|x| x.index == device.index) {
+        let share = match (by_utilization, by_memory) {
+            (Some(total), _) => user.sm_utilization.unwrap_or(0) as f64 / total as f64,
+            (None, Some(total)) => user.used_gpu_memory as f64 / total as f64,
+            (None, None) => continue,
+        };
+        if share > 0. {
+            result.push((device.index, user.user_name.clone(), joules * share));
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{Local, SecondsFormat};
+use serde::Deserialize;
+
+use crate::nvml_metrics::{self, NvmlProcess};
+use crate::quotas::QuotaViolation;
+use crate::terminals;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Logs the violation to stderr
+    Warn,
+    /// Writes a message to the terminals of the user
+    Tty,
+    /// Sends SIGTERM to the newest GPU process of the user
+    Sigterm,
+    /// Sends SIGKILL to the newest GPU process of the user
+    Sigkill,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Warn => "warn",
+            Action::Tty => "tty",
+            Action::Sigterm => "sigterm",
+            Action::Sigkill => "sigkill",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EnforcementConfig {
+    /// Actions are only written to the audit log
+    pub dry_run: bool,
+    /// Seconds a violation lasts before the first action
+    pub grace_period: f64,
+    /// Seconds between two actions, the last one is repeated while the
+    /// violation goes on
+    pub interval: f64,
+    /// Escalating actions, taken one after the other
+    pub actions: Vec<Action>,
+    /// Users never acted upon
+    pub allowlist: Vec<String>,
+    /// Every action is appended to this file
+    pub audit_log: PathBuf,
+}
+
+impl Default for EnforcementConfig {
+    fn default() -> Self {
+        EnforcementConfig {
+            dry_run: true,
+            grace_period: 600.,
+            interval: 300.,
+            actions: vec![Action::Warn, Action::Tty, Action::Sigterm, Action::Sigkill],
+            allowlist: vec!["root".to_string()],
+            audit_log: PathBuf::from("/var/log/syswatch/enforcement.log"),
+        }
+    }
+}
+
+struct Escalation {
+    since: Instant,
+    /// Count of actions taken so far
+    taken: u32,
+}
+
+/// Takes the escalating actions against the users who stay over a quota
+pub struct Enforcer {
+    dry_run: bool,
+    grace_period: Duration,
+    interval: Duration,
+    actions: Vec<Action>,
+    allowlist: HashSet<String>,
+    audit_log: PathBuf,
+    /// Escalations by user name, shared by the rules the user violates
+    escalations: HashMap<String, Escalation>,
+}
+
+impl Enforcer {
+    pub fn new(config: &EnforcementConfig) -> Result<Enforcer> {
+        if config.actions.is_empty() {
+            anyhow::bail!("quotas.enforcement.actions should not be empty");
+        }
+        if !(config.grace_period.is_finite() && config.grace_period >= 0.) {
+            anyhow::bail!("quotas.enforcement.grace_period should not be negative");
+        }
+        if !(config.interval.is_finite() && config.interval > 0.) {
+            anyhow::bail!("quotas.enforcement.interval should be larger than 0");
+        }
+        if let Some(parent) = config.audit_log.parent() {
+            std::fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Creating the directory of {}",
+                    config.audit_log.to_string_lossy()
+                )
+            })?;
+        }
+        Ok(Enforcer {
+            dry_run: config.dry_run,
+            grace_period: Duration::from_secs_f64(config.grace_period),
+            interval: Duration::from_secs_f64(config.interval),
+            actions: config.actions.clone(),
+            allowlist: config.allowlist.iter().cloned().collect(),
+            audit_log: config.audit_log.clone(),
+            escalations: HashMap::new(),
+        })
+    }
+
+    /// Takes the actions which are due. A user over several rules goes
+    /// through a single escalation, which starts with the first violation
+    /// and is forgotten once the user violates no rule, so that a new
+    /// violation starts over.
+    pub fn enforce(
+        &mut self,
+        at: Instant,
+        violations: &[QuotaViolation],
+        processes: &[NvmlProcess],
+    ) {
+        let mut by_user: BTreeMap<&str, Vec<&QuotaViolation>> = BTreeMap::new();
+        for violation in violations.iter() {
+            if violation.soft || self.allowlist.contains(&violation.user_name) {
+                continue;
+            }
+            by_user
+                .entry(violation.user_name.as_str())
+                .or_default()
+                .push(violation);
+        }
+        let mut escalations = HashMap::with_capacity(by_user.len());
+        for (user_name, violations) in by_user {
+            let rules: Vec<&str> = violations.iter().map(|x| x.rule.as_str()).collect();
+            let violation = QuotaViolation {
+                rule: rules.join(","),
+                ..violations[0].clone()
+            };
+            let mut escalation = self.escalations.remove(user_name).unwrap_or(Escalation {
+                since: at,
+                taken: 0,
+            });
+            let due = escalation.since + self.grace_period + self.interval * escalation.taken;
+            if at >= due {
+                let action = self.actions[(escalation.taken as usize).min(self.actions.len() - 1)];
+                self.take(
+                    action,
+                    &violation,
+                    at.duration_since(escalation.since),
+                    processes,
+                );
+                escalation.taken += 1;
+            }
+            escalations.insert(user_name.to_string(), escalation);
+        }
+        self.escalations = escalations;
+    }
+
+    fn take(
+        &self,
+        action: Action,
+        violation: &QuotaViolation,
+        lasted: Duration,
+        processes: &[NvmlProcess],
+    ) {
+        let message = format!(
+            "syswatch: user {} has been over the GPU quota {} for {} s, holding {} GPUs and {} MiB",
+            violation.user_name,
+            violation.rule,
+            lasted.as_secs(),
+            violation.cards,
+            violation.memory >> 20
+        );
+        let (pid, result) = match action {
+            Action::Warn if self.dry_run => (None, "dry run".to_string()),
+            Action::Warn => {
+                eprintln!("{}", message);
+                (None, "logged".to_string())
+            }
+            Action::Tty => {
+                let user = users::get_user_by_name(&violation.user_name);
+                let result = match user {
+                    _ if self.dry_run => "dry run".to_string(),
+                    None => "skipped, unknown user".to_string(),
+                    Some(user) => match terminals::write_to_user(
+                        Path::new(terminals::UTMP_PATH),
+                        &violation.user_name,
+                        user.uid(),
+                        &message,
+                    ) {
+                        Ok(delivered) => format!("delivered to {} terminals", delivered),
+                        Err(e) => format!("failed: {}", e),
+                    },
+                };
+                (None, result)
+            }
+            Action::Sigterm | Action::Sigkill => {
+                let signal = match action {
+                    Action::Sigterm => libc::SIGTERM,
+                    _ => libc::SIGKILL,
+                };
+                // The newest process is the least work lost
+                let newest = processes
+                    .iter()
+                    .filter(|x| x.user_name.as_deref() == Some(violation.user_name.as_str()))
+                    .max_by(|a, b| {
+                        a.start_time
+                            .unwrap_or(0.)
+                            .total_cmp(&b.start_time.unwrap_or(0.))
+                    });
+                match newest {
+                    Some(process) if self.dry_run => (Some(process.pid), "dry run".to_string()),
+                    Some(process) => (
+                        Some(process.pid),
+                        signal_process(process, &violation.user_name, signal),
+                    ),
+                    None => (None, "no GPU process".to_string()),
+                }
+            }
+        };
+        self.audit(action, violation, pid, &result);
+    }
+
+    fn audit(&self, action: Action, violation: &QuotaViolation, pid: Option<u32>, result: &str) {
+        let line = format!(
+            "{} dry_run={} rule={:?} user={:?} action={} pid={} result={:?}\n",
+            Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            self.dry_run,
+            violation.rule,
+            violation.user_name,
+            action.name(),
+            pid.map(|x| x.to_string()).unwrap_or_default(),
+            result
+        );
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            eprintln!(
+                "Enforcement: cannot write {}: {}",
+                self.audit_log.to_string_lossy(),
+                e
+            );
+        }
+    }
+}
+
+/// Signals the process if it is still the one of the collection, as the pid
+/// may have been reused since: it has to belong to the user and to have
+/// started at the same time
+fn signal_process(process: &NvmlProcess, user_name: &str, signal: libc::c_int) -> String {
+    let Some(user) = users::get_user_by_name(user_name) else {
+        return "skipped, unknown user".to_string();
+    };
+    let Some(start_time) = process.start_time else {
+        return "skipped, unknown start time".to_string();
+    };
+    let current =
+        procfs::process::Process::new(process.pid as i32).and_then(|x| Ok((x.uid()?, x.stat()?)));
+    match current {
+        Ok((uid, _)) if uid != user.uid() => return "skipped, process of another user".to_string(),
+        Ok((_, stat)) if nvml_metrics::start_time(&stat) != Some(start_time) => {
+            return "skipped, pid reused by another process".to_string()
+        }
+        Ok(_) => {}
+        Err(_) => return "skipped, process exited".to_string(),
+    }
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(process.pid as libc::pid_t, signal) } == 0 {
+        "sent".to_string()
+    } else {
+        format!("failed: {}", std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Child, Command};
+
+    fn enforcer(name: &str, dry_run: bool, actions: Vec<Action>) -> Enforcer {
+        let audit_log = std::env::temp_dir().join(format!(
+            "syswatch-audit-{}-{}.log",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&audit_log);
+        Enforcer::new(&EnforcementConfig {
+            dry_run,
+            grace_period: 10.,
+            interval: 5.,
+            actions,
+            allowlist: vec!["allowed".to_string()],
+            audit_log,
+        })
+        .unwrap()
+    }
+
+    fn audit_lines(enforcer: &Enforcer) -> Vec<String> {
+        let lines = std::fs::read_to_string(&enforcer.audit_log).unwrap_or_default();
+        std::fs::remove_file(&enforcer.audit_log).ok();
+        lines.lines().map(|x| x.to_string()).collect()
+    }
+
+    fn violation(user_name: &str) -> QuotaViolation {
+        QuotaViolation {
+            rule: "cards".to_string(),
+            user_name: user_name.to_string(),
+            cards: 3,
+            memory: 0,
+            max_cards: Some(2),
+            max_memory: None,
+            soft: false,
+        }
+    }
+
+    /// A dummy GPU process of the current user
+    fn spawn(user_name: &str) -> (Child, NvmlProcess) {
+        let child = Command::new("sleep").arg("60").spawn().unwrap();
+        let stat = procfs::process::Process::new(child.id() as i32)
+            .and_then(|x| x.stat())
+            .unwrap();
+        let process = NvmlProcess {
+            pid: child.id(),
+            minor_number: 0,
+            process_type: "compute",
+            user_name: Some(user_name.to_string()),
+            command: "sleep 60".to_string(),
+            start_time: nvml_metrics::start_time(&stat),
+            used_gpu_memory: Some(1 << 30),
+            utilization: None,
+            rss: None,
+        };
+        (child, process)
+    }
+
+    fn current_user() -> String {
+        users::get_current_username()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn actions_escalate_after_the_grace_period() {
+        let user_name = current_user();
+        let (mut child, process) = spawn(&user_name);
+        let mut enforcer = enforcer("escalation", false, vec![Action::Warn, Action::Sigterm]);
+        let start = Instant::now();
+        let violations = [violation(&user_name)];
+        for seconds in [0, 9, 10, 14, 15] {
+            let at = start + Duration::from_secs(seconds);
+            enforcer.enforce(at, &violations, std::slice::from_ref(&process));
+        }
+        let lines = audit_lines(&enforcer);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("dry_run=false"));
+        assert!(lines[0].contains(" action=warn pid= result=\"logged\""));
+        assert!(lines[1].contains(&format!(
+            " action=sigterm pid={} result=\"sent\"",
+            process.pid
+        )));
+        assert!(child.wait().unwrap().code().is_none());
+    }
+
+    #[test]
+    fn dry_run_only_audits() {
+        let user_name = current_user();
+        let (mut child, process) = spawn(&user_name);
+        let mut enforcer = enforcer("dry-run", true, vec![Action::Sigkill]);
+        let at = Instant::now();
+        enforcer.enforce(at, &[violation(&user_name)], std::slice::from_ref(&process));
+        enforcer.enforce(
+            at + Duration::from_secs(10),
+            &[violation(&user_name)],
+            std::slice::from_ref(&process),
+        );
+        let lines = audit_lines(&enforcer);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(&format!(
+            "dry_run=true rule=\"cards\" user={:?} action=sigkill pid={} result=\"dry run\"",
+            user_name, process.pid
+        )));
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn rules_of_a_user_share_one_escalation() {
+        let mut enforcer = enforcer("rules", true, vec![Action::Warn, Action::Sigterm]);
+        let memory = QuotaViolation {
+            rule: "memory".to_string(),
+            ..violation("bob")
+        };
+        let at = Instant::now();
+        enforcer.enforce(at, &[violation("bob")], &[]);
+        for seconds in [5, 10, 15] {
+            enforcer.enforce(
+                at + Duration::from_secs(seconds),
+                &[violation("bob"), memory.clone()],
+                &[],
+            );
+        }
+        let lines = audit_lines(&enforcer);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("rule=\"cards,memory\" user=\"bob\" action=warn"));
+        assert!(lines[1].contains("rule=\"cards,memory\" user=\"bob\" action=sigterm"));
+    }
+
+    #[test]
+    fn allowlisted_and_soft_violations_are_left_alone() {
+        let mut enforcer = enforcer("allowlist", false, vec![Action::Warn]);
+        let soft = QuotaViolation {
+            soft: true,
+            ..violation("bob")
+        };
+        let at = Instant::now();
+        for seconds in [0, 10, 20] {
+            enforcer.enforce(
+                at + Duration::from_secs(seconds),
+                &[violation("allowed"), soft.clone()],
+                &[],
+            );
+        }
+        assert!(audit_lines(&enforcer).is_empty());
+    }
+
+    #[test]
+    fn reused_pid_is_not_signaled() {
+        let user_name = current_user();
+        let (mut child, mut process) = spawn(&user_name);
+        process.start_time = process.start_time.map(|x| x - 100.);
+        assert_eq!(
+            signal_process(&process, &user_name, libc::SIGKILL),
+            "skipped, pid reused by another process"
+        );
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use std::fmt::Write;
+
+use thiserror::Error;
+
+/// Suffixes a sample name may carry on top of its family name
+const SAMPLE_SUFFIXES: [&str; 8] = [
+    "_total", "_bucket", "_sum", "_count", "_created", "_info", "_gsum", "_gcount",
+];
+
+pub type Labels = Vec<(String, String)>;
+
+#[derive(Error, Debug)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: Option<String>,
+    pub metric_type: Option<String>,
+    pub unit: Option<String>,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Labels,
+    pub value: f64,
+    pub timestamp: Option<String>,
+}
+
+impl MetricFamily {
+    pub fn new(name: &str) -> MetricFamily {
+        MetricFamily {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether a sample of this name belongs to the family
+    pub fn owns(&self, sample_name: &str) -> bool {
+        match sample_name.strip_prefix(self.name.as_str()) {
+            Some("") => true,
+            Some(suffix) => SAMPLE_SUFFIXES.contains(&suffix),
+            None => false,
+        }
+    }
+}
+
+/// Parses the Prometheus text exposition format (and the OpenMetrics subset that
+/// `prometheus_client` emits) into metric families.
+pub fn parse(text: &str) -> Result<Vec<MetricFamily>, ParseError> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+
+    for (line_no, line) in text.lines().enumerate() {
+        let error = |message: &str| ParseError {
+            line: line_no + 1,
+            message: message.to_string(),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            let keyword = parts.next().unwrap_or_default();
+            if !matches!(keyword, "HELP" | "TYPE" | "UNIT") {
+                continue;
+            }
+            let name = parts.next().ok_or_else(|| error("missing metric name"))?;
+            if !is_valid_metric_name(name) {
+                return Err(error(&format!("invalid metric name {:?}", name)));
+            }
+            let rest = parts.next().unwrap_or_default().to_string();
+
+            let family = match families.last_mut() {
+                Some(family) if family.name == name => family,
+                _ => {
+                    families.push(MetricFamily::new(name));
+                    families.last_mut().unwrap()
+                }
+            };
+            let slot = match keyword {
+                "HELP" => &mut family.help,
+                "TYPE" => &mut family.metric_type,
+                _ => &mut family.unit,
+            };
+            if slot.is_some() {
+                return Err(error(&format!("duplicate {} for {}", keyword, name)));
+            }
+            *slot = Some(if keyword == "HELP" {
+                unescape(&rest, false)
+            } else {
+                rest
+            });
+            continue;
+        }
+
+        let sample = parse_sample(line).map_err(|message| error(&message))?;
+        match families.last_mut() {
+            Some(family) if family.owns(&sample.name) => family.samples.push(sample),
+            _ => {
+                let mut family = MetricFamily::new(&sample.name);
+                family.samples.push(sample);
+                families.push(family);
+            }
+        }
+    }
+
+    Ok(families)
+}
+
+/// Encodes metric families back into the text exposition format.
+pub fn encode(families: &[MetricFamily], out: &mut String) {
+    for family in families {
+        if let Some(help) = &family.help {
+            writeln!(out, "# HELP {} {}", family.name, escape(help, false)).unwrap();
+        }
+        if let Some(metric_type) = &family.metric_type {
+            writeln!(out, "# TYPE {} {}", family.name, metric_type).unwrap();
+        }
+        if let Some(unit) = &family.unit {
+            writeln!(out, "# UNIT {} {}", family.name, unit).unwrap();
+        }
+        for sample in family.samples.iter() {
+            out.push_str(&sample.name);
+            if !sample.labels.is_empty() {
+                out.push('{');
+                for (i, (name, value)) in sample.labels.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write!(out, "{}=\"{}\"", name, escape(value, true)).unwrap();
+                }
+                out.push('}');
+            }
+            write!(out, " {}", format_value(sample.value)).unwrap();
+            if let Some(timestamp) = &sample.timestamp {
+                write!(out, " {}", timestamp).unwrap();
+            }
+            out.push('\n');
+        }
+    }
+}
+
+pub fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_ascii_whitespace())
+        .ok_or_else(|| "missing sample value".to_string())?;
+    let name = &line[..name_end];
+    if !is_valid_metric_name(name) {
+        return Err(format!("invalid metric name {:?}", name));
+    }
+
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+    if let Some(label_text) = rest.strip_prefix('{') {
+        let (parsed, remaining) = parse_labels(label_text)?;
+        labels = parsed;
+        rest = remaining;
+    }
+
+    let mut fields = rest.split_ascii_whitespace();
+    let value = fields
+        .next()
+        .ok_or_else(|| format!("missing value for {}", name))?;
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("invalid value {:?} for {}", value, name))?;
+    let timestamp = fields.next().map(|x| x.to_string());
+    if fields.next().is_some() {
+        return Err(format!("trailing characters after sample {}", name));
+    }
+
+    Ok(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// Parses `name="value",...}` and returns the labels with the remaining text.
+fn parse_labels(mut text: &str) -> Result<(Labels, &str), String> {
+    let mut labels: Labels = Vec::new();
+    loop {
+        text = text.trim_start();
+        if let Some(remaining) = text.strip_prefix('}') {
+            return Ok((labels, remaining));
+        }
+
+        let eq = text
+            .find('=')
+            .ok_or_else(|| "malformed label set".to_string())?;
+        let name = text[..eq].trim();
+        if !is_valid_label_name(name) {
+            return Err(format!("invalid label name {:?}", name));
+        }
+        if labels.iter().any(|(n, _)| n == name) {
+            return Err(format!("duplicate label {:?}", name));
+        }
+        text = text[eq + 1..]
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| format!("label {:?} is not quoted", name))?;
+
+        let mut escaped = false;
+        let end = text
+            .char_indices()
+            .find(|&(_, c)| {
+                let end = !escaped && c == '"';
+                escaped = !escaped && c == '\\';
+                end
+            })
+            .map(|(i, _)| i)
+            .ok_or_else(|| format!("unterminated value of label {:?}", name))?;
+        labels.push((name.to_string(), unescape(&text[..end], true)));
+
+        text = text[end + 1..].trim_start();
+        if let Some(remaining) = text.strip_prefix(',') {
+            text = remaining;
+        } else if !text.starts_with('}') {
+            return Err("malformed label set".to_string());
+        }
+    }
+}
+
+fn unescape(text: &str, quoted: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('\\') => result.push('\\'),
+            Some('"') if quoted => result.push('"'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+fn escape(text: &str, quoted: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '"' if quoted => result.push_str("\\\""),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0. { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_round_trips() {
+        let text = "# HELP node_load \"Load\" of the \\\\ node\\n\n\
+                    # TYPE node_load gauge\n\
+                    node_load{cpu=\"0\",path=\"C:\\\\ \\\"x\\\"\\n\"} 1.5\n\
+                    node_load 2 1700000000000\n\
+                    # HELP node_requests Requests\n\
+                    # TYPE node_requests counter\n\
+                    node_requests_total{code=\"200\"} 3\n\
+                    node_requests_created{code=\"200\"} 1700000000\n\
+                    node_temperature NaN\n\
+                    node_limit +Inf\n";
+        let families = parse(text).unwrap();
+        assert_eq!(families.len(), 4);
+        assert_eq!(
+            families[0].help.as_deref(),
+            Some("\"Load\" of the \\ node\n")
+        );
+        assert_eq!(families[0].samples[0].labels[1].1, "C:\\ \"x\"\n");
+        assert_eq!(
+            families[0].samples[1].timestamp.as_deref(),
+            Some("1700000000000")
+        );
+        assert_eq!(families[1].samples.len(), 2);
+        assert_eq!(families[1].metric_type.as_deref(), Some("counter"));
+
+        let mut encoded = String::new();
+        encode(&families, &mut encoded);
+        assert_eq!(encoded, text);
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        let error = parse("node_load 1\nnode_load{cpu=0} 1\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(parse("node_load{cpu=\"0\",cpu=\"1\"} 1\n").is_err());
+        assert!(parse("node_load one\n").is_err());
+        assert!(parse("# TYPE node_load gauge\n# TYPE node_load gauge\n").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::nvml_metrics::NvmlMetrics;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IdleConfig {
+    /// Processes holding less GPU memory, in bytes, are never idle
+    pub min_memory: u64,
+    /// Utilization ratio of the busiest engine at or below which a process is idle
+    pub max_utilization: f64,
+    /// Seconds a process must stay idle before it is reported
+    pub min_duration: f64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            min_memory: 1 << 30,
+            max_utilization: 0.05,
+            min_duration: 300.,
+        }
+    }
+}
+
+/// A process holding GPU memory without using the GPU
+#[derive(Serialize, Clone, Debug)]
+pub struct IdleHold {
+    pub pid: u32,
+    pub user_name: String,
+    pub index: u32,
+    pub minor_number: u32,
+    pub command: String,
+    pub used_gpu_memory: u64,
+    /// Unix time in seconds when the process became idle
+    pub since: f64,
+    /// Seconds the process has been idle
+    pub duration: f64,
+}
+
+struct Hold {
+    since: Instant,
+    start_time: Option<f64>,
+}
+
+/// Follows for how long each process has been idle over the collections
+pub struct IdleDetector {
+    min_memory: u64,
+    max_utilization: f64,
+    min_duration: f64,
+    holds: HashMap<(u32, u32), Hold>,
+}
+
+impl IdleDetector {
+    pub fn new(config: &IdleConfig) -> IdleDetector {
+        IdleDetector {
+            min_memory: config.min_memory,
+            max_utilization: config.max_utilization,
+            min_duration: config.min_duration,
+            holds: HashMap::new(),
+        }
+    }
+
+    /// Returns the processes idle for at least `min_duration`, longest first.
+    /// The utilization of a process falls back to the one of its GPU when the
+    /// GPU does not report process utilization, except in MIG mode where the
+    /// GPU is shared. Processes of hidden users are left out.
+    pub fn observe(&mut self, at: Instant, state: &NvmlMetrics) -> Vec<IdleHold> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut holds = HashMap::with_capacity(self.holds.len());
+        let mut idle = Vec::new();
+        let mut seen = HashSet::with_capacity(state.processes.len());
+        for process in state.processes.iter() {
+            let key = (process.minor_number, process.pid);
+            // A process may be listed both as compute and graphics process
+            if !seen.insert(key) {
+                continue;
+            }
+            let Some(user_name) = &process.user_name else {
+                continue;
+            };
+            let Some(device) = state
+                .devices
+                .iter()
+                .find(|x| x.minor_number == process.minor_number)
+            else {
+                continue;
+            };
+            let utilization = match process.utilization {
+                Some(utilization) => Some(utilization),
+                None if device.mig_devices.is_empty() => device.utilization_gpu,
+                None => None,
+            };
+            let used_gpu_memory = process.used_gpu_memory.unwrap_or(0);
+            let is_idle = utilization.is_some_and(|x| x as f64 / 100. <= self.max_utilization)
+                && used_gpu_memory >= self.min_memory;
+            if !is_idle {
+                continue;
+            }
+            let since = match self.holds.remove(&key) {
+                // The pid was reused by another process otherwise
+                Some(hold) if hold.start_time == process.start_time => hold.since,
+                _ => at,
+            };
+            let duration = at.duration_since(since).as_secs_f64();
+            if duration >= self.min_duration {
+                idle.push(IdleHold {
+                    pid: process.pid,
+                    user_name: user_name.clone(),
+                    index: device.index,
+                    minor_number: process.minor_number,
+                    command: process.command.clone(),
+                    used_gpu_memory,
+                    since: now - duration,
+                    duration,
+                });
+            }
+            holds.insert(
+                key,
+                Hold {
+                    since,
+                    start_time: process.start_time,
+                },
+            );
+        }
+        self.holds = holds;
+        idle.sort_by(|a, b| b.duration.total_cmp(&a.duration));
+        idle
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt::Write;
+
+use humansize::{format_size, BINARY};
+
+use crate::metrics::{AliveTarget, DeviceSummary};
+
+pub struct LandingPage {
+    pub collectors: Vec<(&'static str, bool)>,
+    pub upstreams: Vec<String>,
+    pub links: Vec<(&'static str, &'static str)>,
+    pub devices: Vec<DeviceSummary>,
+    pub targets: Vec<AliveTarget>,
+}
+
+impl LandingPage {
+    pub fn render(&self) -> String {
+        let mut html = String::new();
+        html.push_str(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>syswatch</title>\n",
+        );
+        html.push_str(
+            "<style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}\
+             td,th{border:1px solid #ccc;padding:4px 8px;text-align:left}</style>\n",
+        );
+        html.push_str("</head>\n<body>\n");
+        writeln!(
+            html,
+            "<h1>syswatch</h1>\n<p>Version {}</p>",
+            escape(env!("CARGO_PKG_VERSION"))
+        )
+        .unwrap();
+
+        html.push_str("<h2>Endpoints</h2>\n<ul>\n");
+        for (path, description) in self.links.iter() {
+            writeln!(
+                html,
+                "<li><a href=\"{0}\">{0}</a> - {1}</li>",
+                escape(path),
+                escape(description)
+            )
+            .unwrap();
+        }
+        html.push_str("</ul>\n");
+
+        html.push_str("<h2>Collectors</h2>\n<ul>\n");
+        for (name, enabled) in self.collectors.iter() {
+            let state = if *enabled { "enabled" } else { "disabled" };
+            writeln!(html, "<li>{}: {}</li>", escape(name), state).unwrap();
+        }
+        html.push_str("</ul>\n");
+
+        if !self.upstreams.is_empty() {
+            html.push_str("<h2>Upstreams</h2>\n<ul>\n");
+            for upstream in self.upstreams.iter() {
+                writeln!(html, "<li>{}</li>", escape(upstream)).unwrap();
+            }
+            html.push_str("</ul>\n");
+        }
+
+        html.push_str("<h2>GPUs</h2>\n");
+        if self.devices.is_empty() {
+            html.push_str("<p>No GPU has been collected yet.</p>\n");
+        } else {
+            html.push_str(
+                "<table>\n<tr><th>Index</th><th>Name</th><th>Utilization</th>\
+                 <th>Memory</th><th>Temperature</th></tr>\n",
+            );
+            for device in self.devices.iter() {
+                let utilization = device
+                    .utilization_gpu
+                    .map(|x| format!("{}%", x))
+                    .unwrap_or_else(|| "-".to_string());
+                let memory = match (device.memory_used, device.memory_total) {
+                    (Some(used), Some(total)) => format!(
+                        "{} / {}",
+                        format_size(used, BINARY),
+                        format_size(total, BINARY)
+                    ),
+                    _ => "-".to_string(),
+                };
+                let temperature = device
+                    .temperature
+                    .map(|x| format!("{} &deg;C", x))
+                    .unwrap_or_else(|| "-".to_string());
+                writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    device.index,
+                    escape(&device.name),
+                    utilization,
+                    memory,
+                    temperature,
+                )
+                .unwrap();
+            }
+            html.push_str("</table>\n");
+        }
+
+        if !self.targets.is_empty() {
+            let alive = self.targets.iter().filter(|x| x.alive).count();
+            writeln!(
+                html,
+                "<h2>Alive targets</h2>\n<p>{} of {} targets are alive.</p>\n<ul>",
+                alive,
+                self.targets.len()
+            )
+            .unwrap();
+            for target in self.targets.iter() {
+                let state = if target.alive { "up" } else { "down" };
+                writeln!(
+                    html,
+                    "<li>{} ({}): {}</li>",
+                    escape(&target.hostname),
+                    escape(&target.url),
+                    state
+                )
+                .unwrap();
+            }
+            html.push_str("</ul>\n");
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
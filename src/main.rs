@@ -1,28 +1,69 @@
+mod accounting;
+mod config;
+mod energy;
+mod enforcement;
+mod exposition;
+mod idle;
+mod landing;
 mod metrics;
+mod notifications;
 mod nvml_metrics;
+mod nvml_raw;
+mod processes;
+mod quotas;
+mod relabel;
+mod sampler;
+mod simulate;
+mod teams;
+mod terminals;
+mod textfile;
+mod upstream;
 mod utils;
+mod workloads;
+mod xid;
 
 use actix_web::http::header::ContentEncoding;
-use actix_web::http::Uri;
+use actix_web::http::{StatusCode, Uri};
 use anyhow::{Context, Result};
 use clap::Parser;
 // use env_logger::Env;
 use awc::Client;
 
-use std::path::PathBuf;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::Arc;
 use std::time::Duration;
 
-use actix_web::{get, middleware, web, App, HttpResponse, HttpServer};
+use actix_web::rt::time;
+use actix_web::web::Bytes;
+use actix_web::{get, middleware, web, App, HttpRequest, HttpResponse, HttpServer};
 use prometheus_client::encoding::text::encode;
 
 use prometheus_client::registry::Registry;
 use std::net::SocketAddr;
 
+use crate::accounting::{Accounting, AccountingMeter, AccountingQuery, ExportFormat};
+use crate::config::Config;
+use crate::enforcement::Enforcer;
+use crate::idle::IdleDetector;
+use crate::landing::LandingPage;
 use crate::metrics::KeepAliveConfig;
-use crate::nvml_metrics::NvmlMetricsCollector;
-use crate::utils::IntoHttpError;
+use crate::notifications::Notifier;
+use crate::nvml_metrics::{GpuSource, NvmlMetricsCollector};
+use crate::processes::ProcessSelector;
+use crate::quotas::QuotaEvaluator;
+use crate::relabel::Relabeler;
+use crate::sampler::{Sampler, SamplerStatus};
+use crate::simulate::{Scenario, SimulatedGpuSource};
+use crate::teams::{TeamMapper, TeamSource};
+use crate::textfile::TextfileCollector;
+use crate::upstream::Upstream;
+use crate::utils::{IntoHttpError, UserPolicy};
+use crate::workloads::WorkloadClassifier;
+use crate::xid::XidCollector;
+use users::{gid_t, uid_t};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -47,22 +88,61 @@ struct Args {
     #[arg(short, long, default_value = "9100")]
     upstream_port: u16,
 
+    /// Upstream URL, either http://host:port or unix:///path/to/socket. Overrides --upstream-port
+    #[arg(long)]
+    upstream_url: Option<String>,
+
+    /// Also listen on a Unix domain socket
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+
+    /// Permission bits of the Unix domain socket, in octal
+    #[arg(long, default_value = "660")]
+    unix_socket_mode: String,
+
+    /// Owner of the Unix domain socket, as user[:group]
+    #[arg(long)]
+    unix_socket_owner: Option<String>,
+
+    /// Do not listen on the TCP address, only on the Unix domain socket
+    #[arg(long, requires = "unix_socket")]
+    no_tcp: bool,
+
     /// Keep alive check service
     #[arg(long)]
     alive_check: bool,
 
     #[arg(long, default_value = "/etc/syswatch.toml")]
     alive_check_config: PathBuf,
+
+    /// Configuration file
+    #[arg(long, default_value = "/etc/syswatch.toml")]
+    config: PathBuf,
+
+    /// Simulate GPUs described by a scenario file instead of using NVML
+    #[arg(long)]
+    simulate: Option<PathBuf>,
 }
 
+const SCRAPE_TIMEOUT_HEADER: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+const SCRAPE_TIMEOUT_MARGIN: Duration = Duration::from_millis(500);
+
 struct AppState {
     registry: Registry,
-    collector: NvmlMetricsCollector,
+    sampler: Arc<SamplerStatus>,
 }
 
 struct AppReadOnlyConfig {
-    upstream: bool,
-    upstream_port: u16,
+    upstream: Option<Upstream>,
+    relabeler: Relabeler,
+    textfile: Option<TextfileCollector>,
+    scrape_timeout: Duration,
+    alive_check: bool,
+    xid: bool,
+    idle: bool,
+    quotas: bool,
+    accounting: Option<Arc<Accounting>>,
+    simulated: bool,
 }
 
 fn main() -> Result<()> {
@@ -75,70 +155,323 @@ fn main() -> Result<()> {
         .parse()
         .with_context(|| "Cannot parse listen address")?;
 
+    let upstream = if args.combine_with_upstream {
+        let url = args
+            .upstream_url
+            .clone()
+            .unwrap_or_else(|| format!("http://127.0.0.1:{}", args.upstream_port));
+        Some(Upstream::parse(&url)?)
+    } else {
+        None
+    };
+    let unix_socket_mode = u32::from_str_radix(&args.unix_socket_mode, 8)
+        .with_context(|| "Cannot parse unix socket mode")?;
+    let unix_socket_owner = args
+        .unix_socket_owner
+        .as_deref()
+        .map(utils::parse_owner)
+        .transpose()?;
+
     let keep_alive_config = read_keep_alive_config(&args)?;
+    let syswatch_config = Config::load(&args.config)?;
+    for warning in syswatch_config.warnings() {
+        eprintln!("Configuration warning: {}", warning);
+    }
+    let relabeler = Relabeler::new(
+        &syswatch_config.static_labels,
+        &syswatch_config.metric_relabel_configs,
+    )
+    .with_context(|| "Relabel configuration error")?;
 
-    let collector = NvmlMetricsCollector::new(args.show_all_users)?;
+    let user_policy = UserPolicy::new(&syswatch_config.users);
+    let collector: Box<dyn GpuSource> = match &args.simulate {
+        Some(scenario) => {
+            println!("Simulating GPUs from {}", scenario.to_string_lossy());
+            Box::new(SimulatedGpuSource::new(Scenario::load(scenario)?))
+        }
+        None => Box::new(NvmlMetricsCollector::new(
+            args.show_all_users,
+            user_policy.clone(),
+        )?),
+    };
     let metrics = web::Data::new(metrics::Metrics::new());
     let alive_status = web::Data::new(metrics::AliveStatus::default());
+    let scrape_status = web::Data::new(metrics::ScrapeStatus::default());
+    let textfile_status = web::Data::new(metrics::TextfileStatus::default());
+    let xid_status = web::Data::new(metrics::XidStatus::default());
+    let quota_status = web::Data::new(metrics::QuotaStatus::default());
+    let accounting = match &syswatch_config.accounting {
+        Some(accounting_config) => Some(Arc::new(
+            Accounting::load(accounting_config)
+                .with_context(|| "Cannot restore the accounting state")?,
+        )),
+        None => None,
+    };
 
-    let registry = build_registry(&metrics, &alive_status);
+    let registry = build_registry(
+        &metrics,
+        &alive_status,
+        &scrape_status,
+        &textfile_status,
+        &xid_status,
+        &quota_status,
+        accounting.as_deref(),
+    );
+    // Textfiles may not define the families of syswatch
+    let reserved_families = {
+        let mut text = String::new();
+        encode(&mut text, &registry).unwrap();
+        exposition::parse(&text).with_context(|| "Cannot parse the exported metrics")?
+    };
 
-    let state = web::Data::new(Mutex::new(AppState {
-        registry,
+    let mut sampler = Sampler::new(
+        &syswatch_config.sampler,
+        WorkloadClassifier::new(&syswatch_config.workloads),
         collector,
-    }));
+        metrics.clone().into_inner(),
+    );
+    if let Some(process_config) = &syswatch_config.processes {
+        sampler.export_processes(
+            ProcessSelector::new(process_config).with_context(|| "Process configuration error")?,
+        );
+        println!(
+            "Per-process metrics are enabled, at most {} series",
+            process_config.max_series
+        );
+    }
+    if let Some(idle_config) = &syswatch_config.idle {
+        sampler.detect_idle(IdleDetector::new(idle_config));
+        println!(
+            "Idle detector is enabled, reporting holds longer than {} s",
+            idle_config.min_duration
+        );
+    }
+    if let Some(team_config) = &syswatch_config.teams {
+        sampler.aggregate_teams(TeamMapper::new(team_config));
+        let source = match team_config.source {
+            TeamSource::Primary => "primary groups",
+            TeamSource::Supplementary => "Unix groups",
+            TeamSource::Static => "the configuration",
+        };
+        println!("Team metrics are enabled, teams from {}", source);
+    }
+    if let Some(quota_config) = &syswatch_config.quotas {
+        sampler.enforce_quotas(
+            QuotaEvaluator::new(quota_config, quota_status.clone().into_inner())
+                .with_context(|| "Quota configuration error")?,
+        );
+        println!("Quotas are enabled, {} rules", quota_config.rules.len());
+        if let Some(enforcement_config) = &quota_config.enforcement {
+            sampler.enforce(
+                Enforcer::new(enforcement_config)
+                    .with_context(|| "Enforcement configuration error")?,
+            );
+            println!(
+                "Quota enforcement is enabled{}. Audit log = {}",
+                if enforcement_config.dry_run {
+                    " in dry run"
+                } else {
+                    ""
+                },
+                enforcement_config.audit_log.to_string_lossy()
+            );
+        }
+    }
+    if let Some(notification_config) = &syswatch_config.notifications {
+        sampler.notify(Notifier::new(
+            notification_config,
+            user_policy.clone(),
+            metrics.clone().into_inner(),
+        ));
+        println!(
+            "Notifications are enabled, at most one every {} s per user and reason",
+            notification_config.min_interval
+        );
+    }
+    if let (Some(accounting_config), Some(accounting)) = (&syswatch_config.accounting, &accounting)
+    {
+        sampler.account(AccountingMeter::new(accounting_config, accounting.clone()));
+        println!(
+            "Accounting is enabled. State file = {}",
+            accounting_config.state_file.to_string_lossy()
+        );
+    }
+    let state = web::Data::new(AppState {
+        registry,
+        sampler: sampler.status(),
+    });
+    sampler
+        .spawn()
+        .with_context(|| "Cannot start the sampler")?;
+    println!(
+        "Sampling GPUs every {} s over a window of {} s",
+        syswatch_config.sampler.interval, syswatch_config.sampler.window
+    );
+    if let Some(xid_config) = &syswatch_config.xid {
+        XidCollector::new(
+            xid_config,
+            metrics.clone().into_inner(),
+            xid_status.clone().into_inner(),
+        )
+        .spawn()
+        .with_context(|| "Cannot start the XID collector")?;
+        println!(
+            "XID collector is enabled. Path = {}",
+            xid_config.path.to_string_lossy()
+        );
+    }
 
     let config = web::Data::new(AppReadOnlyConfig {
-        upstream: args.combine_with_upstream,
-        upstream_port: args.upstream_port,
+        upstream: upstream.clone(),
+        relabeler,
+        textfile: syswatch_config
+            .textfile
+            .as_ref()
+            .map(|x| TextfileCollector::new(x, reserved_families)),
+        scrape_timeout: Duration::from_secs_f64(syswatch_config.scrape_timeout),
+        alive_check: args.alive_check,
+        xid: syswatch_config.xid.is_some(),
+        idle: syswatch_config.idle.is_some(),
+        quotas: syswatch_config.quotas.is_some(),
+        accounting: accounting.clone(),
+        simulated: args.simulate.is_some(),
     });
 
-    if args.combine_with_upstream {
-        println!("The upstream port is set as {}", args.upstream_port);
+    if let Some(upstream) = &upstream {
+        println!("The upstream is set as {}", upstream);
+    }
+    if let Some(textfile) = &config.textfile {
+        println!(
+            "Textfile collector is enabled. Directory = {}",
+            textfile.directory().to_string_lossy()
+        );
     }
 
-    println!(
-        "Exporter service is starting at http://{}:{}/metrics",
-        args.address, args.port
-    );
+    if !args.no_tcp {
+        println!(
+            "Exporter service is starting at http://{}:{}/metrics",
+            args.address, args.port
+        );
+    }
+    if let Some(unix_socket) = &args.unix_socket {
+        println!(
+            "Exporter service is starting at unix://{}",
+            unix_socket.to_string_lossy()
+        );
+    }
 
     actix_web::rt::System::new().block_on(async {
         if let Some(keep_alive_config) = keep_alive_config {
+            let alive_status = alive_status.clone();
             actix_web::rt::spawn(async move {
                 keep_alive_worker(keep_alive_config, alive_status).await
             });
         }
 
-        HttpServer::new(move || {
+        let mut server = HttpServer::new(move || {
             App::new()
                 .wrap(middleware::Compress::default())
                 .app_data(metrics.clone())
+                .app_data(scrape_status.clone())
+                .app_data(alive_status.clone())
+                .app_data(textfile_status.clone())
+                .app_data(xid_status.clone())
+                .app_data(quota_status.clone())
                 .app_data(state.clone())
                 .app_data(config.clone())
                 .app_data(web::Data::new(Client::new()))
+                .service(index_handler)
                 .service(upstream_handler)
                 .service(metrics_handler)
                 .service(status_handler)
                 .service(speedtest_handler)
+                .service(xid_handler)
+                .service(idle_handler)
+                .service(accounting_handler)
+                .service(quotas_handler)
         })
-        .workers(2)
-        .bind(addr)?
-        .run()
-        .await
+        .workers(2);
+        if !args.no_tcp {
+            server = server.bind(addr)?;
+        }
+        if let Some(unix_socket) = &args.unix_socket {
+            server = server.listen_uds(bind_unix_socket(
+                unix_socket,
+                unix_socket_mode,
+                unix_socket_owner,
+            )?)?;
+        }
+        server.run().await
     })?;
 
+    if let Some(accounting) = &accounting {
+        accounting
+            .save()
+            .with_context(|| "Cannot save the accounting state")?;
+    }
+
+    Ok(())
+}
+
+/// Removes a socket left behind by a previous run, which would make bind fail
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Binds the socket in a private directory and moves it into place once its
+/// mode and owner are set, so that it is never reachable with the default
+/// permissions
+fn bind_unix_socket(
+    path: &Path,
+    mode: u32,
+    owner: Option<(Option<uid_t>, Option<gid_t>)>,
+) -> std::io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let private = path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(format!(".syswatch-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("s");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        set_socket_permissions(&staged, mode, owner)?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&private)?;
+    listener
+}
+
+fn set_socket_permissions(
+    path: &Path,
+    mode: u32,
+    owner: Option<(Option<uid_t>, Option<gid_t>)>,
+) -> std::io::Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
     Ok(())
 }
 
 fn build_registry(
     metrics: &web::Data<metrics::Metrics>,
     alive_status: &web::Data<metrics::AliveStatus>,
+    scrape_status: &web::Data<metrics::ScrapeStatus>,
+    textfile_status: &web::Data<metrics::TextfileStatus>,
+    xid_status: &web::Data<metrics::XidStatus>,
+    quota_status: &web::Data<metrics::QuotaStatus>,
+    accounting: Option<&Accounting>,
 ) -> Registry {
     let mut registry = Registry::default();
     registry.register(
-        "node_nvidia_driver_status", 
+        "node_nvidia_driver_status",
         "NVML is funcitonal",
-        metrics.nvml_status.clone() 
+        metrics.nvml_status.clone(),
     );
     registry.register(
         "node_nvidia_driver_version",
@@ -185,99 +518,588 @@ fn build_registry(
         "Memory utilization of NVIDIA GPU",
         metrics.utilization_memory.clone(),
     );
+    registry.register(
+        "node_nvidia_utilization_gpu_ratio_window",
+        "GPU Utilization of NVIDIA GPU over the sampling window",
+        metrics.utilization_gpu_window.clone(),
+    );
+    registry.register(
+        "node_nvidia_power_usage_window",
+        "Power usage of NVIDIA GPU over the sampling window",
+        metrics.power_usage_window.clone(),
+    );
+    registry.register(
+        "node_nvidia_temperature_celsius_window",
+        "Temperature of NVIDIA GPU over the sampling window",
+        metrics.temperature_window.clone(),
+    );
+    registry.register(
+        "node_nvidia_clock_mhz",
+        "Current clock of NVIDIA GPU",
+        metrics.clock.clone(),
+    );
+    registry.register(
+        "node_nvidia_max_clock_mhz",
+        "Maximum clock of NVIDIA GPU",
+        metrics.max_clock.clone(),
+    );
+    registry.register(
+        "node_nvidia_clock_throttle_reason",
+        "Whether clocks of NVIDIA GPU are reduced for a reason",
+        metrics.throttle_reason.clone(),
+    );
+    registry.register(
+        "node_nvidia_power_limit_enforced",
+        "Enforced power limit of NVIDIA GPU",
+        metrics.power_limit_enforced.clone(),
+    );
+    registry.register(
+        "node_nvidia_power_limit_default",
+        "Default power limit of NVIDIA GPU",
+        metrics.power_limit_default.clone(),
+    );
+    registry.register(
+        "node_nvidia_performance_state",
+        "P-state of NVIDIA GPU, from 0 (maximum) to 15 (minimum performance)",
+        metrics.performance_state.clone(),
+    );
+    registry.register(
+        "node_nvidia_energy_joules",
+        "Energy consumed by NVIDIA GPU",
+        metrics.energy.clone(),
+    );
+    registry.register(
+        "node_nvidia_user_energy_joules",
+        "Energy of NVIDIA GPU attributed to a user by utilization share",
+        metrics.users_energy.clone(),
+    );
+    registry.register(
+        "node_nvidia_ecc_errors",
+        "ECC errors of NVIDIA GPU by counter, error type and memory location",
+        metrics.ecc_errors.clone(),
+    );
+    registry.register(
+        "node_nvidia_retired_pages",
+        "Memory pages of NVIDIA GPU retired after ECC errors",
+        metrics.retired_pages.clone(),
+    );
+    registry.register(
+        "node_nvidia_retired_pages_pending",
+        "Whether a page retirement of NVIDIA GPU waits for a reset",
+        metrics.retired_pages_pending.clone(),
+    );
+    registry.register(
+        "node_nvidia_remapped_rows",
+        "Memory rows of NVIDIA GPU remapped after errors",
+        metrics.remapped_rows.clone(),
+    );
+    registry.register(
+        "node_nvidia_remapped_rows_pending",
+        "Whether a row remapping of NVIDIA GPU waits for a reset",
+        metrics.remapped_rows_pending.clone(),
+    );
+    registry.register(
+        "node_nvidia_remapped_rows_failed",
+        "Whether a row remapping of NVIDIA GPU failed",
+        metrics.remapped_rows_failed.clone(),
+    );
+    registry.register(
+        "node_nvidia_health_ok",
+        "Whether NVIDIA GPU has no uncorrected memory errors, pending retirements or remappings",
+        metrics.health_ok.clone(),
+    );
+    registry.register(
+        "node_nvidia_pcie_link_gen",
+        "Current PCIe link generation of NVIDIA GPU",
+        metrics.pcie_link_gen.clone(),
+    );
+    registry.register(
+        "node_nvidia_pcie_link_width",
+        "Current PCIe link width of NVIDIA GPU",
+        metrics.pcie_link_width.clone(),
+    );
+    registry.register(
+        "node_nvidia_pcie_link_gen_max",
+        "Maximum PCIe link generation of NVIDIA GPU",
+        metrics.pcie_link_gen_max.clone(),
+    );
+    registry.register(
+        "node_nvidia_pcie_link_width_max",
+        "Maximum PCIe link width of NVIDIA GPU",
+        metrics.pcie_link_width_max.clone(),
+    );
+    registry.register(
+        "node_nvidia_pcie_throughput_bytes_per_second",
+        "PCIe throughput of NVIDIA GPU",
+        metrics.pcie_throughput.clone(),
+    );
+    registry.register(
+        "node_nvidia_pcie_replay",
+        "PCIe replays of NVIDIA GPU",
+        metrics.pcie_replay.clone(),
+    );
+    registry.register(
+        "node_nvidia_nvlink_active",
+        "Whether an NVLink link of NVIDIA GPU is active",
+        metrics.nvlink_active.clone(),
+    );
+    registry.register(
+        "node_nvidia_nvlink_utilization",
+        "NVLink utilization counter of NVIDIA GPU, in the configured unit",
+        metrics.nvlink_utilization.clone(),
+    );
+    registry.register(
+        "node_nvidia_nvlink_errors",
+        "NVLink errors of NVIDIA GPU",
+        metrics.nvlink_errors.clone(),
+    );
+    registry.register(
+        "node_nvidia_mig_info",
+        "MIG device of NVIDIA GPU, by GPU instance and compute instance",
+        metrics.mig_info.clone(),
+    );
+    registry.register(
+        "node_nvidia_mig_total_memory_bytes",
+        "Total memory of a MIG device",
+        metrics.mig_memory_total.clone(),
+    );
+    registry.register(
+        "node_nvidia_mig_used_memory_bytes",
+        "Used memory of a MIG device",
+        metrics.mig_memory_used.clone(),
+    );
+    registry.register(
+        "node_nvidia_mig_utilization_gpu_ratio",
+        "GPU utilization of a MIG device, where supported",
+        metrics.mig_utilization_gpu.clone(),
+    );
+    registry.register(
+        "node_nvidia_mig_utilization_memory_ratio",
+        "Memory utilization of a MIG device, where supported",
+        metrics.mig_utilization_memory.clone(),
+    );
     registry.register(
         "node_nvidia_user_used_memory_bytes",
         "User utilization of NVIDIA GPU",
         metrics.users_used_memory.clone(),
     );
+    registry.register(
+        "node_nvidia_user_utilization_ratio",
+        "Utilization of NVIDIA GPU by the processes of a user, counting the busiest engine of each",
+        metrics.users_utilization.clone(),
+    );
+    registry.register(
+        "node_nvidia_mig_user_used_memory_bytes",
+        "Memory used by a user on a MIG device",
+        metrics.users_mig_used_memory.clone(),
+    );
+    registry.register(
+        "node_nvidia_process_used_memory_bytes",
+        "GPU memory used by a process",
+        metrics.process_used_memory.clone(),
+    );
+    registry.register(
+        "node_nvidia_process_resident_memory_bytes",
+        "Host resident memory of a process using NVIDIA GPU",
+        metrics.process_rss.clone(),
+    );
+    registry.register(
+        "node_nvidia_process_start_time_seconds",
+        "Start time of a process using NVIDIA GPU",
+        metrics.process_start_time.clone(),
+    );
+    registry.register(
+        "syswatch_process_series_dropped",
+        "Count of processes left out of the per-process series by max_series",
+        metrics.process_series_dropped.clone(),
+    );
+    registry.register(
+        "node_nvidia_workload_used_memory_bytes",
+        "GPU memory used by a container, job or service, classified by cgroup",
+        metrics.workload_used_memory.clone(),
+    );
+    registry.register(
+        "node_nvidia_idle_hold_seconds",
+        "Longest time a process of a user has held NVIDIA GPU memory while idle",
+        metrics.idle_hold.clone(),
+    );
+    registry.register(
+        "node_nvidia_quota_violation",
+        "User over a quota rule of NVIDIA GPUs",
+        quota_status.violation.clone(),
+    );
+    if let Some(accounting) = accounting {
+        registry.register(
+            "node_nvidia_user_gpu_seconds",
+            "Time NVIDIA GPUs were held by a user, summed over the cards",
+            accounting.gpu_seconds.clone(),
+        );
+        registry.register(
+            "node_nvidia_user_memory_byte_seconds",
+            "NVIDIA GPU memory held by a user integrated over time",
+            accounting.memory_byte_seconds.clone(),
+        );
+    }
     registry.register(
         "node_nvidia_user_cards",
         "Count of GPUs used by a user",
         metrics.users_used_cards.clone(),
     );
+    registry.register(
+        "node_nvidia_team_used_memory_bytes",
+        "GPU memory used by the members of a team",
+        metrics.teams_used_memory.clone(),
+    );
+    registry.register(
+        "node_nvidia_team_cards",
+        "Count of GPUs used by the members of a team",
+        metrics.teams_used_cards.clone(),
+    );
+    registry.register(
+        "syswatch_nvml_field_errors",
+        "Count of failures reading a field of NVIDIA GPU",
+        metrics.field_errors.clone(),
+    );
+    registry.register(
+        "nvml_reinit",
+        "Count of NVML reinitializations after the handle became stale",
+        metrics.nvml_reinit.clone(),
+    );
+    registry.register(
+        "syswatch_notifications",
+        "Count of messages to the terminals of a user by reason and result",
+        metrics.notifications.clone(),
+    );
     registry.register(
         "node_alive_status",
         "Alive status of machine",
         alive_status.alive_status.clone(),
     );
+    registry.register(
+        "syswatch_collector_timeout",
+        "Whether a collector ran out of time during the last scrape",
+        scrape_status.collector_timeout.clone(),
+    );
+    registry.register(
+        "syswatch_collector_error",
+        "Whether a collector failed during the last scrape",
+        scrape_status.collector_error.clone(),
+    );
+    registry.register(
+        "syswatch_textfile_directory_error",
+        "Whether the textfile directory could not be read",
+        textfile_status.directory_error.clone(),
+    );
+    registry.register(
+        "syswatch_textfile_mtime_seconds",
+        "Modification time of a textfile",
+        textfile_status.mtime.clone(),
+    );
+    registry.register(
+        "syswatch_textfile_parse_error",
+        "Whether a textfile could not be read, parsed or merged",
+        textfile_status.parse_error.clone(),
+    );
+    registry.register(
+        "node_nvidia_xid_errors",
+        "Count of Xid errors reported by the NVIDIA driver",
+        xid_status.errors.clone(),
+    );
+    registry.register(
+        "node_nvidia_xid_last_seen_timestamp_seconds",
+        "Time of the latest Xid error reported by the NVIDIA driver",
+        xid_status.last_seen.clone(),
+    );
 
     registry
 }
 
 #[get("/metrics")]
 async fn metrics_handler(
-    state: web::Data<Mutex<AppState>>,
+    request: HttpRequest,
+    state: web::Data<AppState>,
     metrics: web::Data<metrics::Metrics>,
+    scrape_status: web::Data<metrics::ScrapeStatus>,
+    textfile_status: web::Data<metrics::TextfileStatus>,
     http_client: web::Data<Client>,
     config: web::Data<AppReadOnlyConfig>,
 ) -> actix_web::Result<HttpResponse> {
-    let response: Option<_> = if config.upstream {
-        let response = http_client
-            .get(format!("http://127.0.0.1:{}/metrics", config.upstream_port))
-            .send();
+    let timeout = scrape_timeout(&request, config.scrape_timeout);
 
-        Some(response)
-    } else {
-        None
+    let upstream = async {
+        let upstream = config.upstream.as_ref()?;
+        let fetched = time::timeout(timeout, fetch_upstream(&http_client, upstream, timeout)).await;
+        scrape_status.set_timeout("upstream", fetched.is_err());
+        match fetched {
+            Ok(Ok(body)) => {
+                scrape_status.set_error("upstream", false);
+                Some(body)
+            }
+            Ok(Err(e)) => {
+                eprintln!("Upstream: {:#}", e);
+                scrape_status.set_error("upstream", true);
+                None
+            }
+            Err(_) => None,
+        }
     };
 
-    let mut body: Vec<u8> = {
-        let mut state = state.lock().unwrap();
-        if let Err(e) = metrics.update(&mut state.collector) {
-            eprintln!("Metric update failed: {}", e);
-            metrics.clear();
+    // The sampler collects in the background, it is only late when stuck
+    scrape_status.set_timeout("nvml", state.sampler.is_stale(timeout));
+    let upstream_body = upstream.await;
+    // Parsed before the local metrics are encoded, so that a parse error is
+    // reported in this scrape
+    let upstream_families = match &upstream_body {
+        Some(upstream_body) if !config.relabeler.is_empty() => {
+            match exposition::parse(&String::from_utf8_lossy(upstream_body)) {
+                Ok(families) => families,
+                Err(e) => {
+                    eprintln!("Upstream: cannot parse the metrics: {:#}", e);
+                    scrape_status.set_error("upstream", true);
+                    Vec::new()
+                }
+            }
         }
-        // .http_error("metric update failed", StatusCode::INTERNAL_SERVER_ERROR)?;
+        _ => Vec::new(),
+    };
+
+    let textfile_families = match &config.textfile {
+        Some(textfile) => textfile.collect(&textfile_status),
+        None => Vec::new(),
+    };
+
+    let encode_local = || {
         let mut body: String = String::new();
+        let _encode = metrics.lock_encode();
         encode(&mut body, &state.registry).unwrap();
-        body.into_bytes()
+        body
     };
+    let relabeling = !config.relabeler.is_empty();
+    if relabeling {
+        scrape_status.set_error("relabel", false);
+    }
+    let mut body = encode_local();
 
-    if let Some(response) = response {
-        let mut response = response
-            .await
-            .http_internal_error("Failed to get upstream data")?;
-
-        if response.status().is_server_error() {
-            return Ok(HttpResponse::InternalServerError().body("Failed to fetch upstream data"));
+    let local_families = if relabeling {
+        match exposition::parse(&body) {
+            Ok(families) => Some(families),
+            Err(e) => {
+                // Served without relabeling rather than failing the scrape,
+                // encoded again to report the error
+                eprintln!("Relabeling: cannot parse the metrics: {:#}", e);
+                scrape_status.set_error("relabel", true);
+                body = encode_local();
+                None
+            }
         }
-        body = [
-            response
-                .body()
-                .await
-                .http_internal_error("Failed to parse upstream data")?,
+    } else {
+        None
+    };
+
+    let body: Vec<u8> = if let Some(local_families) = local_families {
+        let mut families = upstream_families;
+        families.extend(textfile_families);
+        families.extend(local_families);
+        config.relabeler.apply(&mut families);
+
+        let mut text = String::new();
+        exposition::encode(&families, &mut text);
+        text.push_str("# EOF\n");
+        text.into_bytes()
+    } else {
+        let mut textfile_body = String::new();
+        exposition::encode(&textfile_families, &mut textfile_body);
+        [
+            upstream_body.unwrap_or_default(),
+            textfile_body.into(),
             body.into(),
         ]
-        .concat();
-    }
+        .concat()
+    };
     Ok(HttpResponse::Ok()
         .content_type("text/plain; version=0.0.4; charset=utf-8")
         .insert_header(("Access-Control-Allow-Origin", "*"))
         .body(body))
 }
 
+/// Derives the scrape deadline from the header sent by Prometheus, leaving a
+/// small margin for encoding and transferring the response.
+fn scrape_timeout(request: &HttpRequest, default: Duration) -> Duration {
+    let timeout = request
+        .headers()
+        .get(SCRAPE_TIMEOUT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite() && *value > 0.)
+        .map(Duration::from_secs_f64)
+        .unwrap_or(default);
+    timeout - (timeout / 10).min(SCRAPE_TIMEOUT_MARGIN)
+}
+
+async fn fetch_upstream(
+    http_client: &Client,
+    upstream: &Upstream,
+    timeout: Duration,
+) -> anyhow::Result<Bytes> {
+    let response = upstream.get(http_client, "metrics", timeout).await?;
+    if response.status.is_server_error() {
+        anyhow::bail!("Upstream responded {}", response.status);
+    }
+    Ok(response.body)
+}
+
 #[get("/")]
+async fn index_handler(
+    metrics: web::Data<metrics::Metrics>,
+    alive_status: web::Data<metrics::AliveStatus>,
+    config: web::Data<AppReadOnlyConfig>,
+) -> actix_web::Result<HttpResponse> {
+    let mut links = vec![
+        ("/metrics", "Prometheus metrics"),
+        ("/status", "Liveness of syswatch"),
+        ("/speedtest", "Download speed test payload"),
+    ];
+    let mut upstreams = Vec::new();
+    if let Some(upstream) = &config.upstream {
+        links.push(("/upstream/", "Pages of the upstream exporter"));
+        upstreams.push(upstream.to_string());
+    }
+    if config.xid {
+        links.push(("/xid", "Recent Xid errors"));
+    }
+    if config.idle {
+        links.push(("/idle", "Processes holding idle GPUs"));
+    }
+    if config.quotas {
+        links.push(("/quotas", "Quota violations"));
+    }
+    if config.accounting.is_some() {
+        links.push(("/accounting", "GPU usage of the users by day"));
+    }
+
+    let page = LandingPage {
+        collectors: vec![
+            ("nvml", !config.simulated),
+            ("simulation", config.simulated),
+            ("upstream", config.upstream.is_some()),
+            ("alive_check", config.alive_check),
+            ("relabel", !config.relabeler.is_empty()),
+            ("textfile", config.textfile.is_some()),
+            ("xid", config.xid),
+            ("idle", config.idle),
+            ("quotas", config.quotas),
+            ("accounting", config.accounting.is_some()),
+        ],
+        upstreams,
+        links,
+        devices: metrics.devices(),
+        targets: alive_status.targets(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page.render()))
+}
+
+#[get("/upstream/{path:.*}")]
 async fn upstream_handler(
+    request: HttpRequest,
     http_client: web::Data<Client>,
     config: web::Data<AppReadOnlyConfig>,
 ) -> actix_web::Result<HttpResponse> {
-    if !config.upstream {
+    let Some(upstream) = &config.upstream else {
         return Ok(HttpResponse::NotFound().into());
-    }
+    };
+    // Forwarded percent-encoded as received, the decoded path could carry
+    // line breaks into the request to the upstream
+    let path = request
+        .uri()
+        .path_and_query()
+        .and_then(|x| x.as_str().strip_prefix("/upstream/"));
+    let Some(path) = path else {
+        return Ok(HttpResponse::NotFound().into());
+    };
 
-    let mut response = http_client
-        .get(format!("http://127.0.0.1:{}", config.upstream_port))
-        .send()
+    let response = upstream
+        .get(&http_client, path, config.scrape_timeout)
         .await
         .http_internal_error("Failed to get upstream data")?;
+    let content_type = response
+        .content_type
+        .unwrap_or_else(|| "text/html; charset=utf-8".to_string());
+    Ok(HttpResponse::build(response.status)
+        .content_type(content_type)
+        .body(response.body))
+}
+
+#[get("/xid")]
+async fn xid_handler(
+    xid_status: web::Data<metrics::XidStatus>,
+    config: web::Data<AppReadOnlyConfig>,
+) -> actix_web::Result<HttpResponse> {
+    if !config.xid {
+        return Ok(HttpResponse::NotFound().into());
+    }
     Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(
-            response
-                .body()
-                .await
-                .http_internal_error("Failed to get upstream data")?,
-        ))
+        .insert_header(("Access-Control-Allow-Origin", "*"))
+        .json(xid_status.recent()))
+}
+
+#[get("/idle")]
+async fn idle_handler(
+    metrics: web::Data<metrics::Metrics>,
+    config: web::Data<AppReadOnlyConfig>,
+) -> actix_web::Result<HttpResponse> {
+    if !config.idle {
+        return Ok(HttpResponse::NotFound().into());
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(("Access-Control-Allow-Origin", "*"))
+        .json(metrics.idle_holds()))
+}
+
+#[get("/quotas")]
+async fn quotas_handler(
+    quota_status: web::Data<metrics::QuotaStatus>,
+    config: web::Data<AppReadOnlyConfig>,
+) -> actix_web::Result<HttpResponse> {
+    if !config.quotas {
+        return Ok(HttpResponse::NotFound().into());
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(("Access-Control-Allow-Origin", "*"))
+        .json(quota_status.history()))
+}
+
+#[get("/accounting")]
+async fn accounting_handler(
+    query: web::Query<AccountingQuery>,
+    config: web::Data<AppReadOnlyConfig>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(accounting) = &config.accounting else {
+        return Ok(HttpResponse::NotFound().into());
+    };
+    let parse_date = |date: &Option<String>| {
+        date.as_deref()
+            .map(|x| chrono::NaiveDate::parse_from_str(x, accounting::DATE_FORMAT))
+            .transpose()
+            .http_error(
+                "Dates should be formatted as YYYY-MM-DD",
+                StatusCode::BAD_REQUEST,
+            )
+    };
+    let rows = accounting.report(
+        parse_date(&query.from)?,
+        parse_date(&query.to)?,
+        query.bucket,
+    );
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("Access-Control-Allow-Origin", "*"));
+    Ok(match query.format {
+        ExportFormat::Json => response.json(rows),
+        ExportFormat::Csv => response
+            .content_type("text/csv; charset=utf-8")
+            .body(accounting::to_csv(&rows)),
+    })
 }
 
 #[get("/status")]
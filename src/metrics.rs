@@ -1,15 +1,25 @@
-use crate::nvml_metrics::{NvmlDevice, NvmlMetricsCollector, NvmlUserUtilization};
-use anyhow::Context;
+use crate::energy::EnergyUse;
+use crate::idle::IdleHold;
+use crate::nvml_metrics::{
+    cards_by_user, FieldError, MigDevice, NvmlDevice, NvmlMetrics, NvmlProcess,
+    NvmlUserUtilization, CLOCK_DOMAINS, ECC_COUNTERS, MEMORY_ERROR_TYPES, MEMORY_LOCATIONS,
+    NVLINK_ERROR_COUNTERS, NVLINK_MAX_LINKS, THROTTLE_REASONS,
+};
+use crate::quotas::{QuotaEpisode, QuotaViolation};
+use crate::sampler::{SampleWindow, WindowStats};
+use crate::teams::TeamUsage;
+use crate::workloads::WorkloadUsage;
+use crate::xid::XidEvent;
 use prometheus_client::encoding::EncodeLabelSet;
 use prometheus_client::metrics::family::Family;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
 use std::sync::atomic::AtomicU64;
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use anyhow::Result;
-
-use prometheus_client::metrics::gauge::Gauge;
-
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::{Atomic, Gauge};
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct DeviceLabel {
@@ -19,6 +29,17 @@ pub struct DeviceLabel {
     pub uuid: String,
 }
 
+impl From<&NvmlDevice> for DeviceLabel {
+    fn from(device: &NvmlDevice) -> Self {
+        DeviceLabel {
+            index: device.index,
+            minor_number: device.minor_number,
+            name: device.name.clone(),
+            uuid: device.uuid.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct UserLabel {
     pub index: u32,
@@ -35,17 +56,194 @@ pub struct DeviceMinorLabel {
     pub minor_number: u32,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceClockLabel {
+    pub minor_number: u32,
+    pub clock: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceThrottleLabel {
+    pub minor_number: u32,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceEccLabel {
+    pub minor_number: u32,
+    pub counter: String,
+    pub error_type: String,
+    pub location: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceRetirementLabel {
+    pub minor_number: u32,
+    pub cause: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceErrorTypeLabel {
+    pub minor_number: u32,
+    pub error_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceDirectionLabel {
+    pub minor_number: u32,
+    pub direction: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceLinkLabel {
+    pub minor_number: u32,
+    pub link: u32,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceLinkDirectionLabel {
+    pub minor_number: u32,
+    pub link: u32,
+    pub direction: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceLinkErrorLabel {
+    pub minor_number: u32,
+    pub link: u32,
+    pub counter: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MigLabel {
+    pub minor_number: u32,
+    pub gi_id: u32,
+    pub ci_id: u32,
+    pub profile: String,
+}
+
+impl MigLabel {
+    fn new(minor_number: u32, mig_device: &MigDevice) -> MigLabel {
+        MigLabel {
+            minor_number,
+            gi_id: mig_device.instance.gpu_instance_id,
+            ci_id: mig_device.instance.compute_instance_id,
+            profile: mig_device.profile.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MigInfoLabel {
+    pub minor_number: u32,
+    pub gi_id: u32,
+    pub ci_id: u32,
+    pub profile: String,
+    pub uuid: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ProcessLabel {
+    pub pid: u32,
+    pub user_name: String,
+    pub command: String,
+    pub minor_number: u32,
+    pub process_type: String,
+}
+
+impl From<&NvmlProcess> for ProcessLabel {
+    fn from(process: &NvmlProcess) -> Self {
+        ProcessLabel {
+            pid: process.pid,
+            user_name: process.user_name.clone().unwrap_or_default(),
+            command: process.command.clone(),
+            minor_number: process.minor_number,
+            process_type: process.process_type.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct WorkloadLabel {
+    pub minor_number: u32,
+    pub workload_type: String,
+    pub workload_id: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MigUserLabel {
+    pub index: u32,
+    pub gi_id: u32,
+    pub ci_id: u32,
+    pub user_name: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceStatLabel {
+    pub minor_number: u32,
+    pub stat: String,
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct UserNameLabel {
     pub user_name: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TeamLabel {
+    pub index: u32,
+    pub team: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TeamNameLabel {
+    pub team: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FieldErrorLabel {
+    pub field: String,
+    pub minor_number: String,
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct WatchdogLabel {
     pub hostname: String,
     pub url: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CollectorLabel {
+    pub collector: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FileLabel {
+    pub file: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct QuotaLabel {
+    pub user_name: String,
+    pub rule: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NotificationLabel {
+    pub user_name: String,
+    /// `idle` or `quota`
+    pub reason: String,
+    /// `delivered`, `no_terminal`, `unknown_user` or `failed`
+    pub result: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct XidLabel {
+    /// Empty when the PCI address matches no known device
+    pub minor_number: String,
+    pub xid: u32,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct KeepAliveConfig {
     pub interval: u64,
@@ -58,6 +256,26 @@ pub struct KeepAliveItem {
     pub url: String,
 }
 
+/// Latest state of a GPU, shown on the landing page
+#[derive(Clone, Debug)]
+pub struct DeviceSummary {
+    pub index: u32,
+    pub minor_number: u32,
+    pub name: String,
+    pub pci_bus_id: Option<String>,
+    pub utilization_gpu: Option<u32>,
+    pub memory_used: Option<u64>,
+    pub memory_total: Option<u64>,
+    pub temperature: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AliveTarget {
+    pub hostname: String,
+    pub url: String,
+    pub alive: bool,
+}
+
 #[derive(Default)]
 pub struct Metrics {
     pub nvml_status: Gauge,
@@ -70,13 +288,131 @@ pub struct Metrics {
     pub temperature: Family<DeviceMinorLabel, Gauge>,
     pub utilization_gpu: Family<DeviceMinorLabel, Gauge<f64, AtomicU64>>,
     pub utilization_memory: Family<DeviceMinorLabel, Gauge<f64, AtomicU64>>,
+    pub utilization_gpu_window: Family<DeviceStatLabel, Gauge<f64, AtomicU64>>,
+    pub power_usage_window: Family<DeviceStatLabel, Gauge<f64, AtomicU64>>,
+    pub temperature_window: Family<DeviceStatLabel, Gauge<f64, AtomicU64>>,
+    pub clock: Family<DeviceClockLabel, Gauge>,
+    pub max_clock: Family<DeviceClockLabel, Gauge>,
+    pub throttle_reason: Family<DeviceThrottleLabel, Gauge>,
+    pub power_limit_enforced: Family<DeviceMinorLabel, Gauge>,
+    pub power_limit_default: Family<DeviceMinorLabel, Gauge>,
+    pub performance_state: Family<DeviceMinorLabel, Gauge>,
+    /// Energy since syswatch started, kept across failed collections like
+    /// the energy of the users
+    pub energy: Family<DeviceMinorLabel, Counter<f64, AtomicU64>>,
+    pub ecc_errors: Family<DeviceEccLabel, Counter>,
+    pub retired_pages: Family<DeviceRetirementLabel, Counter>,
+    pub retired_pages_pending: Family<DeviceMinorLabel, Gauge>,
+    pub remapped_rows: Family<DeviceErrorTypeLabel, Counter>,
+    pub remapped_rows_pending: Family<DeviceMinorLabel, Gauge>,
+    pub remapped_rows_failed: Family<DeviceMinorLabel, Gauge>,
+    pub health_ok: Family<DeviceMinorLabel, Gauge>,
+    pub pcie_link_gen: Family<DeviceMinorLabel, Gauge>,
+    pub pcie_link_width: Family<DeviceMinorLabel, Gauge>,
+    pub pcie_link_gen_max: Family<DeviceMinorLabel, Gauge>,
+    pub pcie_link_width_max: Family<DeviceMinorLabel, Gauge>,
+    pub pcie_throughput: Family<DeviceDirectionLabel, Gauge>,
+    pub pcie_replay: Family<DeviceMinorLabel, Counter>,
+    pub nvlink_active: Family<DeviceLinkLabel, Gauge>,
+    pub nvlink_utilization: Family<DeviceLinkDirectionLabel, Counter>,
+    pub nvlink_errors: Family<DeviceLinkErrorLabel, Counter>,
+    pub mig_info: Family<MigInfoLabel, Gauge>,
+    pub mig_memory_total: Family<MigLabel, Gauge>,
+    pub mig_memory_used: Family<MigLabel, Gauge>,
+    pub mig_utilization_gpu: Family<MigLabel, Gauge<f64, AtomicU64>>,
+    pub mig_utilization_memory: Family<MigLabel, Gauge<f64, AtomicU64>>,
+    /// Kept across failed collections, as it is used for billing
+    pub users_energy: Family<UserLabel, Counter<f64, AtomicU64>>,
     pub users_used_memory: Family<UserLabel, Gauge>,
+    pub users_utilization: Family<UserLabel, Gauge<f64, AtomicU64>>,
+    pub users_mig_used_memory: Family<MigUserLabel, Gauge>,
+    pub process_used_memory: Family<ProcessLabel, Gauge>,
+    pub process_rss: Family<ProcessLabel, Gauge>,
+    pub process_start_time: Family<ProcessLabel, Gauge<f64, AtomicU64>>,
+    pub process_series_dropped: Gauge,
+    pub workload_used_memory: Family<WorkloadLabel, Gauge>,
+    pub idle_hold: Family<UserLabel, Gauge<f64, AtomicU64>>,
     pub users_used_cards: Family<UserNameLabel, Gauge>,
+    pub teams_used_memory: Family<TeamLabel, Gauge>,
+    pub teams_used_cards: Family<TeamNameLabel, Gauge>,
+    pub field_errors: Family<FieldErrorLabel, Counter>,
+    pub nvml_reinit: Counter,
+    pub notifications: Family<NotificationLabel, Counter>,
+    devices: Mutex<Vec<DeviceSummary>>,
+    idle_holds: Mutex<Vec<IdleHold>>,
+    /// Held by the sampler while it updates the families, and by scrapes
+    /// while they encode them
+    exposition: RwLock<()>,
+    seen_versions: SeenLabels<VersionLabel>,
+    seen_devices: SeenLabels<DeviceLabel>,
+    seen_minors: SeenLabels<DeviceMinorLabel>,
+}
+
+/// Label sets exposed by the previous collection, so that series of vanished
+/// devices can be removed without clearing the whole family
+struct SeenLabels<L> {
+    labels: Mutex<HashSet<L>>,
+}
+
+impl<L> Default for SeenLabels<L> {
+    fn default() -> Self {
+        SeenLabels {
+            labels: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<L: Clone + Hash + Eq> SeenLabels<L> {
+    /// Records the label sets of this collection and returns the stale ones
+    fn replace(&self, current: HashSet<L>) -> Vec<L> {
+        let previous = std::mem::replace(&mut *self.labels.lock().unwrap(), current.clone());
+        previous.difference(&current).cloned().collect()
+    }
+
+    fn clear(&self) {
+        self.labels.lock().unwrap().clear();
+    }
 }
 
 #[derive(Default)]
 pub struct AliveStatus {
     pub alive_status: Family<WatchdogLabel, Gauge>,
+    targets: Mutex<BTreeMap<String, AliveTarget>>,
+}
+
+#[derive(Default)]
+pub struct ScrapeStatus {
+    pub collector_timeout: Family<CollectorLabel, Gauge>,
+    pub collector_error: Family<CollectorLabel, Gauge>,
+}
+
+#[derive(Default)]
+pub struct TextfileStatus {
+    pub directory_error: Gauge,
+    pub mtime: Family<FileLabel, Gauge<f64, AtomicU64>>,
+    pub parse_error: Family<FileLabel, Gauge>,
+    /// Files of the latest collection
+    files: Mutex<HashSet<String>>,
+}
+
+/// Outcome of reading a textfile, `mtime` being unset when it is unknown
+pub struct TextfileResult {
+    pub file: String,
+    pub mtime: Option<f64>,
+    pub error: bool,
+}
+
+#[derive(Default)]
+pub struct XidStatus {
+    pub errors: Family<XidLabel, Counter>,
+    pub last_seen: Family<XidLabel, Gauge<f64, AtomicU64>>,
+    recent: Mutex<VecDeque<XidEvent>>,
+}
+
+#[derive(Default)]
+pub struct QuotaStatus {
+    pub violation: Family<QuotaLabel, Gauge>,
+    history: Mutex<VecDeque<QuotaEpisode>>,
 }
 
 impl Metrics {
@@ -94,47 +430,217 @@ impl Metrics {
         self.temperature.clear();
         self.utilization_gpu.clear();
         self.utilization_memory.clear();
+        self.utilization_gpu_window.clear();
+        self.power_usage_window.clear();
+        self.temperature_window.clear();
+        self.clock.clear();
+        self.max_clock.clear();
+        self.throttle_reason.clear();
+        self.power_limit_enforced.clear();
+        self.power_limit_default.clear();
+        self.performance_state.clear();
+        self.ecc_errors.clear();
+        self.retired_pages.clear();
+        self.retired_pages_pending.clear();
+        self.remapped_rows.clear();
+        self.remapped_rows_pending.clear();
+        self.remapped_rows_failed.clear();
+        self.health_ok.clear();
+        self.pcie_link_gen.clear();
+        self.pcie_link_width.clear();
+        self.pcie_link_gen_max.clear();
+        self.pcie_link_width_max.clear();
+        self.pcie_throughput.clear();
+        self.pcie_replay.clear();
+        self.nvlink_active.clear();
+        self.nvlink_utilization.clear();
+        self.nvlink_errors.clear();
+        self.clear_mig();
         self.users_used_memory.clear();
+        self.users_utilization.clear();
+        self.users_mig_used_memory.clear();
         self.users_used_cards.clear();
+        self.teams_used_memory.clear();
+        self.teams_used_cards.clear();
+        self.clear_processes();
+        self.workload_used_memory.clear();
+        self.idle_hold.clear();
+        self.devices.lock().unwrap().clear();
+        self.idle_holds.lock().unwrap().clear();
+        self.seen_versions.clear();
+        self.seen_devices.clear();
+        self.seen_minors.clear();
     }
 
-    pub fn update(&self, collector: &mut NvmlMetricsCollector) -> Result<()> {
-        let state = collector
-            .now()
-            .with_context(|| {
-                self.nvml_status.set(0);
-                "Failed to update metrics"
-            })?;
+    /// Keeps scrapes out until the guard is dropped, so that the updates of a
+    /// collection are exposed at once
+    pub fn lock_update(&self) -> RwLockWriteGuard<'_, ()> {
+        // The lock guards no data, a sampler panicking while holding it
+        // leaves nothing inconsistent
+        self.exposition.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Keeps the sampler from updating the families until the guard is dropped
+    pub fn lock_encode(&self) -> RwLockReadGuard<'_, ()> {
+        self.exposition.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn devices(&self) -> Vec<DeviceSummary> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    pub fn idle_holds(&self) -> Vec<IdleHold> {
+        self.idle_holds.lock().unwrap().clone()
+    }
+
+    pub fn add_field_errors(&self, errors: &[FieldError]) {
+        for error in errors.iter() {
+            self.field_errors
+                .get_or_create(&FieldErrorLabel {
+                    field: error.field.clone(),
+                    minor_number: error
+                        .minor_number
+                        .map(|x| x.to_string())
+                        .unwrap_or_default(),
+                })
+                .inc();
+        }
+    }
+
+    /// Follows the reinitialization count of the collector
+    pub fn set_reinit_count(&self, reinit_count: u64) {
+        if reinit_count > self.nvml_reinit.get() {
+            self.nvml_reinit
+                .inc_by(reinit_count - self.nvml_reinit.get());
+        }
+    }
+
+    /// Marks NVML as broken and drops the series of the previous collection
+    pub fn set_failed(&self) {
+        self.nvml_status.set(0);
+        self.clear();
+    }
+
+    pub fn update(&self, state: &NvmlMetrics) {
+        let version = VersionLabel {
+            version: state.version.clone(),
+        };
+        for stale in self.seen_versions.replace(HashSet::from([version.clone()])) {
+            self.version.remove(&stale);
+        }
+        self.version.get_or_create(&version).set(1);
+
+        let current_devices = state.devices.iter().map(DeviceLabel::from).collect();
+        for stale in self.seen_devices.replace(current_devices) {
+            self.device_info.remove(&stale);
+        }
+        let current_minors = state
+            .devices
+            .iter()
+            .map(|x| DeviceMinorLabel {
+                minor_number: x.minor_number,
+            })
+            .collect();
+        for stale in self.seen_minors.replace(current_minors) {
+            self.remove_device(&stale);
+        }
 
-        self.update_nvml_version(state.version);
+        self.add_field_errors(&state.field_errors);
 
-        for device in state.devices {
+        let mut summaries = Vec::with_capacity(state.devices.len());
+        for device in state.devices.iter() {
+            summaries.push(DeviceSummary {
+                index: device.index,
+                minor_number: device.minor_number,
+                name: device.name.clone(),
+                pci_bus_id: device.pci_bus_id.clone(),
+                utilization_gpu: device.utilization_gpu,
+                memory_used: device.memory_used,
+                memory_total: device.memory_total,
+                temperature: device.temperature,
+            });
             self.update_nvml_device(device);
         }
+        *self.devices.lock().unwrap() = summaries;
+
+        // MIG devices come and go with reconfiguration, rebuild their series
+        self.clear_mig();
+        for device in state.devices.iter() {
+            for mig_device in device.mig_devices.iter() {
+                self.update_mig_device(device.minor_number, mig_device);
+            }
+        }
 
         self.users_used_memory.clear();
-        let mut count = HashMap::new();
+        self.users_utilization.clear();
+        self.users_mig_used_memory.clear();
+        let mut users_utilization: HashMap<UserLabel, f64> = HashMap::new();
         for user in state.users_utilization.iter() {
             if user.used_gpu_memory != 0 {
-                count
-                    .entry(user.user_name.clone())
-                    .and_modify(|x: &mut i64| *x += 1)
-                    .or_insert(1);
                 self.update_nvml_user_utilization(user);
+                if let Some(utilization) = user.utilization {
+                    let ulabel = UserLabel {
+                        user_name: user.user_name.clone(),
+                        index: user.index,
+                    };
+                    *users_utilization.entry(ulabel).or_default() += utilization as f64 / 100.;
+                }
             }
         }
+        // Processes busy on different engines may add up to more than the GPU
+        for (ulabel, utilization) in users_utilization {
+            self.users_utilization
+                .get_or_create(&ulabel)
+                .set(utilization.min(1.));
+        }
 
         self.users_used_cards.clear();
-        for (user_name, cnt) in count {
+        for (user_name, cnt) in cards_by_user(&state.users_utilization) {
             self.users_used_cards
-                .get_or_create(&UserNameLabel {
+                .get_or_create(&UserNameLabel { user_name })
+                .set(cnt as i64);
+        }
+
+        self.nvml_status.set(1);
+    }
+
+    pub fn add_energy(&self, energy: &EnergyUse) {
+        for (minor_number, joules) in energy.devices.iter() {
+            self.energy
+                .get_or_create(&DeviceMinorLabel {
+                    minor_number: *minor_number,
+                })
+                .inc_by(*joules);
+        }
+        for (index, user_name, joules) in energy.users.iter() {
+            self.users_energy
+                .get_or_create(&UserLabel {
+                    index: *index,
                     user_name: user_name.clone(),
                 })
-                .set(cnt);
+                .inc_by(*joules);
+        }
+    }
+
+    /// Exposes the min, max and mean over the window of the current devices
+    pub fn update_window(&self, window: &SampleWindow) {
+        for minor_number in window.minor_numbers() {
+            set_window_stats(
+                &self.utilization_gpu_window,
+                minor_number,
+                window.stats(minor_number, |x| x.utilization_gpu),
+            );
+            set_window_stats(
+                &self.power_usage_window,
+                minor_number,
+                window.stats(minor_number, |x| x.power_usage),
+            );
+            set_window_stats(
+                &self.temperature_window,
+                minor_number,
+                window.stats(minor_number, |x| x.temperature),
+            );
         }
-        
-        self.nvml_status.set(1);
-        Ok(())
     }
 
     fn update_nvml_user_utilization(&self, user: &NvmlUserUtilization) {
@@ -142,48 +648,491 @@ impl Metrics {
             user_name: user.user_name.clone(),
             index: user.index,
         };
+        // A user may hold several MIG devices of a GPU
         self.users_used_memory
             .get_or_create(&ulabel)
-            .set(user.used_gpu_memory as i64);
+            .inc_by(user.used_gpu_memory as i64);
+        if let Some(mig) = user.mig {
+            self.users_mig_used_memory
+                .get_or_create(&MigUserLabel {
+                    index: user.index,
+                    gi_id: mig.gpu_instance_id,
+                    ci_id: mig.compute_instance_id,
+                    user_name: user.user_name.clone(),
+                })
+                .set(user.used_gpu_memory as i64);
+        }
     }
 
-    fn update_nvml_version(&self, version: String) {
-        self.version.get_or_create(&VersionLabel { version }).set(1);
+    /// Exposes the selected processes, replacing the previous ones
+    pub fn update_processes(&self, processes: &[NvmlProcess], dropped: usize) {
+        self.clear_processes();
+        for process in processes.iter() {
+            let label = ProcessLabel::from(process);
+            set_or_remove(
+                &self.process_used_memory,
+                &label,
+                process.used_gpu_memory.map(|x| x as i64),
+            );
+            set_or_remove(&self.process_rss, &label, process.rss.map(|x| x as i64));
+            set_or_remove(&self.process_start_time, &label, process.start_time);
+        }
+        self.process_series_dropped.set(dropped as i64);
     }
 
-    fn update_nvml_device(&self, device: NvmlDevice) {
-        self.device_info
-            .get_or_create(&DeviceLabel {
-                index: device.index,
-                minor_number: device.minor_number,
-                name: device.name,
-                uuid: device.uuid,
+    pub fn update_workloads(&self, workloads: &[WorkloadUsage]) {
+        self.workload_used_memory.clear();
+        for usage in workloads.iter() {
+            self.workload_used_memory
+                .get_or_create(&WorkloadLabel {
+                    minor_number: usage.minor_number,
+                    workload_type: usage.workload.workload_type.to_string(),
+                    workload_id: usage.workload.workload_id.clone(),
+                })
+                .set(usage.used_gpu_memory as i64);
+        }
+    }
+
+    /// Exports the memory of each team on each GPU, and the count of GPUs
+    /// on which a team holds memory
+    pub fn update_teams(&self, teams: &[TeamUsage]) {
+        self.teams_used_memory.clear();
+        self.teams_used_cards.clear();
+        for usage in teams.iter() {
+            self.teams_used_memory
+                .get_or_create(&TeamLabel {
+                    index: usage.index,
+                    team: usage.team.clone(),
+                })
+                .set(usage.used_gpu_memory as i64);
+            self.teams_used_cards
+                .get_or_create(&TeamNameLabel {
+                    team: usage.team.clone(),
+                })
+                .inc();
+        }
+    }
+
+    /// Exports the longest idle hold of each user on each GPU
+    pub fn update_idle(&self, holds: Vec<IdleHold>) {
+        self.idle_hold.clear();
+        for hold in holds.iter() {
+            let gauge = self.idle_hold.get_or_create(&UserLabel {
+                index: hold.index,
+                user_name: hold.user_name.clone(),
+            });
+            if hold.duration > gauge.get() {
+                gauge.set(hold.duration);
+            }
+        }
+        *self.idle_holds.lock().unwrap() = holds;
+    }
+
+    fn clear_processes(&self) {
+        self.process_used_memory.clear();
+        self.process_rss.clear();
+        self.process_start_time.clear();
+    }
+
+    fn clear_mig(&self) {
+        self.mig_info.clear();
+        self.mig_memory_total.clear();
+        self.mig_memory_used.clear();
+        self.mig_utilization_gpu.clear();
+        self.mig_utilization_memory.clear();
+    }
+
+    fn update_mig_device(&self, minor_number: u32, mig_device: &MigDevice) {
+        let label = MigLabel::new(minor_number, mig_device);
+        self.mig_info
+            .get_or_create(&MigInfoLabel {
+                minor_number,
+                gi_id: label.gi_id,
+                ci_id: label.ci_id,
+                profile: label.profile.clone(),
+                uuid: mig_device.uuid.clone(),
             })
             .set(1);
+        set_or_remove(
+            &self.mig_memory_total,
+            &label,
+            mig_device.memory_total.map(|x| x as i64),
+        );
+        set_or_remove(
+            &self.mig_memory_used,
+            &label,
+            mig_device.memory_used.map(|x| x as i64),
+        );
+        set_or_remove(
+            &self.mig_utilization_gpu,
+            &label,
+            mig_device.utilization_gpu.map(|x| (x as f64) / 100.),
+        );
+        set_or_remove(
+            &self.mig_utilization_memory,
+            &label,
+            mig_device.utilization_memory.map(|x| (x as f64) / 100.),
+        );
+    }
+
+    /// Removes the series of a device which is gone
+    fn remove_device(&self, mlabel: &DeviceMinorLabel) {
+        self.fan_speed.remove(mlabel);
+        self.memory_total.remove(mlabel);
+        self.memory_used.remove(mlabel);
+        self.power_usage.remove(mlabel);
+        self.temperature.remove(mlabel);
+        self.utilization_gpu.remove(mlabel);
+        self.utilization_memory.remove(mlabel);
+        set_window_stats(&self.utilization_gpu_window, mlabel.minor_number, None);
+        set_window_stats(&self.power_usage_window, mlabel.minor_number, None);
+        set_window_stats(&self.temperature_window, mlabel.minor_number, None);
+        for (clock, _, _) in CLOCK_DOMAINS {
+            let clabel = DeviceClockLabel {
+                minor_number: mlabel.minor_number,
+                clock: clock.to_string(),
+            };
+            self.clock.remove(&clabel);
+            self.max_clock.remove(&clabel);
+        }
+        for (reason, _) in THROTTLE_REASONS {
+            self.throttle_reason.remove(&DeviceThrottleLabel {
+                minor_number: mlabel.minor_number,
+                reason: reason.to_string(),
+            });
+        }
+        self.power_limit_enforced.remove(mlabel);
+        self.power_limit_default.remove(mlabel);
+        self.performance_state.remove(mlabel);
+        self.energy.remove(mlabel);
+        for label in ecc_labels(mlabel.minor_number) {
+            self.ecc_errors.remove(&label);
+        }
+        for label in retirement_labels(mlabel.minor_number) {
+            self.retired_pages.remove(&label);
+        }
+        self.retired_pages_pending.remove(mlabel);
+        for label in remapping_labels(mlabel.minor_number) {
+            self.remapped_rows.remove(&label);
+        }
+        self.remapped_rows_pending.remove(mlabel);
+        self.remapped_rows_failed.remove(mlabel);
+        self.health_ok.remove(mlabel);
+        self.pcie_link_gen.remove(mlabel);
+        self.pcie_link_width.remove(mlabel);
+        self.pcie_link_gen_max.remove(mlabel);
+        self.pcie_link_width_max.remove(mlabel);
+        for label in direction_labels(mlabel.minor_number) {
+            self.pcie_throughput.remove(&label);
+        }
+        self.pcie_replay.remove(mlabel);
+        for link in 0..NVLINK_MAX_LINKS {
+            self.remove_nvlink(mlabel.minor_number, link);
+        }
+    }
+
+    fn remove_nvlink(&self, minor_number: u32, link: u32) {
+        self.nvlink_active
+            .remove(&DeviceLinkLabel { minor_number, link });
+        for label in link_direction_labels(minor_number, link) {
+            self.nvlink_utilization.remove(&label);
+        }
+        for label in link_error_labels(minor_number, link) {
+            self.nvlink_errors.remove(&label);
+        }
+    }
+
+    fn update_nvml_device(&self, device: &NvmlDevice) {
+        self.device_info
+            .get_or_create(&DeviceLabel::from(device))
+            .set(1);
         let mlabel = DeviceMinorLabel {
             minor_number: device.minor_number,
         };
-        self.fan_speed
-            .get_or_create(&mlabel)
-            .set(device.fan_speed.into());
-        self.memory_total
-            .get_or_create(&mlabel)
-            .set(device.memory_total as i64);
-        self.memory_used
-            .get_or_create(&mlabel)
-            .set(device.memory_used as i64);
-        self.power_usage
-            .get_or_create(&mlabel)
-            .set(device.power_usage.into());
-        self.temperature
-            .get_or_create(&mlabel)
-            .set(device.temperature.into());
-        self.utilization_gpu
-            .get_or_create(&mlabel)
-            .set((device.utilization_gpu as f64) / 100.);
-        self.utilization_memory
+        set_or_remove(&self.fan_speed, &mlabel, device.fan_speed.map(i64::from));
+        set_or_remove(
+            &self.memory_total,
+            &mlabel,
+            device.memory_total.map(|x| x as i64),
+        );
+        set_or_remove(
+            &self.memory_used,
+            &mlabel,
+            device.memory_used.map(|x| x as i64),
+        );
+        set_or_remove(
+            &self.power_usage,
+            &mlabel,
+            device.power_usage.map(i64::from),
+        );
+        set_or_remove(
+            &self.temperature,
+            &mlabel,
+            device.temperature.map(i64::from),
+        );
+        set_or_remove(
+            &self.utilization_gpu,
+            &mlabel,
+            device.utilization_gpu.map(|x| (x as f64) / 100.),
+        );
+        set_or_remove(
+            &self.utilization_memory,
+            &mlabel,
+            device.utilization_memory.map(|x| (x as f64) / 100.),
+        );
+        for clock in device.clocks.iter() {
+            let clabel = DeviceClockLabel {
+                minor_number: device.minor_number,
+                clock: clock.domain.to_string(),
+            };
+            set_or_remove(&self.clock, &clabel, clock.current.map(i64::from));
+            set_or_remove(&self.max_clock, &clabel, clock.max.map(i64::from));
+        }
+        for (reason, bit) in THROTTLE_REASONS {
+            let tlabel = DeviceThrottleLabel {
+                minor_number: device.minor_number,
+                reason: reason.to_string(),
+            };
+            let active = device.throttle_reasons.map(|x| (x & bit != 0) as i64);
+            set_or_remove(&self.throttle_reason, &tlabel, active);
+        }
+        set_or_remove(
+            &self.power_limit_enforced,
+            &mlabel,
+            device.power_limit_enforced.map(i64::from),
+        );
+        set_or_remove(
+            &self.power_limit_default,
+            &mlabel,
+            device.power_limit_default.map(i64::from),
+        );
+        set_or_remove(
+            &self.performance_state,
+            &mlabel,
+            device.performance_state.map(i64::from),
+        );
+
+        for label in ecc_labels(device.minor_number) {
+            let count = device.ecc_errors.iter().find(|x| {
+                x.counter == label.counter
+                    && x.error_type == label.error_type
+                    && x.location == label.location
+            });
+            set_counter(&self.ecc_errors, &label, count.map(|x| x.count));
+        }
+        let [single_bit, double_bit] = retirement_labels(device.minor_number);
+        let retired_pages = device.retired_pages;
+        set_counter(
+            &self.retired_pages,
+            &single_bit,
+            retired_pages.map(|x| x.multiple_single_bit_ecc),
+        );
+        set_counter(
+            &self.retired_pages,
+            &double_bit,
+            retired_pages.map(|x| x.double_bit_ecc),
+        );
+        set_or_remove(
+            &self.retired_pages_pending,
+            &mlabel,
+            retired_pages.map(|x| x.pending as i64),
+        );
+        let [corrected, uncorrected] = remapping_labels(device.minor_number);
+        let remapped_rows = device.remapped_rows;
+        set_counter(
+            &self.remapped_rows,
+            &corrected,
+            remapped_rows.map(|x| x.corrected),
+        );
+        set_counter(
+            &self.remapped_rows,
+            &uncorrected,
+            remapped_rows.map(|x| x.uncorrected),
+        );
+        set_or_remove(
+            &self.remapped_rows_pending,
+            &mlabel,
+            remapped_rows.map(|x| x.pending as i64),
+        );
+        set_or_remove(
+            &self.remapped_rows_failed,
+            &mlabel,
+            remapped_rows.map(|x| x.failed as i64),
+        );
+        self.health_ok
             .get_or_create(&mlabel)
-            .set((device.utilization_memory as f64) / 100.);
+            .set(device.is_healthy() as i64);
+
+        let pcie = &device.pcie;
+        set_or_remove(&self.pcie_link_gen, &mlabel, pcie.generation.map(i64::from));
+        set_or_remove(&self.pcie_link_width, &mlabel, pcie.width.map(i64::from));
+        set_or_remove(
+            &self.pcie_link_gen_max,
+            &mlabel,
+            pcie.max_generation.map(i64::from),
+        );
+        set_or_remove(
+            &self.pcie_link_width_max,
+            &mlabel,
+            pcie.max_width.map(i64::from),
+        );
+        let [tx, rx] = direction_labels(device.minor_number);
+        set_or_remove(
+            &self.pcie_throughput,
+            &tx,
+            pcie.tx_throughput.map(|x| x as i64 * 1024),
+        );
+        set_or_remove(
+            &self.pcie_throughput,
+            &rx,
+            pcie.rx_throughput.map(|x| x as i64 * 1024),
+        );
+        set_counter(&self.pcie_replay, &mlabel, pcie.replay_count);
+
+        for link in 0..NVLINK_MAX_LINKS {
+            let Some(nvlink) = device.nvlinks.iter().find(|x| x.link == link) else {
+                self.remove_nvlink(device.minor_number, link);
+                continue;
+            };
+            self.nvlink_active
+                .get_or_create(&DeviceLinkLabel {
+                    minor_number: device.minor_number,
+                    link,
+                })
+                .set(nvlink.active as i64);
+            let [tx, rx] = link_direction_labels(device.minor_number, link);
+            set_counter(
+                &self.nvlink_utilization,
+                &tx,
+                nvlink.utilization.map(|x| x.1),
+            );
+            set_counter(
+                &self.nvlink_utilization,
+                &rx,
+                nvlink.utilization.map(|x| x.0),
+            );
+            for label in link_error_labels(device.minor_number, link) {
+                let count = nvlink.errors.iter().find(|x| x.0 == label.counter);
+                set_counter(&self.nvlink_errors, &label, count.map(|x| x.1));
+            }
+        }
+    }
+}
+
+fn direction_labels(minor_number: u32) -> [DeviceDirectionLabel; 2] {
+    ["tx", "rx"].map(|direction| DeviceDirectionLabel {
+        minor_number,
+        direction: direction.to_string(),
+    })
+}
+
+fn link_direction_labels(minor_number: u32, link: u32) -> [DeviceLinkDirectionLabel; 2] {
+    ["tx", "rx"].map(|direction| DeviceLinkDirectionLabel {
+        minor_number,
+        link,
+        direction: direction.to_string(),
+    })
+}
+
+fn link_error_labels(minor_number: u32, link: u32) -> [DeviceLinkErrorLabel; 4] {
+    NVLINK_ERROR_COUNTERS.map(|counter| DeviceLinkErrorLabel {
+        minor_number,
+        link,
+        counter: counter.to_string(),
+    })
+}
+
+fn ecc_labels(minor_number: u32) -> Vec<DeviceEccLabel> {
+    let mut labels = Vec::new();
+    for counter in ECC_COUNTERS {
+        for error_type in MEMORY_ERROR_TYPES {
+            for location in MEMORY_LOCATIONS {
+                labels.push(DeviceEccLabel {
+                    minor_number,
+                    counter: counter.to_string(),
+                    error_type: error_type.to_string(),
+                    location: location.to_string(),
+                });
+            }
+        }
+    }
+    labels
+}
+
+fn retirement_labels(minor_number: u32) -> [DeviceRetirementLabel; 2] {
+    ["multiple_single_bit_ecc", "double_bit_ecc"].map(|cause| DeviceRetirementLabel {
+        minor_number,
+        cause: cause.to_string(),
+    })
+}
+
+fn remapping_labels(minor_number: u32) -> [DeviceErrorTypeLabel; 2] {
+    MEMORY_ERROR_TYPES.map(|error_type| DeviceErrorTypeLabel {
+        minor_number,
+        error_type: error_type.to_string(),
+    })
+}
+
+/// Follows a counter kept by the driver. A decrease means the driver reset
+/// it, so the series starts over.
+fn set_counter<S>(family: &Family<S, Counter>, label: &S, value: Option<u64>)
+where
+    S: Clone + Hash + Eq,
+{
+    let Some(value) = value else {
+        family.remove(label);
+        return;
+    };
+    let current = family.get_or_create(label).get();
+    if value < current {
+        family.remove(label);
+        family.get_or_create(label).inc_by(value);
+    } else {
+        family.get_or_create(label).inc_by(value - current);
+    }
+}
+
+/// Sets the gauge of a device, or removes it when the field is unavailable
+fn set_or_remove<S, N, A>(family: &Family<S, Gauge<N, A>>, label: &S, value: Option<N>)
+where
+    S: Clone + Hash + Eq,
+    A: Atomic<N> + Default,
+{
+    match value {
+        Some(value) => {
+            family.get_or_create(label).set(value);
+        }
+        None => {
+            family.remove(label);
+        }
+    }
+}
+
+/// Sets the min, max and mean gauges of a device, or removes them when the
+/// window holds no value
+fn set_window_stats(
+    family: &Family<DeviceStatLabel, Gauge<f64, AtomicU64>>,
+    minor_number: u32,
+    stats: Option<WindowStats>,
+) {
+    let values = [
+        ("min", stats.map(|x| x.min)),
+        ("max", stats.map(|x| x.max)),
+        ("mean", stats.map(|x| x.mean)),
+    ];
+    for (stat, value) in values {
+        let label = DeviceStatLabel {
+            minor_number,
+            stat: stat.to_string(),
+        };
+        match value {
+            Some(value) => {
+                family.get_or_create(&label).set(value);
+            }
+            None => {
+                family.remove(&label);
+            }
+        }
     }
 }
 
@@ -195,5 +1144,208 @@ impl AliveStatus {
                 url: item.url.clone(),
             })
             .set(status as i64);
+        self.targets.lock().unwrap().insert(
+            item.hostname.clone(),
+            AliveTarget {
+                hostname: item.hostname.clone(),
+                url: item.url.clone(),
+                alive: status,
+            },
+        );
+    }
+
+    pub fn targets(&self) -> Vec<AliveTarget> {
+        self.targets.lock().unwrap().values().cloned().collect()
+    }
+}
+
+impl ScrapeStatus {
+    pub fn set_timeout(&self, collector: &str, timed_out: bool) {
+        self.collector_timeout
+            .get_or_create(&CollectorLabel {
+                collector: collector.to_string(),
+            })
+            .set(timed_out as i64);
+    }
+
+    pub fn set_error(&self, collector: &str, failed: bool) {
+        self.collector_error
+            .get_or_create(&CollectorLabel {
+                collector: collector.to_string(),
+            })
+            .set(failed as i64);
+    }
+}
+
+impl TextfileStatus {
+    /// Replaces the outcome of the previous collection. Series of the files
+    /// which are gone are removed rather than clearing every series, so that
+    /// concurrent scrapes always see a complete status.
+    pub fn update(&self, directory_error: bool, results: &[TextfileResult]) {
+        let mut files = self.files.lock().unwrap();
+        self.directory_error.set(directory_error as i64);
+        let current: HashSet<String> = results.iter().map(|x| x.file.clone()).collect();
+        for file in files.difference(&current) {
+            let label = FileLabel { file: file.clone() };
+            self.mtime.remove(&label);
+            self.parse_error.remove(&label);
+        }
+        for result in results.iter() {
+            let label = FileLabel {
+                file: result.file.clone(),
+            };
+            match result.mtime {
+                Some(mtime) => {
+                    self.mtime.get_or_create(&label).set(mtime);
+                }
+                None => {
+                    self.mtime.remove(&label);
+                }
+            }
+            self.parse_error
+                .get_or_create(&label)
+                .set(result.error as i64);
+        }
+        *files = current;
+    }
+}
+
+impl XidStatus {
+    /// Counts the event and keeps it among the `history` latest ones
+    pub fn record(&self, event: XidEvent, history: usize) {
+        let label = XidLabel {
+            minor_number: event
+                .minor_number
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            xid: event.xid,
+        };
+        self.errors.get_or_create(&label).inc();
+        let last_seen = self.last_seen.get_or_create(&label);
+        if event.timestamp > last_seen.get() {
+            last_seen.set(event.timestamp);
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(event);
+        while recent.len() > history {
+            recent.pop_front();
+        }
+    }
+
+    pub fn recent(&self) -> Vec<XidEvent> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl QuotaStatus {
+    /// Replaces the current violations. Episodes of violations which go on
+    /// are extended, the others are ended, and the `history` latest are kept.
+    /// Episodes which go on are never evicted, so that they are not restarted
+    /// on the next collection.
+    pub fn update(&self, violations: &[QuotaViolation], timestamp: f64, history: usize) {
+        self.violation.clear();
+        let mut episodes = self.history.lock().unwrap();
+        for episode in episodes.iter_mut().filter(|x| x.ended.is_none()) {
+            let goes_on = violations
+                .iter()
+                .any(|x| x.rule == episode.rule && x.user_name == episode.user_name);
+            if !goes_on {
+                episode.ended = Some(timestamp);
+            }
+        }
+        for violation in violations.iter() {
+            self.violation
+                .get_or_create(&QuotaLabel {
+                    user_name: violation.user_name.clone(),
+                    rule: violation.rule.clone(),
+                })
+                .set(1);
+            let ongoing = episodes.iter_mut().find(|x| {
+                x.ended.is_none() && x.rule == violation.rule && x.user_name == violation.user_name
+            });
+            match ongoing {
+                Some(episode) => {
+                    episode.peak_cards = episode.peak_cards.max(violation.cards);
+                    episode.peak_memory = episode.peak_memory.max(violation.memory);
+                }
+                None => episodes.push_back(QuotaEpisode {
+                    rule: violation.rule.clone(),
+                    user_name: violation.user_name.clone(),
+                    started: timestamp,
+                    ended: None,
+                    peak_cards: violation.cards,
+                    peak_memory: violation.memory,
+                    max_cards: violation.max_cards,
+                    max_memory: violation.max_memory,
+                }),
+            }
+        }
+        while episodes.len() > history {
+            let Some(ended) = episodes.iter().position(|x| x.ended.is_some()) else {
+                break;
+            };
+            episodes.remove(ended);
+        }
+    }
+
+    /// Drops the violations while the devices cannot be read. Their episodes
+    /// go on until a collection tells whether they ended.
+    pub fn set_failed(&self) {
+        self.violation.clear();
+    }
+
+    pub fn history(&self) -> Vec<QuotaEpisode> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violation(user_name: &str) -> QuotaViolation {
+        QuotaViolation {
+            rule: "cards".to_string(),
+            user_name: user_name.to_string(),
+            cards: 3,
+            memory: 0,
+            max_cards: Some(2),
+            max_memory: None,
+            soft: false,
+        }
+    }
+
+    fn label(user_name: &str) -> QuotaLabel {
+        QuotaLabel {
+            user_name: user_name.to_string(),
+            rule: "cards".to_string(),
+        }
+    }
+
+    #[test]
+    fn ongoing_quota_episodes_are_not_evicted() {
+        let status = QuotaStatus::default();
+        status.update(&[violation("alice")], 1., 1);
+        status.update(&[violation("alice"), violation("bob")], 2., 1);
+        let episodes = status.history();
+        assert_eq!(episodes.len(), 2);
+        assert!(episodes.iter().all(|x| x.ended.is_none()));
+        assert_eq!(episodes[0].started, 1.);
+
+        status.update(&[violation("bob")], 3., 1);
+        let episodes = status.history();
+        assert_eq!(episodes.len(), 1);
+        assert_eq!(episodes[0].user_name, "bob");
+    }
+
+    #[test]
+    fn quota_violations_are_dropped_on_failure() {
+        let status = QuotaStatus::default();
+        status.update(&[violation("alice")], 1., 10);
+        assert_eq!(status.violation.get_or_create(&label("alice")).get(), 1);
+        status.set_failed();
+        assert_eq!(status.violation.get_or_create(&label("alice")).get(), 0);
+        assert!(status.history()[0].ended.is_none());
     }
 }
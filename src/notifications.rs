@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use humansize::{format_size, BINARY};
+use serde::Deserialize;
+use users::uid_t;
+
+use crate::idle::IdleHold;
+use crate::metrics::{Metrics, NotificationLabel};
+use crate::quotas::QuotaViolation;
+use crate::terminals;
+use crate::utils::UserPolicy;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NotificationConfig {
+    /// Login records the terminals of the users are read from
+    pub utmp: PathBuf,
+    /// Seconds between two messages to a user for the same reason
+    pub min_interval: f64,
+    /// Seconds a process must be idle before its user is notified, with the
+    /// idle detector enabled
+    pub idle_after: f64,
+    /// Sent for idle processes, with `{user}`, `{index}`, `{pid}`,
+    /// `{command}`, `{memory}` and `{duration}` replaced
+    pub idle_message: String,
+    /// Sent for soft quota rules, with `{user}`, `{rule}`, `{cards}` and
+    /// `{memory}` replaced
+    pub quota_message: String,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            utmp: PathBuf::from(terminals::UTMP_PATH),
+            min_interval: 3600.,
+            idle_after: 1800.,
+            idle_message: "syswatch: your process {pid} ({command}) has held {memory} on GPU \
+                           {index} without using it for {duration}. Please free the GPU if you \
+                           no longer need it."
+                .to_string(),
+            quota_message: "syswatch: you hold {cards} GPUs and {memory} of GPU memory, over \
+                            the quota {rule}. Please release what you do not need."
+                .to_string(),
+        }
+    }
+}
+
+/// Writes rate-limited messages to the terminals of users hoarding GPUs
+pub struct Notifier {
+    utmp: PathBuf,
+    min_interval: Duration,
+    idle_after: f64,
+    idle_message: String,
+    quota_message: String,
+    user_policy: UserPolicy,
+    metrics: Arc<Metrics>,
+    /// Last message by user name and reason
+    sent: HashMap<(String, String), Instant>,
+}
+
+impl Notifier {
+    pub fn new(
+        config: &NotificationConfig,
+        user_policy: UserPolicy,
+        metrics: Arc<Metrics>,
+    ) -> Notifier {
+        Notifier {
+            utmp: config.utmp.clone(),
+            min_interval: Duration::from_secs_f64(config.min_interval),
+            idle_after: config.idle_after,
+            idle_message: config.idle_message.clone(),
+            quota_message: config.quota_message.clone(),
+            user_policy,
+            metrics,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Notifies the users of processes idle for `idle_after`, about their
+    /// longest idle process
+    pub fn notify_idle(&mut self, at: Instant, holds: &[IdleHold]) {
+        let idle_after = self.idle_after;
+        for hold in holds.iter().filter(|x| x.duration >= idle_after) {
+            let message = self.idle_message(hold);
+            self.send(at, &hold.user_name, "idle", "idle".to_string(), &message);
+        }
+    }
+
+    fn idle_message(&self, hold: &IdleHold) -> String {
+        // The command line is chosen by the user, escape sequences in it
+        // would be interpreted by the terminal
+        let command = hold.command.chars().filter(|x| !x.is_control()).collect();
+        render(
+            &self.idle_message,
+            &[
+                ("user", hold.user_name.clone()),
+                ("index", hold.index.to_string()),
+                ("pid", hold.pid.to_string()),
+                ("command", command),
+                ("memory", format_memory(hold.used_gpu_memory)),
+                ("duration", format_duration(hold.duration)),
+            ],
+        )
+    }
+
+    /// Notifies the users over soft quota rules
+    pub fn notify_quota(&mut self, at: Instant, violations: &[QuotaViolation]) {
+        for violation in violations.iter().filter(|x| x.soft) {
+            let message = render(
+                &self.quota_message,
+                &[
+                    ("user", violation.user_name.clone()),
+                    ("rule", violation.rule.clone()),
+                    ("cards", violation.cards.to_string()),
+                    ("memory", format_memory(violation.memory)),
+                ],
+            );
+            let key = format!("quota:{}", violation.rule);
+            self.send(at, &violation.user_name, "quota", key, &message);
+        }
+    }
+
+    fn send(&mut self, at: Instant, user_name: &str, reason: &str, key: String, message: &str) {
+        let min_interval = self.min_interval;
+        self.sent
+            .retain(|_, last| at.duration_since(*last) < min_interval);
+        let key = (user_name.to_string(), key);
+        if self.sent.contains_key(&key) {
+            return;
+        }
+        self.sent.insert(key, at);
+
+        let result = match self.known_uid(user_name) {
+            None => "unknown_user",
+            Some(uid) => match terminals::write_to_user(&self.utmp, user_name, uid, message) {
+                Ok(0) => "no_terminal",
+                Ok(_) => "delivered",
+                Err(e) => {
+                    eprintln!(
+                        "Notification: cannot read {}: {}",
+                        self.utmp.to_string_lossy(),
+                        e
+                    );
+                    "failed"
+                }
+            },
+        };
+        self.metrics
+            .notifications
+            .get_or_create(&NotificationLabel {
+                user_name: user_name.to_string(),
+                reason: reason.to_string(),
+                result: result.to_string(),
+            })
+            .inc();
+    }
+
+    /// Uid of a user among the known users, so that system accounts are never
+    /// written to
+    fn known_uid(&self, user_name: &str) -> Option<uid_t> {
+        let (known_users, _) = self.user_policy.get_users_map();
+        known_users
+            .into_iter()
+            .find(|(_, user)| user.name() == user_name)
+            .map(|(uid, _)| uid)
+    }
+}
+
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut message = template.to_string();
+    for (key, value) in values.iter() {
+        message = message.replace(&format!("{{{}}}", key), value);
+    }
+    message
+}
+
+fn format_memory(bytes: u64) -> String {
+    format_size(bytes, BINARY)
+}
+
+fn format_duration(seconds: f64) -> String {
+    let minutes = (seconds / 60.) as u64;
+    match minutes {
+        0 => format!("{} s", seconds as u64),
+        1..=59 => format!("{} min", minutes),
+        _ => format!("{} h {} min", minutes / 60, minutes % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::UserPolicyConfig;
+
+    fn notifier(name: &str, utmp: Option<&[u8]>, min_interval: f64) -> Notifier {
+        let path =
+            std::env::temp_dir().join(format!("syswatch-utmp-{}-{}", std::process::id(), name));
+        if let Some(content) = utmp {
+            std::fs::write(&path, content).unwrap();
+        }
+        let config = NotificationConfig {
+            utmp: path,
+            min_interval,
+            ..Default::default()
+        };
+        let user_policy = UserPolicy::new(&UserPolicyConfig {
+            allow_users: vec!["root".to_string()],
+            ..Default::default()
+        });
+        Notifier::new(&config, user_policy, Arc::new(Metrics::new()))
+    }
+
+    fn sent(notifier: &Notifier, user_name: &str, result: &str) -> u64 {
+        notifier
+            .metrics
+            .notifications
+            .get_or_create(&NotificationLabel {
+                user_name: user_name.to_string(),
+                reason: "idle".to_string(),
+                result: result.to_string(),
+            })
+            .get()
+    }
+
+    #[test]
+    fn templates_are_rendered() {
+        let message = render(
+            "{user} holds GPU {index}, {user}, {unknown}",
+            &[("user", "alice".to_string()), ("index", "3".to_string())],
+        );
+        assert_eq!(message, "alice holds GPU 3, alice, {unknown}");
+    }
+
+    #[test]
+    fn durations_are_rounded_down() {
+        assert_eq!(format_duration(0.), "0 s");
+        assert_eq!(format_duration(59.9), "59 s");
+        assert_eq!(format_duration(60.), "1 min");
+        assert_eq!(format_duration(3599.), "59 min");
+        assert_eq!(format_duration(3600.), "1 h 0 min");
+        assert_eq!(format_duration(90000.), "25 h 0 min");
+    }
+
+    #[test]
+    fn control_characters_are_stripped_from_commands() {
+        let mut notifier = notifier("control", None, 3600.);
+        notifier.idle_message = "{pid} ({command})".to_string();
+        let hold = IdleHold {
+            pid: 42,
+            user_name: "alice".to_string(),
+            index: 0,
+            minor_number: 0,
+            command: "python\x1b]0;owned\x07 train.py\r\n\x1b[2J".to_string(),
+            used_gpu_memory: 1 << 30,
+            since: 0.,
+            duration: 3600.,
+        };
+        assert_eq!(
+            notifier.idle_message(&hold),
+            "42 (python]0;owned train.py[2J)"
+        );
+    }
+
+    #[test]
+    fn messages_are_rate_limited() {
+        let mut notifier = notifier("rate-limit", Some(&[]), 60.);
+        let at = Instant::now();
+        notifier.send(at, "root", "idle", "idle".to_string(), "hello");
+        notifier.send(
+            at + Duration::from_secs(59),
+            "root",
+            "idle",
+            "idle".to_string(),
+            "hello",
+        );
+        assert_eq!(sent(&notifier, "root", "no_terminal"), 1);
+        // Other reasons are limited apart
+        notifier.send(
+            at + Duration::from_secs(59),
+            "root",
+            "idle",
+            "other".to_string(),
+            "hello",
+        );
+        assert_eq!(sent(&notifier, "root", "no_terminal"), 2);
+        notifier.send(
+            at + Duration::from_secs(60),
+            "root",
+            "idle",
+            "idle".to_string(),
+            "hello",
+        );
+        assert_eq!(sent(&notifier, "root", "no_terminal"), 3);
+        std::fs::remove_file(&notifier.utmp).unwrap();
+    }
+
+    #[test]
+    fn unknown_users_are_not_written_to() {
+        let mut notifier = notifier("unknown", Some(&[]), 60.);
+        let at = Instant::now();
+        notifier.send(at, "syswatch-nobody", "idle", "idle".to_string(), "hello");
+        assert_eq!(sent(&notifier, "syswatch-nobody", "unknown_user"), 1);
+        // System accounts are not known either
+        notifier.send(at, "daemon", "idle", "idle".to_string(), "hello");
+        assert_eq!(sent(&notifier, "daemon", "unknown_user"), 1);
+        std::fs::remove_file(&notifier.utmp).unwrap();
+    }
+
+    #[test]
+    fn unreadable_utmp_fails() {
+        let mut notifier = notifier("missing", None, 60.);
+        notifier.send(Instant::now(), "root", "idle", "idle".to_string(), "hello");
+        assert_eq!(sent(&notifier, "root", "failed"), 1);
+    }
+}
@@ -1,9 +1,22 @@
-use crate::utils;
+use crate::nvml_raw::RawNvml;
+use crate::utils::UserPolicy;
 use anyhow::Context;
-use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+use nvml_wrapper::bitmasks::device::ThrottleReasons;
+use nvml_wrapper::enum_wrappers::device::PcieUtilCounter;
+use nvml_wrapper::enum_wrappers::device::{
+    Clock, EccCounter, MemoryError, MemoryLocation, PerformanceState, RetirementCause,
+    TemperatureSensor,
+};
+use nvml_wrapper::enum_wrappers::nv_link::ErrorCounter;
 use nvml_wrapper::enums::device::UsedGpuMemory;
-use nvml_wrapper::Nvml;
-use std::collections::HashMap;
+use nvml_wrapper::enums::nv_link::Counter;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{ProcessInfo, ProcessUtilizationSample};
+use nvml_wrapper::{Device, Nvml};
+use procfs::WithCurrentSystemInfo;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use users::{uid_t, User};
 
 #[derive(Debug)]
@@ -11,152 +24,1191 @@ pub struct NvmlMetrics {
     pub version: String,
     pub devices: Vec<NvmlDevice>,
     pub users_utilization: Vec<NvmlUserUtilization>,
+    pub processes: Vec<NvmlProcess>,
+    pub field_errors: Vec<FieldError>,
 }
 
+/// Fields are `None` when the device does not support them or reading them failed
 #[derive(Debug)]
 pub struct NvmlDevice {
     pub index: u32,
     pub minor_number: u32,
     pub name: String,
     pub uuid: String,
-    pub temperature: u32,
-    pub power_usage: u32,
-    pub fan_speed: u32,
-    pub memory_total: u64,
-    pub memory_used: u64,
-    pub utilization_memory: u32,
-    pub utilization_gpu: u32,
+    /// PCI address as `domain:bus:device.function`
+    pub pci_bus_id: Option<String>,
+    pub temperature: Option<u32>,
+    pub power_usage: Option<u32>,
+    /// Energy consumed since the driver was loaded, in millijoules
+    pub energy_consumption: Option<u64>,
+    pub fan_speed: Option<u32>,
+    pub memory_total: Option<u64>,
+    pub memory_used: Option<u64>,
+    pub utilization_memory: Option<u32>,
+    pub utilization_gpu: Option<u32>,
+    pub clocks: Vec<ClockSpeed>,
+    /// Bitmask of the reasons in `THROTTLE_REASONS`
+    pub throttle_reasons: Option<u64>,
+    pub power_limit_enforced: Option<u32>,
+    pub power_limit_default: Option<u32>,
+    /// P-state, from 0 (maximum performance) to 15 (minimum performance)
+    pub performance_state: Option<u32>,
+    /// SM utilization summed over the processes of all users, including
+    /// those not listed in `users_utilization`
+    pub process_sm_utilization: Option<u32>,
+    /// GPU memory summed over the processes of all users
+    pub process_used_memory: Option<u64>,
+    /// Supported ECC error counters
+    pub ecc_errors: Vec<EccErrors>,
+    pub retired_pages: Option<RetiredPages>,
+    pub remapped_rows: Option<RemappedRows>,
+    pub pcie: PcieLink,
+    /// Links which could be queried, empty on boards without NVLink
+    pub nvlinks: Vec<NvLinkState>,
+    /// Empty unless the GPU is in MIG mode
+    pub mig_devices: Vec<MigDevice>,
 }
 
+/// GPU instance and compute instance of a MIG device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MigInstance {
+    pub gpu_instance_id: u32,
+    pub compute_instance_id: u32,
+}
+
+/// A MIG device of a physical GPU. Its memory and utilization only cover
+/// the slices of its instance.
+#[derive(Debug)]
+pub struct MigDevice {
+    pub instance: MigInstance,
+    /// Profile name, e.g. `1g.10gb`
+    pub profile: String,
+    pub uuid: String,
+    pub memory_total: Option<u64>,
+    pub memory_used: Option<u64>,
+    pub utilization_gpu: Option<u32>,
+    pub utilization_memory: Option<u32>,
+}
+
+/// PCIe link of a device
+#[derive(Debug, Default)]
+pub struct PcieLink {
+    pub generation: Option<u32>,
+    pub width: Option<u32>,
+    pub max_generation: Option<u32>,
+    pub max_width: Option<u32>,
+    /// Throughput in KB/s, sampled over 20 ms
+    pub tx_throughput: Option<u32>,
+    pub rx_throughput: Option<u32>,
+    pub replay_count: Option<u64>,
+}
+
+/// State of an NVLink link. Counters are only read from active links.
+#[derive(Debug)]
+pub struct NvLinkState {
+    pub link: u32,
+    pub active: bool,
+    /// Receive and send counts, in the unit configured for counter 0
+    pub utilization: Option<(u64, u64)>,
+    /// Counts of the errors in `NVLINK_ERROR_COUNTERS`
+    pub errors: Vec<(&'static str, u64)>,
+}
+
+impl NvLinkState {
+    /// Field name used when reading a property of a link
+    pub fn field(link: u32, property: &str) -> String {
+        format!("nvlink_{}_{}", link, property)
+    }
+}
+
+pub const NVLINK_MAX_LINKS: u32 = nvml_wrapper_sys::bindings::NVML_NVLINK_MAX_LINKS;
+pub const NVLINK_ERROR_COUNTERS: [&str; 4] = ["replay", "recovery", "crc_flit", "crc_data"];
+
+impl NvmlDevice {
+    /// A device is unhealthy when memory errors could not be corrected since
+    /// the driver was loaded, when a page retirement or row remapping waits
+    /// for a reset, or when row remapping failed. Unknown fields count as
+    /// healthy.
+    pub fn is_healthy(&self) -> bool {
+        let uncorrected = self
+            .ecc_errors
+            .iter()
+            .any(|x| x.counter == "volatile" && x.error_type == "uncorrected" && x.count > 0);
+        let retirement_pending = self.retired_pages.map(|x| x.pending).unwrap_or(false);
+        let remapping_broken = self
+            .remapped_rows
+            .map(|x| x.pending || x.failed)
+            .unwrap_or(false);
+        !(uncorrected || retirement_pending || remapping_broken)
+    }
+}
+
+pub const ECC_COUNTERS: [&str; 2] = ["volatile", "aggregate"];
+pub const MEMORY_ERROR_TYPES: [&str; 2] = ["corrected", "uncorrected"];
+pub const MEMORY_LOCATIONS: [&str; 8] = [
+    "l1_cache",
+    "l2_cache",
+    "device_memory",
+    "register_file",
+    "texture_memory",
+    "texture_shm",
+    "cbu",
+    "sram",
+];
+
+/// Count of memory errors of a type in a location. Volatile counts restart
+/// when the driver is loaded, aggregate counts persist.
+#[derive(Debug)]
+pub struct EccErrors {
+    pub counter: &'static str,
+    pub error_type: &'static str,
+    pub location: &'static str,
+    pub count: u64,
+}
+
+impl EccErrors {
+    /// Field name used when reading the count
+    pub fn field(counter: &str, error_type: &str, location: &str) -> String {
+        format!("ecc_{}_{}_{}", counter, error_type, location)
+    }
+}
+
+/// Pages retired by cause, and whether a retirement waits for a reset
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RetiredPages {
+    pub multiple_single_bit_ecc: u64,
+    pub double_bit_ecc: u64,
+    pub pending: bool,
+}
+
+/// Rows remapped after memory errors, on Ampere and newer
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RemappedRows {
+    pub corrected: u64,
+    pub uncorrected: u64,
+    /// A remapping waits for a reset
+    pub pending: bool,
+    /// A remapping failed, the memory is unusable
+    pub failed: bool,
+}
+
+/// Clock of a domain in `CLOCK_DOMAINS`, in MHz
+#[derive(Debug)]
+pub struct ClockSpeed {
+    pub domain: &'static str,
+    pub current: Option<u32>,
+    pub max: Option<u32>,
+}
+
+/// Clock domains with the names of their current and maximum clock fields
+pub const CLOCK_DOMAINS: [(&str, &str, &str); 3] = [
+    ("graphics", "clock_graphics", "max_clock_graphics"),
+    ("sm", "clock_sm", "max_clock_sm"),
+    ("memory", "clock_memory", "max_clock_memory"),
+];
+
+/// Reasons for reduced clocks and their bits in the NVML bitmask
+pub const THROTTLE_REASONS: [(&str, u64); 9] = [
+    ("gpu_idle", ThrottleReasons::GPU_IDLE.bits()),
+    (
+        "applications_clocks_setting",
+        ThrottleReasons::APPLICATIONS_CLOCKS_SETTING.bits(),
+    ),
+    ("sw_power_cap", ThrottleReasons::SW_POWER_CAP.bits()),
+    ("hw_slowdown", ThrottleReasons::HW_SLOWDOWN.bits()),
+    ("sync_boost", ThrottleReasons::SYNC_BOOST.bits()),
+    (
+        "sw_thermal_slowdown",
+        ThrottleReasons::SW_THERMAL_SLOWDOWN.bits(),
+    ),
+    (
+        "hw_thermal_slowdown",
+        ThrottleReasons::HW_THERMAL_SLOWDOWN.bits(),
+    ),
+    (
+        "hw_power_brake_slowdown",
+        ThrottleReasons::HW_POWER_BRAKE_SLOWDOWN.bits(),
+    ),
+    (
+        "display_clock_setting",
+        ThrottleReasons::DISPLAY_CLOCK_SETTING.bits(),
+    ),
+];
+
 #[derive(Debug)]
 pub struct NvmlUserUtilization {
     pub index: u32,
     pub user_name: String,
     pub used_gpu_memory: u64,
+    /// SM utilization in percent summed over the processes of the user,
+    /// `None` when the device does not report process utilization
+    pub sm_utilization: Option<u32>,
+    /// Utilization in percent summed over the processes of the user, each
+    /// counting its busiest engine among SM, memory, encoder and decoder
+    pub utilization: Option<u32>,
+    /// MIG device the processes run on, `None` outside MIG mode
+    pub mig: Option<MigInstance>,
+}
+
+/// Counts the GPUs on which each user holds memory
+pub fn cards_by_user(users: &[NvmlUserUtilization]) -> HashMap<String, u32> {
+    let cards: HashSet<(&str, u32)> = users
+        .iter()
+        .filter(|x| x.used_gpu_memory != 0)
+        .map(|x| (x.user_name.as_str(), x.index))
+        .collect();
+    let mut count = HashMap::new();
+    for (user_name, _) in cards {
+        *count.entry(user_name.to_string()).or_insert(0) += 1;
+    }
+    count
+}
+
+/// A process using a GPU. Host values are `None` when the process exited
+/// or could not be read.
+#[derive(Debug, Clone)]
+pub struct NvmlProcess {
+    pub pid: u32,
+    pub minor_number: u32,
+    /// `compute` or `graphics`
+    pub process_type: &'static str,
+    /// `None` for users hidden from `users_utilization`
+    pub user_name: Option<String>,
+    /// Command line, or the command name for kernel threads and zombies
+    pub command: String,
+    /// Unix time in seconds
+    pub start_time: Option<f64>,
+    pub used_gpu_memory: Option<u64>,
+    /// Utilization in percent of the busiest engine, `None` when the device
+    /// does not report process utilization
+    pub utilization: Option<u32>,
+    /// Resident set size on the host in bytes
+    pub rss: Option<u64>,
+}
+
+/// A source of GPU metrics, backed either by NVML or by a simulation
+pub trait GpuSource: Send {
+    fn now(&mut self) -> anyhow::Result<NvmlMetrics>;
+
+    /// Count of times the backend has been reinitialized after a failure
+    fn reinit_count(&self) -> u64 {
+        0
+    }
+
+    /// Field errors of collections which failed as a whole, so that they are
+    /// still counted
+    fn take_field_errors(&mut self) -> Vec<FieldError> {
+        Vec::new()
+    }
 }
 
+/// A field which could not be read. `minor_number` is `None` when the whole
+/// device could not be identified.
+#[derive(Debug, Clone)]
+pub struct FieldError {
+    pub minor_number: Option<u32>,
+    pub field: String,
+}
+
+pub enum FieldFailure {
+    NotSupported,
+    Failed(String),
+    /// The backend has to be reinitialized
+    Fatal(String),
+}
+
+impl From<NvmlError> for FieldFailure {
+    fn from(e: NvmlError) -> Self {
+        match e {
+            // Symbols are missing from older drivers
+            NvmlError::NotSupported | NvmlError::FailedToLoadSymbol(_) => {
+                FieldFailure::NotSupported
+            }
+            e if is_fatal(&e) => FieldFailure::Fatal(e.to_string()),
+            e => FieldFailure::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Errors which mean the NVML handle is stale, e.g. after a GPU reset or a
+/// driver upgrade
+fn is_fatal(e: &NvmlError) -> bool {
+    matches!(
+        e,
+        NvmlError::Uninitialized
+            | NvmlError::DriverNotLoaded
+            | NvmlError::GpuLost
+            | NvmlError::ResetRequired
+            | NvmlError::LibRmVersionMismatch
+            | NvmlError::Unknown
+    )
+}
+
+/// Reads device fields independently. Fields reported as not supported are
+/// remembered per device and not queried again.
+#[derive(Default)]
+pub struct FieldReader {
+    unsupported: HashSet<(String, String)>,
+    errors: Vec<FieldError>,
+    fatal: bool,
+}
+
+impl FieldReader {
+    pub fn read<T>(
+        &mut self,
+        uuid: &str,
+        minor_number: u32,
+        field: &str,
+        f: impl FnOnce() -> Result<T, FieldFailure>,
+    ) -> Option<T> {
+        let key = (uuid.to_string(), field.to_string());
+        if self.unsupported.contains(&key) {
+            return None;
+        }
+        match f() {
+            Ok(value) => Some(value),
+            Err(FieldFailure::NotSupported) => {
+                self.unsupported.insert(key);
+                None
+            }
+            Err(failure) => {
+                let e = match failure {
+                    FieldFailure::Fatal(e) => {
+                        self.fatal = true;
+                        e
+                    }
+                    FieldFailure::Failed(e) => e,
+                    FieldFailure::NotSupported => unreachable!(),
+                };
+                eprintln!("Failed to read {} of GPU {}: {}", field, minor_number, e);
+                self.errors.push(FieldError {
+                    minor_number: Some(minor_number),
+                    field: field.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    pub fn device_failed(
+        &mut self,
+        index: u32,
+        minor_number: Option<u32>,
+        e: &dyn std::fmt::Display,
+        fatal: bool,
+    ) {
+        eprintln!("Failed to read GPU at index {}: {}", index, e);
+        self.fatal |= fatal;
+        self.errors.push(FieldError {
+            minor_number,
+            field: "device".to_string(),
+        });
+    }
+
+    /// Reads the compute and graphics processes as separate fields, so that
+    /// a device which cannot list one type still lists the other. `None` when
+    /// a list failed, or when neither is supported.
+    pub fn read_processes<T>(
+        &mut self,
+        uuid: &str,
+        minor_number: u32,
+        prefix: &str,
+        mut list: impl FnMut(&'static str) -> Result<Vec<T>, FieldFailure>,
+    ) -> Option<Vec<(T, &'static str)>> {
+        let mut processes = Vec::new();
+        let mut listed = false;
+        for process_type in ["compute", "graphics"] {
+            let field = format!("{}{}_processes", prefix, process_type);
+            match self.read(uuid, minor_number, &field, || list(process_type)) {
+                Some(list) => {
+                    listed = true;
+                    processes.extend(list.into_iter().map(|x| (x, process_type)));
+                }
+                None if self.unsupported.contains(&(uuid.to_string(), field)) => {}
+                None => return None,
+            }
+        }
+        listed.then_some(processes)
+    }
+
+    pub fn take_errors(&mut self) -> Vec<FieldError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Whether a failure since the last call requires reinitialization
+    pub fn take_fatal(&mut self) -> bool {
+        std::mem::take(&mut self.fatal)
+    }
+}
+
+/// GPU usage summed over the processes of a uid
+#[derive(Default)]
+struct ProcessUsage {
+    used_gpu_memory: u64,
+    sm_utilization: Option<u32>,
+    utilization: Option<u32>,
+}
+
+/// Utilization of a process in percent, averaged over the samples since the
+/// previous collection
+#[derive(Clone, Copy, Default)]
+struct ProcessUtilization {
+    sm: u32,
+    /// Highest of the SM, memory, encoder and decoder utilizations
+    busiest: u32,
+}
+
+/// GPU usage of the processes on a device
+#[derive(Default)]
+struct DeviceUsage {
+    /// Summed by uid, split by MIG device in MIG mode
+    users: HashMap<(uid_t, Option<MigInstance>), ProcessUsage>,
+    processes: Vec<(uid_t, NvmlProcess)>,
+}
+
+const REINIT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const REINIT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
 pub struct NvmlMetricsCollector {
-    nvml: Nvml,
+    /// `None` after a fatal error, until reinitialization succeeds
+    nvml: Option<Nvml>,
+    /// `None` if the library could not be loaded for the raw calls
+    raw: Option<RawNvml>,
+    next_init: Instant,
+    backoff: Duration,
+    reinit_count: u64,
+    /// Field errors of the collections dropped for a stale handle
+    dropped_field_errors: Vec<FieldError>,
+    fields: FieldReader,
+    /// Timestamp of the latest process utilization sample of each device
+    utilization_timestamps: HashMap<String, u64>,
     show_all_users: bool,
+    user_policy: UserPolicy,
     known_user_map: HashMap<uid_t, User>,
     blocked_user_map: HashMap<uid_t, User>,
 }
 
 impl NvmlMetricsCollector {
-    pub fn new(show_all_users: bool) -> anyhow::Result<NvmlMetricsCollector> {
+    pub fn new(
+        show_all_users: bool,
+        user_policy: UserPolicy,
+    ) -> anyhow::Result<NvmlMetricsCollector> {
         let nvml = Nvml::init().with_context(|| "Nvml initialization failed")?;
-        let (known_user_map, blocked_user_map) = utils::get_users_map();
+        let (known_user_map, blocked_user_map) = user_policy.get_users_map();
+        let raw = RawNvml::load()
+            .map_err(|e| eprintln!("Functions outside nvml-wrapper are unavailable: {}", e))
+            .ok();
 
         Ok(NvmlMetricsCollector {
-            nvml,
+            nvml: Some(nvml),
+            raw,
+            next_init: Instant::now(),
+            backoff: REINIT_BACKOFF_MIN,
+            reinit_count: 0,
+            dropped_field_errors: Vec::new(),
+            fields: FieldReader::default(),
+            utilization_timestamps: HashMap::new(),
             show_all_users,
+            user_policy,
             known_user_map,
             blocked_user_map,
         })
     }
 
-    pub fn now(&mut self) -> anyhow::Result<NvmlMetrics> {
-        let nvml = &self.nvml;
+    fn ensure_initialized(&mut self) -> anyhow::Result<()> {
+        if self.nvml.is_some() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now < self.next_init {
+            anyhow::bail!(
+                "NVML is unavailable, retrying in {:.0?}",
+                self.next_init - now
+            );
+        }
+        match Nvml::init() {
+            Ok(nvml) => {
+                println!("NVML is reinitialized");
+                self.nvml = Some(nvml);
+                self.backoff = REINIT_BACKOFF_MIN;
+                self.reinit_count += 1;
+                Ok(())
+            }
+            Err(e) => {
+                self.next_init = now + self.backoff;
+                self.backoff = (self.backoff * 2).min(REINIT_BACKOFF_MAX);
+                Err(e).with_context(|| "Nvml reinitialization failed")
+            }
+        }
+    }
+
+    /// Drops the stale handle. Devices may have changed, so what is known
+    /// about unsupported fields is dropped too.
+    fn invalidate(&mut self) {
+        eprintln!("NVML handle is stale, reinitializing");
+        self.nvml = None;
+        self.dropped_field_errors.extend(self.fields.take_errors());
+        self.fields = FieldReader::default();
+        self.next_init = Instant::now();
+    }
+
+    fn collect(&mut self) -> Result<NvmlMetrics, NvmlError> {
+        let Some(nvml) = &self.nvml else {
+            return Err(NvmlError::Uninitialized);
+        };
 
         let version = nvml.sys_driver_version()?;
         let device_count = nvml.device_count()?;
         let mut devices = Vec::new();
         let mut users_utilization = Vec::with_capacity(8);
+        let mut processes = Vec::new();
         for index in 0..device_count {
-            let device = nvml.device_by_index(index)?;
-            let uuid = device.uuid()?;
-            let name = device.name()?;
-            let minor_number = device.minor_number()?;
-            let temperature = device.temperature(TemperatureSensor::Gpu)?;
-            let power_usage = device.power_usage()?;
-            let fan_speed = device.fan_speed(0)?;
-            let memory_info = device.memory_info()?;
-            let utilization = device.utilization_rates()?;
-            devices.push(NvmlDevice {
-                index,
-                minor_number,
-                name,
-                uuid,
-                temperature,
-                power_usage,
-                fan_speed,
-                memory_total: memory_info.total,
-                memory_used: memory_info.used,
-                utilization_memory: utilization.memory,
-                utilization_gpu: utilization.gpu,
-            });
-
-            let compute_processes = device.running_compute_processes()?;
-            let graphic_processes = device.running_graphics_processes()?;
-            let mut user_usage: HashMap<uid_t, u64> = HashMap::new();
-            for proc_info in compute_processes.iter().chain(graphic_processes.iter()) {
-                let proc = procfs::process::Process::new(proc_info.pid as i32);
-                let proc = if let Ok(proc) = proc {
-                    proc
-                } else {
-                    continue;
-                };
-                let uid = if let Ok(uid) = proc.uid() {
-                    uid
-                } else {
+            let (device, user_usage) = match self.read_device(index) {
+                Ok(result) => result,
+                Err((minor_number, e)) => {
+                    let fatal = is_fatal(&e);
+                    self.fields.device_failed(index, minor_number, &e, fatal);
                     continue;
-                };
-                // tracing::trace!("Nvml process pid = {}, uid = {}", proc.pid, uid);
-                let r = match proc_info.used_gpu_memory {
-                    UsedGpuMemory::Used(u) => u,
-                    UsedGpuMemory::Unavailable => 0,
-                };
+                }
+            };
+            devices.push(device);
+            if let Some(user_usage) = user_usage {
+                self.resolve_users(index, user_usage, &mut users_utilization, &mut processes);
+            }
+        }
 
-                user_usage.entry(uid).and_modify(|e| *e += r).or_insert(r);
+        Ok(NvmlMetrics {
+            version,
+            devices,
+            users_utilization,
+            processes,
+            field_errors: self.fields.take_errors(),
+        })
+    }
+
+    /// Reads a device and the GPU memory used by each uid on it. The usage
+    /// is `None` if the processes could not be listed.
+    fn read_device(
+        &mut self,
+        index: u32,
+    ) -> Result<(NvmlDevice, Option<DeviceUsage>), (Option<u32>, NvmlError)> {
+        let fields = &mut self.fields;
+        let utilization_timestamps = &mut self.utilization_timestamps;
+        let raw = &self.raw;
+        let Some(nvml) = &self.nvml else {
+            return Err((None, NvmlError::Uninitialized));
+        };
+        let device = nvml.device_by_index(index).map_err(|e| (None, e))?;
+        let minor_number = device.minor_number().map_err(|e| (None, e))?;
+        let uuid = device.uuid().map_err(|e| (Some(minor_number), e))?;
+        let name = device.name().map_err(|e| (Some(minor_number), e))?;
+
+        let pci_bus_id = fields.read(&uuid, minor_number, "pci_info", || {
+            Ok(device.pci_info()?.bus_id)
+        });
+        let temperature = fields.read(&uuid, minor_number, "temperature", || {
+            Ok(device.temperature(TemperatureSensor::Gpu)?)
+        });
+        let power_usage = fields.read(&uuid, minor_number, "power_usage", || {
+            Ok(device.power_usage()?)
+        });
+        let energy_consumption = fields.read(&uuid, minor_number, "energy_consumption", || {
+            Ok(device.total_energy_consumption()?)
+        });
+        let fan_speed = fields.read(
+            &uuid,
+            minor_number,
+            "fan_speed",
+            || Ok(device.fan_speed(0)?),
+        );
+        let memory_info = fields.read(&uuid, minor_number, "memory_info", || {
+            Ok(device.memory_info()?)
+        });
+        let utilization = fields.read(&uuid, minor_number, "utilization_rates", || {
+            Ok(device.utilization_rates()?)
+        });
+
+        let clocks = [Clock::Graphics, Clock::SM, Clock::Memory]
+            .into_iter()
+            .zip(CLOCK_DOMAINS)
+            .map(|(clock, (domain, field, max_field))| ClockSpeed {
+                domain,
+                current: fields.read(&uuid, minor_number, field, || {
+                    Ok(device.clock_info(clock.clone())?)
+                }),
+                max: fields.read(&uuid, minor_number, max_field, || {
+                    Ok(device.max_clock_info(clock.clone())?)
+                }),
+            })
+            .collect();
+        let throttle_reasons = fields.read(&uuid, minor_number, "throttle_reasons", || {
+            Ok(device.current_throttle_reasons()?.bits())
+        });
+        let power_limit_enforced = fields.read(&uuid, minor_number, "enforced_power_limit", || {
+            Ok(device.enforced_power_limit()?)
+        });
+        let power_limit_default = fields.read(&uuid, minor_number, "default_power_limit", || {
+            Ok(device.power_management_limit_default()?)
+        });
+        let performance_state = fields.read(&uuid, minor_number, "performance_state", || {
+            match device.performance_state()? {
+                PerformanceState::Unknown => Err(FieldFailure::NotSupported),
+                state => Ok(state.as_c()),
             }
+        });
+
+        let ecc_errors = read_ecc_errors(fields, &device, &uuid, minor_number);
+        let retired_pages = fields.read(&uuid, minor_number, "retired_pages", || {
+            Ok(RetiredPages {
+                multiple_single_bit_ecc: device
+                    .retired_pages(RetirementCause::MultipleSingleBitEccErrors)?
+                    .len() as u64,
+                double_bit_ecc: device
+                    .retired_pages(RetirementCause::DoubleBitEccError)?
+                    .len() as u64,
+                pending: device.are_pages_pending_retired()?,
+            })
+        });
+        let remapped_rows = fields.read(&uuid, minor_number, "remapped_rows", || match raw {
+            Some(raw) => Ok(raw.remapped_rows(&device)?),
+            None => Err(FieldFailure::NotSupported),
+        });
 
-            // for user in self.known_user_map.values().chain(self.blocked_user_map.values()) {
-            //     user_usage.entry(user.uid()).or_insert(0);
-            // }
-
-            for (uid, _used) in user_usage.iter() {
-                if !self.known_user_map.contains_key(uid)
-                    && !self.blocked_user_map.contains_key(uid)
-                {
-                    let (new_known, new_blocked) = utils::get_users_map();
-                    self.known_user_map = new_known;
-                    self.blocked_user_map = new_blocked;
-                    break;
+        let pcie = PcieLink {
+            generation: fields.read(&uuid, minor_number, "pcie_link_gen", || {
+                Ok(device.current_pcie_link_gen()?)
+            }),
+            width: fields.read(&uuid, minor_number, "pcie_link_width", || {
+                Ok(device.current_pcie_link_width()?)
+            }),
+            max_generation: fields.read(&uuid, minor_number, "max_pcie_link_gen", || {
+                Ok(device.max_pcie_link_gen()?)
+            }),
+            max_width: fields.read(&uuid, minor_number, "max_pcie_link_width", || {
+                Ok(device.max_pcie_link_width()?)
+            }),
+            tx_throughput: fields.read(&uuid, minor_number, "pcie_tx_throughput", || {
+                Ok(device.pcie_throughput(PcieUtilCounter::Send)?)
+            }),
+            rx_throughput: fields.read(&uuid, minor_number, "pcie_rx_throughput", || {
+                Ok(device.pcie_throughput(PcieUtilCounter::Receive)?)
+            }),
+            replay_count: fields.read(&uuid, minor_number, "pcie_replay_counter", || {
+                Ok(device.pcie_replay_counter()?.into())
+            }),
+        };
+        let nvlinks = read_nvlinks(fields, &device, &uuid, minor_number);
+
+        let last_seen = utilization_timestamps.get(&uuid).copied();
+        let process_utilization = fields
+            .read(&uuid, minor_number, "process_utilization", || {
+                match device.process_utilization_stats(last_seen) {
+                    Ok(samples) => Ok(samples),
+                    // No process ran since the last sample
+                    Err(NvmlError::NotFound) => Ok(Vec::new()),
+                    Err(e) => Err(e.into()),
+                }
+            })
+            .map(|samples| {
+                if let Some(latest) = samples.iter().map(|x| x.timestamp).max() {
+                    utilization_timestamps.insert(uuid.clone(), latest);
                 }
+                utilization_by_pid(&samples)
+            });
+
+        let mig_handles = fields
+            .read(&uuid, minor_number, "mig_devices", || match raw {
+                Some(raw) => Ok(raw.mig_devices(nvml, &device)?),
+                None => Err(FieldFailure::NotSupported),
+            })
+            .unwrap_or_default();
+
+        let mut user_usage = None;
+        if mig_handles.is_empty() {
+            user_usage = fields
+                .read_processes(&uuid, minor_number, "", |process_type| {
+                    Ok(list_processes(&device, process_type)?)
+                })
+                .map(|processes| {
+                    let mut user_usage = DeviceUsage::default();
+                    add_process_usage(
+                        processes,
+                        minor_number,
+                        process_utilization.as_ref(),
+                        None,
+                        &mut user_usage,
+                    );
+                    user_usage
+                });
+        }
+        let mut mig_devices = Vec::with_capacity(mig_handles.len());
+        let mut mig_usage = DeviceUsage::default();
+        let mut mig_processes_failed = false;
+        for mig_handle in mig_handles.iter() {
+            // Processes of a GPU in MIG mode are only listed with privileges,
+            // so they are listed for each MIG device instead
+            let Some(raw) = raw else {
+                break;
+            };
+            let identity = mig_handle.uuid().and_then(|mig_uuid| {
+                let instance = raw.mig_instance(mig_handle)?;
+                let profile = raw.mig_profile(&device, &instance)?;
+                Ok((mig_uuid, instance, profile))
+            });
+            let (mig_uuid, instance, profile) = match identity {
+                Ok(identity) => identity,
+                Err(e) => {
+                    let fatal = is_fatal(&e);
+                    fields.device_failed(index, Some(minor_number), &e, fatal);
+                    continue;
+                }
+            };
+            let memory_info = fields.read(&mig_uuid, minor_number, "mig_memory_info", || {
+                Ok(mig_handle.memory_info()?)
+            });
+            let utilization = fields.read(&mig_uuid, minor_number, "mig_utilization_rates", || {
+                Ok(mig_handle.utilization_rates()?)
+            });
+            let listed = fields.read_processes(&mig_uuid, minor_number, "mig_", |process_type| {
+                Ok(list_processes(mig_handle, process_type)?)
+            });
+            match listed {
+                Some(processes) => add_process_usage(
+                    processes,
+                    minor_number,
+                    None,
+                    Some(instance),
+                    &mut mig_usage,
+                ),
+                None => mig_processes_failed = true,
             }
+            mig_devices.push(MigDevice {
+                instance,
+                profile,
+                uuid: mig_uuid,
+                memory_total: memory_info.as_ref().map(|x| x.total),
+                memory_used: memory_info.as_ref().map(|x| x.used),
+                utilization_gpu: utilization.as_ref().map(|x| x.gpu),
+                utilization_memory: utilization.as_ref().map(|x| x.memory),
+            });
+        }
+        if !mig_handles.is_empty() && !mig_processes_failed {
+            user_usage = Some(mig_usage);
+        }
 
-            for (uid, used_gpu_memory) in user_usage.iter() {
-                let user_name = if self.known_user_map.contains_key(uid) {
-                    self.known_user_map[uid]
+        let device = NvmlDevice {
+            index,
+            minor_number,
+            name,
+            uuid,
+            pci_bus_id,
+            temperature,
+            power_usage,
+            energy_consumption,
+            fan_speed,
+            memory_total: memory_info.as_ref().map(|x| x.total),
+            memory_used: memory_info.as_ref().map(|x| x.used),
+            utilization_memory: utilization.as_ref().map(|x| x.memory),
+            utilization_gpu: utilization.as_ref().map(|x| x.gpu),
+            clocks,
+            throttle_reasons,
+            power_limit_enforced,
+            power_limit_default,
+            performance_state,
+            process_sm_utilization: user_usage.as_ref().and_then(|usage| {
+                process_utilization.as_ref()?;
+                Some(usage.users.values().filter_map(|x| x.sm_utilization).sum())
+            }),
+            process_used_memory: user_usage
+                .as_ref()
+                .map(|usage| usage.users.values().map(|x| x.used_gpu_memory).sum()),
+            ecc_errors,
+            retired_pages,
+            remapped_rows,
+            pcie,
+            nvlinks,
+            mig_devices,
+        };
+        Ok((device, user_usage))
+    }
+
+    fn resolve_users(
+        &mut self,
+        index: u32,
+        user_usage: DeviceUsage,
+        users_utilization: &mut Vec<NvmlUserUtilization>,
+        processes: &mut Vec<NvmlProcess>,
+    ) {
+        // for user in self.known_user_map.values().chain(self.blocked_user_map.values()) {
+        //     user_usage.entry(user.uid()).or_insert(0);
+        // }
+
+        for (uid, _) in user_usage.users.keys() {
+            if !self.known_user_map.contains_key(uid) && !self.blocked_user_map.contains_key(uid) {
+                let (new_known, new_blocked) = self.user_policy.get_users_map();
+                self.known_user_map = new_known;
+                self.blocked_user_map = new_blocked;
+                break;
+            }
+        }
+
+        for ((uid, mig), usage) in user_usage.users.iter() {
+            let Some(user_name) = self.user_name(*uid) else {
+                continue;
+            };
+            users_utilization.push(NvmlUserUtilization {
+                index,
+                user_name,
+                used_gpu_memory: usage.used_gpu_memory,
+                sm_utilization: usage.sm_utilization,
+                utilization: usage.utilization,
+                mig: *mig,
+            })
+        }
+        for (uid, mut process) in user_usage.processes {
+            process.user_name = self.user_name(uid);
+            processes.push(process);
+        }
+    }
+
+    /// Name under which a uid is reported, `None` if it is hidden
+    fn user_name(&self, uid: uid_t) -> Option<String> {
+        if self.known_user_map.contains_key(&uid) {
+            Some(
+                self.known_user_map[&uid]
+                    .name()
+                    .to_string_lossy()
+                    .to_string(),
+            )
+        } else if self.show_all_users {
+            if self.blocked_user_map.contains_key(&uid) {
+                Some(
+                    self.blocked_user_map[&uid]
                         .name()
                         .to_string_lossy()
-                        .to_string()
-                } else if self.show_all_users {
-                    if self.blocked_user_map.contains_key(uid) {
-                        self.blocked_user_map[uid]
-                            .name()
-                            .to_string_lossy()
-                            .to_string()
-                    } else {
-                        uid.to_string()
-                    }
-                } else {
-                    continue;
-                };
+                        .to_string(),
+                )
+            } else {
+                Some(uid.to_string())
+            }
+        } else {
+            None
+        }
+    }
+}
 
-                let used_gpu_memory = *used_gpu_memory;
-                users_utilization.push(NvmlUserUtilization {
-                    index,
-                    user_name,
-                    used_gpu_memory,
-                })
+fn list_processes(device: &Device, process_type: &str) -> Result<Vec<ProcessInfo>, NvmlError> {
+    match process_type {
+        "compute" => device.running_compute_processes(),
+        _ => device.running_graphics_processes(),
+    }
+}
+
+/// Adds the GPU memory and SM utilization of the processes on a device to
+/// the usage of their uid, and records the processes
+fn add_process_usage(
+    processes: Vec<(ProcessInfo, &'static str)>,
+    minor_number: u32,
+    process_utilization: Option<&HashMap<u32, ProcessUtilization>>,
+    mig: Option<MigInstance>,
+    user_usage: &mut DeviceUsage,
+) {
+    for (proc_info, process_type) in processes {
+        let proc = procfs::process::Process::new(proc_info.pid as i32);
+        let proc = if let Ok(proc) = proc {
+            proc
+        } else {
+            continue;
+        };
+        let uid = if let Ok(uid) = proc.uid() {
+            uid
+        } else {
+            continue;
+        };
+        // tracing::trace!("Nvml process pid = {}, uid = {}", proc.pid, uid);
+        let r = match proc_info.used_gpu_memory {
+            UsedGpuMemory::Used(u) => u,
+            UsedGpuMemory::Unavailable => 0,
+        };
+
+        let usage = user_usage.users.entry((uid, mig)).or_default();
+        usage.used_gpu_memory += r;
+        // Processes without samples were idle since the previous collection
+        let utilization =
+            process_utilization.map(|x| x.get(&proc_info.pid).copied().unwrap_or_default());
+        if let Some(utilization) = utilization {
+            *usage.sm_utilization.get_or_insert(0) += utilization.sm;
+            *usage.utilization.get_or_insert(0) += utilization.busiest;
+        }
+
+        let stat = proc.stat().ok();
+        let command = match proc.cmdline() {
+            Ok(cmdline) if !cmdline.is_empty() => cmdline.join(" "),
+            _ => stat.as_ref().map(|x| x.comm.clone()).unwrap_or_default(),
+        };
+        user_usage.processes.push((
+            uid,
+            NvmlProcess {
+                pid: proc_info.pid,
+                minor_number,
+                process_type,
+                user_name: None,
+                command,
+                start_time: stat.as_ref().and_then(start_time),
+                used_gpu_memory: match proc_info.used_gpu_memory {
+                    UsedGpuMemory::Used(u) => Some(u),
+                    UsedGpuMemory::Unavailable => None,
+                },
+                utilization: utilization.map(|x| x.busiest),
+                rss: stat.as_ref().map(|x| x.rss_bytes().get()),
+            },
+        ));
+    }
+}
+
+/// Start of a process as Unix time in seconds
+pub fn start_time(stat: &procfs::process::Stat) -> Option<f64> {
+    let started = stat.starttime().get().ok()?;
+    Some(started.timestamp_millis() as f64 / 1000.)
+}
+
+/// Reads the supported ECC error counters of every memory location
+fn read_ecc_errors(
+    fields: &mut FieldReader,
+    device: &Device,
+    uuid: &str,
+    minor_number: u32,
+) -> Vec<EccErrors> {
+    let mut ecc_errors = Vec::new();
+    for (counter_type, counter) in [EccCounter::Volatile, EccCounter::Aggregate]
+        .into_iter()
+        .zip(ECC_COUNTERS)
+    {
+        for (error_kind, error_type) in [MemoryError::Corrected, MemoryError::Uncorrected]
+            .into_iter()
+            .zip(MEMORY_ERROR_TYPES)
+        {
+            let locations = [
+                MemoryLocation::L1Cache,
+                MemoryLocation::L2Cache,
+                MemoryLocation::Device,
+                MemoryLocation::RegisterFile,
+                MemoryLocation::Texture,
+                MemoryLocation::Shared,
+                MemoryLocation::Cbu,
+                MemoryLocation::SRAM,
+            ];
+            for (memory_location, location) in locations.into_iter().zip(MEMORY_LOCATIONS) {
+                let field = EccErrors::field(counter, error_type, location);
+                let count = fields.read(uuid, minor_number, &field, || {
+                    Ok(device.memory_error_counter(
+                        error_kind.clone(),
+                        counter_type.clone(),
+                        memory_location,
+                    )?)
+                });
+                if let Some(count) = count {
+                    ecc_errors.push(EccErrors {
+                        counter,
+                        error_type,
+                        location,
+                        count,
+                    });
+                }
             }
         }
+    }
+    ecc_errors
+}
 
-        Ok(NvmlMetrics {
-            version,
-            devices,
-            users_utilization,
+fn read_nvlinks(
+    fields: &mut FieldReader,
+    device: &Device,
+    uuid: &str,
+    minor_number: u32,
+) -> Vec<NvLinkState> {
+    let mut nvlinks = Vec::new();
+    for link in 0..NVLINK_MAX_LINKS {
+        let nvlink = device.link_wrapper_for(link);
+        let field = NvLinkState::field(link, "state");
+        let active = fields.read(uuid, minor_number, &field, || match nvlink.is_active() {
+            // The board has fewer links
+            Err(NvmlError::InvalidArg) => Err(FieldFailure::NotSupported),
+            result => Ok(result?),
+        });
+        let Some(active) = active else {
+            continue;
+        };
+        let mut state = NvLinkState {
+            link,
+            active,
+            utilization: None,
+            errors: Vec::new(),
+        };
+        if active {
+            let field = NvLinkState::field(link, "utilization");
+            state.utilization = fields.read(uuid, minor_number, &field, || {
+                let counter = nvlink.utilization_counter(Counter::Zero)?;
+                Ok((counter.receive, counter.send))
+            });
+            let field = NvLinkState::field(link, "errors");
+            state.errors = fields
+                .read(uuid, minor_number, &field, || {
+                    let counters = [
+                        ErrorCounter::DlReplay,
+                        ErrorCounter::DlRecovery,
+                        ErrorCounter::DlCrcFlit,
+                        ErrorCounter::DlCrcData,
+                    ];
+                    let mut errors = Vec::with_capacity(counters.len());
+                    for (counter, name) in counters.into_iter().zip(NVLINK_ERROR_COUNTERS) {
+                        errors.push((name, nvlink.error_counter(counter)?));
+                    }
+                    Ok(errors)
+                })
+                .unwrap_or_default();
+        }
+        nvlinks.push(state);
+    }
+    nvlinks
+}
+
+/// Averages the utilization samples of each process
+fn utilization_by_pid(samples: &[ProcessUtilizationSample]) -> HashMap<u32, ProcessUtilization> {
+    let mut sums: HashMap<u32, ([u32; 4], u32)> = HashMap::new();
+    for sample in samples.iter() {
+        let sum = sums.entry(sample.pid).or_default();
+        sum.0[0] += sample.sm_util;
+        sum.0[1] += sample.mem_util;
+        sum.0[2] += sample.enc_util;
+        sum.0[3] += sample.dec_util;
+        sum.1 += 1;
+    }
+    sums.into_iter()
+        .map(|(pid, (totals, count))| {
+            let averages = totals.map(|x| x / count);
+            let utilization = ProcessUtilization {
+                sm: averages[0],
+                busiest: averages.into_iter().max().unwrap_or(0),
+            };
+            (pid, utilization)
         })
+        .collect()
+}
+
+impl GpuSource for NvmlMetricsCollector {
+    fn now(&mut self) -> anyhow::Result<NvmlMetrics> {
+        self.ensure_initialized()?;
+        match self.collect() {
+            Ok(metrics) if !self.fields.take_fatal() => Ok(metrics),
+            Ok(metrics) => {
+                self.dropped_field_errors = metrics.field_errors;
+                self.invalidate();
+                anyhow::bail!("NVML handle became stale")
+            }
+            Err(e) => {
+                if is_fatal(&e) {
+                    self.invalidate();
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    fn reinit_count(&self) -> u64 {
+        self.reinit_count
+    }
+
+    fn take_field_errors(&mut self) -> Vec<FieldError> {
+        std::mem::take(&mut self.dropped_field_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_field_is_not_read_again() {
+        let mut fields = FieldReader::default();
+        let mut calls = 0;
+        for _ in 0..3 {
+            let value: Option<u32> = fields.read("GPU-0", 0, "fan_speed", || {
+                calls += 1;
+                Err(FieldFailure::NotSupported)
+            });
+            assert!(value.is_none());
+        }
+        assert_eq!(calls, 1);
+        assert!(fields.take_errors().is_empty());
+        assert!(!fields.take_fatal());
+        // Other devices are still queried
+        assert_eq!(fields.read("GPU-1", 1, "fan_speed", || Ok(30)), Some(30));
+    }
+
+    #[test]
+    fn failed_field_is_retried() {
+        let mut fields = FieldReader::default();
+        let value: Option<u32> = fields.read("GPU-0", 0, "temperature", || {
+            Err(FieldFailure::Failed("Timeout".to_string()))
+        });
+        assert!(value.is_none());
+        let errors = fields.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "temperature");
+        assert_eq!(errors[0].minor_number, Some(0));
+        assert!(!fields.take_fatal());
+
+        assert_eq!(fields.read("GPU-0", 0, "temperature", || Ok(40)), Some(40));
+        assert!(fields.take_errors().is_empty());
+    }
+
+    #[test]
+    fn fatal_failure_requests_reinitialization() {
+        let mut fields = FieldReader::default();
+        let value: Option<u32> =
+            fields.read("GPU-0", 0, "power_usage", || Err(NvmlError::GpuLost.into()));
+        assert!(value.is_none());
+        assert!(fields.take_fatal());
+        assert!(!fields.take_fatal());
+        assert_eq!(fields.take_errors().len(), 1);
+    }
+
+    #[test]
+    fn process_types_are_listed_separately() {
+        let mut fields = FieldReader::default();
+        let mut graphics_calls = 0;
+        for _ in 0..2 {
+            let processes =
+                fields.read_processes("GPU-0", 0, "", |process_type| match process_type {
+                    "compute" => Ok(vec![1001]),
+                    _ => {
+                        graphics_calls += 1;
+                        Err(FieldFailure::NotSupported)
+                    }
+                });
+            assert_eq!(processes, Some(vec![(1001, "compute")]));
+        }
+        assert_eq!(graphics_calls, 1);
+
+        // A transient failure of one list makes the processes unknown
+        let processes = fields.read_processes("GPU-0", 0, "", |_| -> Result<Vec<u32>, _> {
+            Err(FieldFailure::Failed("Timeout".to_string()))
+        });
+        assert_eq!(processes, None);
+        let errors = fields.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "compute_processes");
+        assert_eq!(
+            fields.read_processes("GPU-0", 0, "", |_| Ok(vec![1002])),
+            Some(vec![(1002, "compute")])
+        );
+
+        // Nothing is listed when neither type is supported
+        let processes = fields.read_processes("GPU-1", 1, "", |_| -> Result<Vec<u32>, _> {
+            Err(FieldFailure::NotSupported)
+        });
+        assert_eq!(processes, None);
+        assert!(fields.take_errors().is_empty());
     }
 }
@@ -0,0 +1,182 @@
+use std::ffi::CStr;
+
+use nvml_wrapper::error::{nvml_sym, nvml_try, NvmlError};
+use nvml_wrapper::{Device, Nvml};
+use nvml_wrapper_sys::bindings::{
+    nvmlComputeInstanceInfo_t, nvmlComputeInstanceProfileInfo_v2_t, nvmlGpuInstanceInfo_t,
+    nvmlGpuInstanceProfileInfo_v2_t, NvmlLib, NVML_COMPUTE_INSTANCE_ENGINE_PROFILE_SHARED,
+    NVML_COMPUTE_INSTANCE_PROFILE_COUNT, NVML_DEVICE_MIG_ENABLE, NVML_GPU_INSTANCE_PROFILE_COUNT,
+};
+
+use crate::nvml_metrics::{MigInstance, RemappedRows};
+
+/// A second handle to the NVML library, for functions which nvml-wrapper does
+/// not wrap. The library is initialized through `Nvml`, which has to outlive
+/// the calls made here.
+pub struct RawNvml {
+    lib: NvmlLib,
+}
+
+impl RawNvml {
+    pub fn load() -> Result<RawNvml, NvmlError> {
+        let lib = unsafe { NvmlLib::new("libnvidia-ml.so.1") }?;
+        Ok(RawNvml { lib })
+    }
+
+    pub fn remapped_rows(&self, device: &Device) -> Result<RemappedRows, NvmlError> {
+        let sym = nvml_sym(self.lib.nvmlDeviceGetRemappedRows.as_ref())?;
+        let (mut corrected, mut uncorrected, mut pending, mut failed) = (0, 0, 0, 0);
+        unsafe {
+            nvml_try(sym(
+                device.handle(),
+                &mut corrected,
+                &mut uncorrected,
+                &mut pending,
+                &mut failed,
+            ))?;
+        }
+        Ok(RemappedRows {
+            corrected: corrected.into(),
+            uncorrected: uncorrected.into(),
+            pending: pending != 0,
+            failed: failed != 0,
+        })
+    }
+
+    /// MIG devices of a GPU, empty when MIG mode is disabled
+    pub fn mig_devices<'nvml>(
+        &self,
+        nvml: &'nvml Nvml,
+        device: &Device,
+    ) -> Result<Vec<Device<'nvml>>, NvmlError> {
+        let mig_mode = nvml_sym(self.lib.nvmlDeviceGetMigMode.as_ref())?;
+        let max_count = nvml_sym(self.lib.nvmlDeviceGetMaxMigDeviceCount.as_ref())?;
+        let by_index = nvml_sym(self.lib.nvmlDeviceGetMigDeviceHandleByIndex.as_ref())?;
+        let mut mig_devices = Vec::new();
+        unsafe {
+            let (mut current, mut pending) = (0, 0);
+            nvml_try(mig_mode(device.handle(), &mut current, &mut pending))?;
+            if current != NVML_DEVICE_MIG_ENABLE {
+                return Ok(mig_devices);
+            }
+            let mut count = 0;
+            nvml_try(max_count(device.handle(), &mut count))?;
+            for index in 0..count {
+                let mut handle = std::ptr::null_mut();
+                match nvml_try(by_index(device.handle(), index, &mut handle)) {
+                    Ok(()) => mig_devices.push(Device::new(handle, nvml)),
+                    // Slots without an instance
+                    Err(NvmlError::NotFound) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(mig_devices)
+    }
+
+    pub fn mig_instance(&self, mig_device: &Device) -> Result<MigInstance, NvmlError> {
+        let gpu_instance_id = nvml_sym(self.lib.nvmlDeviceGetGpuInstanceId.as_ref())?;
+        let compute_instance_id = nvml_sym(self.lib.nvmlDeviceGetComputeInstanceId.as_ref())?;
+        let mut instance = MigInstance::default();
+        unsafe {
+            nvml_try(gpu_instance_id(
+                mig_device.handle(),
+                &mut instance.gpu_instance_id,
+            ))?;
+            nvml_try(compute_instance_id(
+                mig_device.handle(),
+                &mut instance.compute_instance_id,
+            ))?;
+        }
+        Ok(instance)
+    }
+
+    /// Profile name as shown by `nvidia-smi`, e.g. `3g.20gb`, or the name of
+    /// the compute instance profile when it is smaller, e.g. `1c.3g.20gb`.
+    /// Both are looked up among the profiles of the GPU, as the memory of an
+    /// instance is not rounded to the size in the name.
+    pub fn mig_profile(
+        &self,
+        device: &Device,
+        instance: &MigInstance,
+    ) -> Result<String, NvmlError> {
+        let gpu_instance_by_id = nvml_sym(self.lib.nvmlDeviceGetGpuInstanceById.as_ref())?;
+        let gpu_instance_info = nvml_sym(self.lib.nvmlGpuInstanceGetInfo.as_ref())?;
+        let gpu_profile_info = nvml_sym(self.lib.nvmlDeviceGetGpuInstanceProfileInfoV.as_ref())?;
+        let compute_instance_by_id =
+            nvml_sym(self.lib.nvmlGpuInstanceGetComputeInstanceById.as_ref())?;
+        let compute_instance_info = nvml_sym(self.lib.nvmlComputeInstanceGetInfo_v2.as_ref())?;
+        let compute_profile_info = nvml_sym(
+            self.lib
+                .nvmlGpuInstanceGetComputeInstanceProfileInfoV
+                .as_ref(),
+        )?;
+        unsafe {
+            let mut gpu_instance = std::ptr::null_mut();
+            nvml_try(gpu_instance_by_id(
+                device.handle(),
+                instance.gpu_instance_id,
+                &mut gpu_instance,
+            ))?;
+            let mut info: nvmlGpuInstanceInfo_t = std::mem::zeroed();
+            nvml_try(gpu_instance_info(gpu_instance, &mut info))?;
+            let gpu_profile = find_profile(NVML_GPU_INSTANCE_PROFILE_COUNT, info.profileId, |x| {
+                let mut profile: nvmlGpuInstanceProfileInfo_v2_t = std::mem::zeroed();
+                profile.version = profile_info_version::<nvmlGpuInstanceProfileInfo_v2_t>();
+                nvml_try(gpu_profile_info(device.handle(), x, &mut profile))?;
+                Ok((profile.id, profile.sliceCount, profile.name))
+            })?;
+
+            let mut compute_instance = std::ptr::null_mut();
+            nvml_try(compute_instance_by_id(
+                gpu_instance,
+                instance.compute_instance_id,
+                &mut compute_instance,
+            ))?;
+            let mut info: nvmlComputeInstanceInfo_t = std::mem::zeroed();
+            nvml_try(compute_instance_info(compute_instance, &mut info))?;
+            let compute_profile =
+                find_profile(NVML_COMPUTE_INSTANCE_PROFILE_COUNT, info.profileId, |x| {
+                    let mut profile: nvmlComputeInstanceProfileInfo_v2_t = std::mem::zeroed();
+                    profile.version = profile_info_version::<nvmlComputeInstanceProfileInfo_v2_t>();
+                    nvml_try(compute_profile_info(
+                        gpu_instance,
+                        x,
+                        NVML_COMPUTE_INSTANCE_ENGINE_PROFILE_SHARED,
+                        &mut profile,
+                    ))?;
+                    Ok((profile.id, profile.sliceCount, profile.name))
+                })?;
+
+            let (_, _, name) = if compute_profile.1 < gpu_profile.1 {
+                compute_profile
+            } else {
+                gpu_profile
+            };
+            let name = CStr::from_ptr(name.as_ptr()).to_string_lossy();
+            Ok(name.trim_start_matches("MIG ").to_string())
+        }
+    }
+}
+
+/// Finds the profile with the ID of an instance among the profile indices,
+/// skipping those the GPU does not support
+fn find_profile<T>(
+    count: u32,
+    id: u32,
+    mut info: impl FnMut(u32) -> Result<(u32, u32, T), NvmlError>,
+) -> Result<(u32, u32, T), NvmlError> {
+    for index in 0..count {
+        match info(index) {
+            Ok(profile) if profile.0 == id => return Ok(profile),
+            Ok(_) | Err(NvmlError::NotSupported) | Err(NvmlError::InvalidArg) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(NvmlError::NotFound)
+}
+
+/// Version of a versioned NVML structure, as set by `NVML_STRUCT_VERSION`
+fn profile_info_version<T>() -> u32 {
+    std::mem::size_of::<T>() as u32 | (2 << 24)
+}
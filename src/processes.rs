@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::nvml_metrics::NvmlProcess;
+
+const REDACTED: &str = "<redacted>";
+/// Options which look like credentials, with their value
+const DEFAULT_REDACT: &str = r"(?i)--?[\w.-]*(token|password|passwd|secret|key)[\w.-]*[= ]\S+";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProcessConfig {
+    /// Most processes exported, those using the most GPU memory first
+    pub max_series: usize,
+    /// Characters of the command line kept in the `command` label
+    pub command_length: usize,
+    /// Matches in command lines are replaced by `<redacted>`, options which
+    /// look like credentials by default
+    pub redact: Option<String>,
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        ProcessConfig {
+            max_series: 100,
+            command_length: 64,
+            redact: Some(DEFAULT_REDACT.to_string()),
+        }
+    }
+}
+
+/// Picks the processes to export and cleans up their command lines
+#[derive(Clone)]
+pub struct ProcessSelector {
+    max_series: usize,
+    command_length: usize,
+    redact: Option<Regex>,
+}
+
+impl ProcessSelector {
+    pub fn new(config: &ProcessConfig) -> Result<ProcessSelector> {
+        let redact = config
+            .redact
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .with_context(|| "Invalid processes.redact")?;
+        Ok(ProcessSelector {
+            max_series: config.max_series,
+            command_length: config.command_length,
+            redact,
+        })
+    }
+
+    /// Returns the processes to export and the count of those left out
+    pub fn select(&self, processes: &[NvmlProcess]) -> (Vec<NvmlProcess>, usize) {
+        let mut selected: Vec<NvmlProcess> = processes
+            .iter()
+            .filter(|x| x.user_name.is_some())
+            .cloned()
+            .collect();
+        selected.sort_by_key(|x| std::cmp::Reverse(x.used_gpu_memory));
+        let dropped = selected.len().saturating_sub(self.max_series);
+        selected.truncate(self.max_series);
+        for process in selected.iter_mut() {
+            process.command = self.command(&process.command);
+        }
+        (selected, dropped)
+    }
+
+    /// Redacts before truncating, so that a secret cut at the end of the
+    /// label still matches
+    pub fn command(&self, command: &str) -> String {
+        let command = match &self.redact {
+            Some(redact) => redact.replace_all(command, REDACTED),
+            None => command.into(),
+        };
+        command.chars().take(self.command_length).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_redacted_by_default() {
+        let selector = ProcessSelector::new(&ProcessConfig::default()).unwrap();
+        assert_eq!(
+            selector.command("python serve.py --port 80 --api-key abc --token=def"),
+            "python serve.py --port 80 <redacted> <redacted>"
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, Local, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::enforcement::EnforcementConfig;
+use crate::metrics::QuotaStatus;
+use crate::nvml_metrics::{cards_by_user, NvmlUserUtilization};
+use crate::utils::{self, GroupCache};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Number of violations kept for the JSON endpoint
+    pub history: usize,
+    pub rules: Vec<QuotaRule>,
+    /// Actions are taken against violations when set
+    pub enforcement: Option<EnforcementConfig>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            history: 100,
+            rules: Vec::new(),
+            enforcement: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuotaRule {
+    /// Value of the `rule` label
+    pub name: String,
+    /// Users the rule applies to. The rule applies to every user when
+    /// neither users nor groups are given.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Unix groups whose members the rule applies to
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Most GPUs a user may hold memory on
+    pub max_cards: Option<u32>,
+    /// Most GPU memory in bytes a user may hold over all GPUs
+    pub max_memory: Option<u64>,
+    /// The rule is always enforced when unset
+    pub schedule: Option<ScheduleConfig>,
+    /// Violations of a soft rule are notified but never enforced
+    #[serde(default)]
+    pub soft: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleConfig {
+    /// Days of the week, e.g. `mon`, every day when empty
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Local time `HH:MM` the rule starts applying
+    pub from: String,
+    /// Local time `HH:MM` the rule stops applying, before `from` for a
+    /// window across midnight
+    pub to: String,
+}
+
+struct Schedule {
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    to: NaiveTime,
+}
+
+impl Schedule {
+    fn new(config: &ScheduleConfig) -> Result<Schedule> {
+        let days = config
+            .days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| anyhow::anyhow!("Invalid day {}", day))
+            })
+            .collect::<Result<_>>()?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .with_context(|| format!("Invalid time {}, expected HH:MM", time))
+        };
+        Ok(Schedule {
+            days,
+            from: time(&config.from)?,
+            to: time(&config.to)?,
+        })
+    }
+
+    /// Whether the schedule covers the local time. Days apply to the time,
+    /// so a window across midnight ends on the next day.
+    fn contains(&self, weekday: Weekday, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            (self.days.is_empty() || self.days.contains(&weekday))
+                && self.from <= time
+                && time < self.to
+        } else if time >= self.from {
+            self.days.is_empty() || self.days.contains(&weekday)
+        } else {
+            time < self.to && (self.days.is_empty() || self.days.contains(&weekday.pred()))
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    users: HashSet<String>,
+    groups: HashSet<String>,
+    max_cards: Option<u32>,
+    max_memory: Option<u64>,
+    schedule: Option<Schedule>,
+    soft: bool,
+}
+
+/// A user over a rule, with what the user held
+#[derive(Serialize, Clone, Debug)]
+pub struct QuotaViolation {
+    pub rule: String,
+    pub user_name: String,
+    pub cards: u32,
+    pub memory: u64,
+    pub max_cards: Option<u32>,
+    pub max_memory: Option<u64>,
+    pub soft: bool,
+}
+
+/// A period during which a user violated a rule
+#[derive(Serialize, Clone, Debug)]
+pub struct QuotaEpisode {
+    pub rule: String,
+    pub user_name: String,
+    /// Unix time in seconds
+    pub started: f64,
+    /// Unix time in seconds, unset while the violation goes on
+    pub ended: Option<f64>,
+    /// Highest card count during the violation
+    pub peak_cards: u32,
+    /// Highest memory in bytes during the violation
+    pub peak_memory: u64,
+    pub max_cards: Option<u32>,
+    pub max_memory: Option<u64>,
+}
+
+/// Checks the users against the quota rules on every collection
+pub struct QuotaEvaluator {
+    rules: Vec<Rule>,
+    history: usize,
+    status: Arc<QuotaStatus>,
+    groups: GroupCache,
+}
+
+impl QuotaEvaluator {
+    pub fn new(config: &QuotaConfig, status: Arc<QuotaStatus>) -> Result<QuotaEvaluator> {
+        let mut names = HashSet::new();
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                if !names.insert(rule.name.as_str()) {
+                    anyhow::bail!("Duplicate quota rule {}", rule.name);
+                }
+                if rule.max_cards.is_none() && rule.max_memory.is_none() {
+                    anyhow::bail!(
+                        "Quota rule {} sets neither max_cards nor max_memory",
+                        rule.name
+                    );
+                }
+                let schedule = rule
+                    .schedule
+                    .as_ref()
+                    .map(Schedule::new)
+                    .transpose()
+                    .with_context(|| format!("Invalid schedule of quota rule {}", rule.name))?;
+                Ok(Rule {
+                    name: rule.name.clone(),
+                    users: rule.users.iter().cloned().collect(),
+                    groups: rule.groups.iter().cloned().collect(),
+                    max_cards: rule.max_cards,
+                    max_memory: rule.max_memory,
+                    schedule,
+                    soft: rule.soft,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(QuotaEvaluator {
+            rules,
+            history: config.history,
+            status,
+            groups: GroupCache::new(utils::user_groups),
+        })
+    }
+
+    /// Returns the current violations
+    pub fn evaluate(&mut self, users: &[NvmlUserUtilization]) -> Vec<QuotaViolation> {
+        let mut memory: HashMap<&str, u64> = HashMap::new();
+        for user in users.iter() {
+            *memory.entry(&user.user_name).or_default() += user.used_gpu_memory;
+        }
+        let cards = cards_by_user(users);
+        let now = Local::now();
+        let (weekday, time) = (now.weekday(), now.time());
+
+        let mut violations = Vec::new();
+        for rule in self.rules.iter() {
+            if let Some(schedule) = &rule.schedule {
+                if !schedule.contains(weekday, time) {
+                    continue;
+                }
+            }
+            for (user_name, cards) in cards.iter() {
+                let memory = memory.get(user_name.as_str()).copied().unwrap_or(0);
+                let over = rule.max_cards.is_some_and(|x| *cards > x)
+                    || rule.max_memory.is_some_and(|x| memory > x);
+                if !over || !applies(rule, user_name, &mut self.groups) {
+                    continue;
+                }
+                violations.push(QuotaViolation {
+                    rule: rule.name.clone(),
+                    user_name: user_name.clone(),
+                    cards: *cards,
+                    memory,
+                    max_cards: rule.max_cards,
+                    max_memory: rule.max_memory,
+                    soft: rule.soft,
+                });
+            }
+        }
+        violations
+    }
+
+    /// Exports the violations and records their start and end in the history
+    pub fn publish(&self, violations: &[QuotaViolation]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.status.update(violations, timestamp, self.history);
+    }
+
+    /// Drops the exported violations after a failed collection
+    pub fn set_failed(&self) {
+        self.status.set_failed();
+    }
+}
+
+fn applies(rule: &Rule, user_name: &str, groups: &mut GroupCache) -> bool {
+    if rule.users.is_empty() && rule.groups.is_empty() {
+        return true;
+    }
+    if rule.users.contains(user_name) {
+        return true;
+    }
+    if rule.groups.is_empty() {
+        return false;
+    }
+    groups
+        .get(user_name)
+        .iter()
+        .any(|x| rule.groups.contains(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn schedule_across_midnight_ends_the_next_day() {
+        let schedule = Schedule::new(&ScheduleConfig {
+            days: vec!["fri".to_string()],
+            from: "22:00".to_string(),
+            to: "06:00".to_string(),
+        })
+        .unwrap();
+        assert!(schedule.contains(Weekday::Fri, time("23:00")));
+        assert!(schedule.contains(Weekday::Sat, time("05:59")));
+        assert!(!schedule.contains(Weekday::Sat, time("06:00")));
+        assert!(!schedule.contains(Weekday::Sat, time("23:00")));
+        assert!(!schedule.contains(Weekday::Fri, time("05:00")));
+        assert!(!schedule.contains(Weekday::Fri, time("21:59")));
+    }
+
+    #[test]
+    fn schedule_within_a_day() {
+        let schedule = Schedule::new(&ScheduleConfig {
+            days: Vec::new(),
+            from: "09:00".to_string(),
+            to: "18:00".to_string(),
+        })
+        .unwrap();
+        assert!(schedule.contains(Weekday::Sun, time("09:00")));
+        assert!(!schedule.contains(Weekday::Mon, time("18:00")));
+    }
+
+    fn usage(index: u32, user_name: &str, used_gpu_memory: u64) -> NvmlUserUtilization {
+        NvmlUserUtilization {
+            index,
+            user_name: user_name.to_string(),
+            used_gpu_memory,
+            sm_utilization: None,
+            utilization: None,
+            mig: None,
+        }
+    }
+
+    fn evaluate(rules: &str, users: &[NvmlUserUtilization]) -> Vec<(String, String)> {
+        let config: QuotaConfig = toml::from_str(rules).unwrap();
+        let mut evaluator = QuotaEvaluator::new(&config, Arc::new(QuotaStatus::default())).unwrap();
+        let mut violations: Vec<(String, String)> = evaluator
+            .evaluate(users)
+            .into_iter()
+            .map(|x| (x.rule, x.user_name))
+            .collect();
+        violations.sort();
+        violations
+    }
+
+    fn pair(rule: &str, user_name: &str) -> (String, String) {
+        (rule.to_string(), user_name.to_string())
+    }
+
+    #[test]
+    fn rules_apply_to_every_user_by_default() {
+        let users = [
+            usage(0, "alice", 10),
+            usage(1, "alice", 10),
+            usage(0, "bob", 10),
+        ];
+        let violations = evaluate("[[rules]]\nname = \"cards\"\nmax_cards = 1\n", &users);
+        assert_eq!(violations, vec![pair("cards", "alice")]);
+    }
+
+    #[test]
+    fn rules_apply_to_listed_users_and_groups() {
+        let users = [
+            usage(0, "alice", 200),
+            usage(0, "bob", 100),
+            usage(1, "bob", 100),
+            usage(0, "root", 300),
+        ];
+        let rules = "[[rules]]\nname = \"memory\"\nusers = [\"bob\"]\nmax_memory = 150\n\
+                     [[rules]]\nname = \"admins\"\ngroups = [\"root\"]\nmax_memory = 250\n";
+        let violations = evaluate(rules, &users);
+        assert_eq!(
+            violations,
+            vec![pair("admins", "root"), pair("memory", "bob")]
+        );
+    }
+
+    #[test]
+    fn rules_need_a_limit() {
+        let config: QuotaConfig = toml::from_str("[[rules]]\nname = \"none\"\n").unwrap();
+        assert!(QuotaEvaluator::new(&config, Arc::new(QuotaStatus::default())).is_err());
+    }
+}
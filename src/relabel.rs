@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::exposition::{is_valid_label_name, Labels, MetricFamily};
+
+const METRIC_NAME_LABEL: &str = "__name__";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RelabelAction {
+    #[default]
+    Replace,
+    Keep,
+    Drop,
+    LabelMap,
+    LabelDrop,
+    LabelKeep,
+}
+
+/// A rule in the style of Prometheus `metric_relabel_configs`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RelabelConfig {
+    pub source_labels: Vec<String>,
+    pub separator: String,
+    pub regex: String,
+    pub target_label: Option<String>,
+    pub replacement: String,
+    pub action: RelabelAction,
+}
+
+impl Default for RelabelConfig {
+    fn default() -> Self {
+        RelabelConfig {
+            source_labels: Vec::new(),
+            separator: ";".to_string(),
+            regex: "(.*)".to_string(),
+            target_label: None,
+            replacement: "$1".to_string(),
+            action: RelabelAction::Replace,
+        }
+    }
+}
+
+struct RelabelRule {
+    config: RelabelConfig,
+    regex: Regex,
+}
+
+#[derive(Default)]
+pub struct Relabeler {
+    static_labels: BTreeMap<String, String>,
+    rules: Vec<RelabelRule>,
+}
+
+impl Relabeler {
+    pub fn new(
+        static_labels: &BTreeMap<String, String>,
+        configs: &[RelabelConfig],
+    ) -> Result<Relabeler> {
+        for name in static_labels.keys() {
+            if !is_valid_label_name(name) {
+                anyhow::bail!("Invalid static label name {:?}", name);
+            }
+        }
+
+        let mut rules = Vec::with_capacity(configs.len());
+        for (i, config) in configs.iter().enumerate() {
+            let regex = Regex::new(&format!("^(?:{})$", config.regex))
+                .with_context(|| format!("Invalid regex in relabel rule #{}", i + 1))?;
+            if config.action == RelabelAction::Replace {
+                match config.target_label.as_deref() {
+                    Some(target) if target == METRIC_NAME_LABEL || is_valid_label_name(target) => {}
+                    Some(target) => {
+                        anyhow::bail!(
+                            "Invalid target_label {:?} in relabel rule #{}",
+                            target,
+                            i + 1
+                        )
+                    }
+                    None => anyhow::bail!("Relabel rule #{} requires a target_label", i + 1),
+                }
+            }
+            rules.push(RelabelRule {
+                config: config.clone(),
+                regex,
+            });
+        }
+
+        Ok(Relabeler {
+            static_labels: static_labels.clone(),
+            rules,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.static_labels.is_empty() && self.rules.is_empty()
+    }
+
+    /// Applies static labels and relabel rules to every sample. Samples
+    /// renamed out of their family are moved to the family of their new name,
+    /// an untyped one unless it exists. Samples left with the name and labels
+    /// of a previous one are dropped, as are families without samples.
+    pub fn apply(&self, families: &mut Vec<MetricFamily>) {
+        let mut renamed = Vec::new();
+        for family in families.iter_mut() {
+            let samples = std::mem::take(&mut family.samples);
+            for mut sample in samples {
+                let mut labels: BTreeMap<String, String> = sample.labels.drain(..).collect();
+                for (name, value) in self.static_labels.iter() {
+                    labels.entry(name.clone()).or_insert_with(|| value.clone());
+                }
+                labels.insert(METRIC_NAME_LABEL.to_string(), sample.name.clone());
+
+                if !self.relabel(&mut labels) {
+                    continue;
+                }
+
+                match labels.remove(METRIC_NAME_LABEL) {
+                    Some(name) if !name.is_empty() => sample.name = name,
+                    _ => continue,
+                }
+                sample.labels = labels
+                    .into_iter()
+                    .filter(|(name, value)| !name.starts_with("__") && !value.is_empty())
+                    .collect();
+                if family.owns(&sample.name) {
+                    family.samples.push(sample);
+                } else {
+                    renamed.push(sample);
+                }
+            }
+        }
+        for sample in renamed {
+            match families.iter_mut().find(|x| x.owns(&sample.name)) {
+                Some(family) => family.samples.push(sample),
+                None => {
+                    let mut family = MetricFamily::new(&sample.name);
+                    family.samples.push(sample);
+                    families.push(family);
+                }
+            }
+        }
+
+        let mut seen: HashSet<(String, Labels)> = HashSet::new();
+        for family in families.iter_mut() {
+            family
+                .samples
+                .retain(|x| seen.insert((x.name.clone(), x.labels.clone())));
+        }
+        families.retain(|family| !family.samples.is_empty());
+    }
+
+    /// Returns false if the sample should be dropped
+    fn relabel(&self, labels: &mut BTreeMap<String, String>) -> bool {
+        for RelabelRule { config, regex } in self.rules.iter() {
+            let source = config
+                .source_labels
+                .iter()
+                .map(|name| labels.get(name).map(String::as_str).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(&config.separator);
+
+            match config.action {
+                RelabelAction::Keep => {
+                    if !regex.is_match(&source) {
+                        return false;
+                    }
+                }
+                RelabelAction::Drop => {
+                    if regex.is_match(&source) {
+                        return false;
+                    }
+                }
+                RelabelAction::Replace => {
+                    let Some(captures) = regex.captures(&source) else {
+                        continue;
+                    };
+                    let mut value = String::new();
+                    captures.expand(&config.replacement, &mut value);
+                    let target = config.target_label.clone().unwrap_or_default();
+                    if value.is_empty() {
+                        labels.remove(&target);
+                    } else {
+                        labels.insert(target, value);
+                    }
+                }
+                RelabelAction::LabelMap => {
+                    let mapped: Vec<(String, String)> = labels
+                        .iter()
+                        .filter_map(|(name, value)| {
+                            let captures = regex.captures(name)?;
+                            let mut target = String::new();
+                            captures.expand(&config.replacement, &mut target);
+                            Some((target, value.clone()))
+                        })
+                        .collect();
+                    labels.extend(mapped);
+                }
+                RelabelAction::LabelDrop => {
+                    labels.retain(|name, _| name == METRIC_NAME_LABEL || !regex.is_match(name));
+                }
+                RelabelAction::LabelKeep => {
+                    labels.retain(|name, _| name == METRIC_NAME_LABEL || regex.is_match(name));
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exposition;
+
+    const METRICS: &str = "# HELP node_load Load\n\
+                           # TYPE node_load gauge\n\
+                           node_load{cpu=\"0\",host=\"a\"} 1\n\
+                           node_load{cpu=\"1\",host=\"a\"} 2\n\
+                           # HELP node_temperature Temperature\n\
+                           # TYPE node_temperature gauge\n\
+                           node_temperature{sensor=\"gpu\"} 40\n";
+
+    fn relabel(static_labels: &[(&str, &str)], rules: &str) -> String {
+        #[derive(Deserialize)]
+        struct Rules {
+            #[serde(default)]
+            rule: Vec<RelabelConfig>,
+        }
+        let rules: Rules = toml::from_str(rules).unwrap();
+        let static_labels = static_labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let relabeler = Relabeler::new(&static_labels, &rules.rule).unwrap();
+        let mut families = exposition::parse(METRICS).unwrap();
+        relabeler.apply(&mut families);
+        let mut text = String::new();
+        exposition::encode(&families, &mut text);
+        text
+    }
+
+    #[test]
+    fn keep_and_drop_filter_samples() {
+        let kept = relabel(
+            &[],
+            "[[rule]]\naction = \"keep\"\nsource_labels = [\"__name__\", \"cpu\"]\nregex = \"node_load;1\"\n",
+        );
+        assert_eq!(
+            kept,
+            "# HELP node_load Load\n# TYPE node_load gauge\nnode_load{cpu=\"1\",host=\"a\"} 2\n"
+        );
+        let dropped = relabel(
+            &[],
+            "[[rule]]\naction = \"drop\"\nsource_labels = [\"__name__\"]\nregex = \"node_load\"\n",
+        );
+        assert_eq!(dropped, "# HELP node_temperature Temperature\n# TYPE node_temperature gauge\nnode_temperature{sensor=\"gpu\"} 40\n");
+    }
+
+    #[test]
+    fn replace_sets_and_removes_labels() {
+        let text = relabel(
+            &[],
+            "[[rule]]\nsource_labels = [\"sensor\"]\nregex = \"(g)pu\"\ntarget_label = \"kind\"\nreplacement = \"${1}raphics\"\n\
+             [[rule]]\nsource_labels = [\"missing\"]\ntarget_label = \"host\"\n",
+        );
+        assert!(text.contains("node_load{cpu=\"0\"} 1\n"));
+        assert!(text.contains("node_temperature{kind=\"graphics\",sensor=\"gpu\"} 40\n"));
+    }
+
+    #[test]
+    fn renamed_samples_move_to_their_family() {
+        let text = relabel(
+            &[],
+            "[[rule]]\nsource_labels = [\"__name__\", \"cpu\"]\nregex = \"node_load;1\"\ntarget_label = \"__name__\"\nreplacement = \"node_temperature\"\n\
+             [[rule]]\nsource_labels = [\"__name__\"]\nregex = \"node_temperature\"\ntarget_label = \"__name__\"\nreplacement = \"gpu_temperature\"\n",
+        );
+        assert_eq!(
+            text,
+            "# HELP node_load Load\n# TYPE node_load gauge\nnode_load{cpu=\"0\",host=\"a\"} 1\n\
+             gpu_temperature{cpu=\"1\",host=\"a\"} 2\ngpu_temperature{sensor=\"gpu\"} 40\n"
+        );
+    }
+
+    #[test]
+    fn labelmap_copies_labels() {
+        let text = relabel(
+            &[],
+            "[[rule]]\naction = \"labelmap\"\nregex = \"(host)\"\nreplacement = \"node_${1}\"\n",
+        );
+        assert!(text.contains("node_load{cpu=\"0\",host=\"a\",node_host=\"a\"} 1\n"));
+    }
+
+    #[test]
+    fn labeldrop_and_labelkeep_remove_labels_and_duplicates() {
+        let text = relabel(&[], "[[rule]]\naction = \"labeldrop\"\nregex = \"cpu\"\n");
+        assert!(text.contains("node_load{host=\"a\"} 1\n"));
+        assert!(!text.contains("node_load{host=\"a\"} 2\n"));
+        let text = relabel(&[], "[[rule]]\naction = \"labelkeep\"\nregex = \"cpu\"\n");
+        assert!(text.contains("node_load{cpu=\"1\"} 2\n"));
+        assert!(text.contains("node_temperature 40\n"));
+    }
+
+    #[test]
+    fn static_labels_do_not_override() {
+        let text = relabel(&[("host", "b"), ("site", "x")], "");
+        assert!(text.contains("node_load{cpu=\"0\",host=\"a\",site=\"x\"} 1\n"));
+        assert!(text.contains("node_temperature{host=\"b\",sensor=\"gpu\",site=\"x\"} 40\n"));
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::accounting::AccountingMeter;
+use crate::energy::EnergyMeter;
+use crate::enforcement::Enforcer;
+use crate::idle::IdleDetector;
+use crate::metrics::Metrics;
+use crate::notifications::Notifier;
+use crate::nvml_metrics::{GpuSource, NvmlMetrics};
+use crate::processes::{ProcessConfig, ProcessSelector};
+use crate::quotas::QuotaEvaluator;
+use crate::teams::TeamMapper;
+use crate::workloads::WorkloadClassifier;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SamplerConfig {
+    /// Seconds between two collections
+    pub interval: f64,
+    /// Seconds covered by the min, max and mean gauges
+    pub window: f64,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        SamplerConfig {
+            interval: 1.,
+            window: 30.,
+        }
+    }
+}
+
+/// Values of a device kept in the window
+#[derive(Clone, Debug)]
+pub struct DeviceSample {
+    pub minor_number: u32,
+    pub utilization_gpu: Option<f64>,
+    pub power_usage: Option<f64>,
+    pub temperature: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WindowStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// Ring buffer of the samples collected within the last `length`
+pub struct SampleWindow {
+    length: Duration,
+    samples: VecDeque<(Instant, Vec<DeviceSample>)>,
+}
+
+impl SampleWindow {
+    pub fn new(length: Duration) -> SampleWindow {
+        SampleWindow {
+            length,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, at: Instant, state: &NvmlMetrics) {
+        while let Some((oldest, _)) = self.samples.front() {
+            if at.duration_since(*oldest) < self.length {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        let devices = state
+            .devices
+            .iter()
+            .map(|device| DeviceSample {
+                minor_number: device.minor_number,
+                utilization_gpu: device.utilization_gpu.map(|x| x as f64 / 100.),
+                power_usage: device.power_usage.map(f64::from),
+                temperature: device.temperature.map(f64::from),
+            })
+            .collect();
+        self.samples.push_back((at, devices));
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Minor numbers of the devices in the latest sample
+    pub fn minor_numbers(&self) -> Vec<u32> {
+        match self.samples.back() {
+            Some((_, devices)) => devices.iter().map(|x| x.minor_number).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Statistics of a field of a device over the window, skipping samples
+    /// where the field was unavailable
+    pub fn stats(
+        &self,
+        minor_number: u32,
+        field: fn(&DeviceSample) -> Option<f64>,
+    ) -> Option<WindowStats> {
+        let mut count = 0;
+        let mut stats = WindowStats {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.,
+        };
+        for (_, devices) in self.samples.iter() {
+            let value = devices
+                .iter()
+                .find(|x| x.minor_number == minor_number)
+                .and_then(field);
+            if let Some(value) = value {
+                count += 1;
+                stats.min = stats.min.min(value);
+                stats.max = stats.max.max(value);
+                stats.mean += value;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        stats.mean /= count as f64;
+        Some(stats)
+    }
+}
+
+/// Progress of the sampler, shared with the HTTP handlers
+pub struct SamplerStatus {
+    interval: Duration,
+    last_sample: Mutex<Instant>,
+}
+
+impl SamplerStatus {
+    /// Whether no collection has finished within one interval plus `timeout`,
+    /// which means that the collector is stuck
+    pub fn is_stale(&self, timeout: Duration) -> bool {
+        self.last_sample.lock().unwrap().elapsed() > self.interval + timeout
+    }
+}
+
+/// Polls the GPU source on a dedicated thread, so that scrapes never wait
+/// for NVML
+pub struct Sampler {
+    source: Box<dyn GpuSource>,
+    metrics: Arc<Metrics>,
+    window: SampleWindow,
+    energy: EnergyMeter,
+    /// Set when per-process series are exported
+    processes: Option<ProcessSelector>,
+    /// Cleans up the commands of idle holds, as configured for per-process
+    /// series or with their defaults
+    commands: ProcessSelector,
+    workloads: WorkloadClassifier,
+    /// Set when idle holds are detected
+    idle: Option<IdleDetector>,
+    /// Set when the usage of the users is accounted
+    accounting: Option<AccountingMeter>,
+    /// Set when the usage of teams is exported
+    teams: Option<TeamMapper>,
+    /// Set when quota rules are configured
+    quotas: Option<QuotaEvaluator>,
+    /// Set when quota violations are enforced
+    enforcer: Option<Enforcer>,
+    /// Set when users are notified on their terminals
+    notifier: Option<Notifier>,
+    status: Arc<SamplerStatus>,
+}
+
+impl Sampler {
+    pub fn new(
+        config: &SamplerConfig,
+        workloads: WorkloadClassifier,
+        source: Box<dyn GpuSource>,
+        metrics: Arc<Metrics>,
+    ) -> Sampler {
+        Sampler {
+            source,
+            metrics,
+            window: SampleWindow::new(Duration::from_secs_f64(config.window)),
+            energy: EnergyMeter::default(),
+            processes: None,
+            commands: ProcessSelector::new(&ProcessConfig::default()).unwrap(),
+            workloads,
+            idle: None,
+            accounting: None,
+            teams: None,
+            quotas: None,
+            enforcer: None,
+            notifier: None,
+            status: Arc::new(SamplerStatus {
+                interval: Duration::from_secs_f64(config.interval),
+                last_sample: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    pub fn export_processes(&mut self, selector: ProcessSelector) {
+        self.commands = selector.clone();
+        self.processes = Some(selector);
+    }
+
+    pub fn detect_idle(&mut self, detector: IdleDetector) {
+        self.idle = Some(detector);
+    }
+
+    pub fn account(&mut self, meter: AccountingMeter) {
+        self.accounting = Some(meter);
+    }
+
+    pub fn aggregate_teams(&mut self, mapper: TeamMapper) {
+        self.teams = Some(mapper);
+    }
+
+    pub fn enforce_quotas(&mut self, evaluator: QuotaEvaluator) {
+        self.quotas = Some(evaluator);
+    }
+
+    pub fn enforce(&mut self, enforcer: Enforcer) {
+        self.enforcer = Some(enforcer);
+    }
+
+    pub fn notify(&mut self, notifier: Notifier) {
+        self.notifier = Some(notifier);
+    }
+
+    pub fn status(&self) -> Arc<SamplerStatus> {
+        self.status.clone()
+    }
+
+    pub fn spawn(self) -> std::io::Result<()> {
+        std::thread::Builder::new()
+            .name("sampler".to_string())
+            .spawn(move || self.run())?;
+        Ok(())
+    }
+
+    fn run(mut self) {
+        let interval = self.status.interval;
+        let mut next = Instant::now();
+        loop {
+            self.sample_or_recover();
+            next += interval;
+            let now = Instant::now();
+            if next > now {
+                std::thread::sleep(next - now);
+            } else {
+                // Collection took longer than the interval, skip the missed ticks
+                next = now;
+            }
+        }
+    }
+
+    /// Samples, recovering from a panic so that the sampler thread keeps
+    /// running. The panic is printed by the panic hook; `last_sample` is left
+    /// alone, so that scrapes report the sampler as stale if it keeps panicking.
+    fn sample_or_recover(&mut self) {
+        if panic::catch_unwind(AssertUnwindSafe(|| self.sample())).is_err() {
+            eprintln!("Metric update panicked, retrying at the next interval");
+            self.clear();
+        }
+    }
+
+    /// Forgets the samples of previous collections, which must not be
+    /// compared with the next one
+    fn clear(&mut self) {
+        self.window.clear();
+        self.energy.clear();
+        if let Some(meter) = &mut self.accounting {
+            meter.clear();
+        }
+    }
+
+    fn sample(&mut self) {
+        let state = self.source.now();
+        let reinit_count = self.source.reinit_count();
+        match state {
+            Ok(state) => {
+                let now = Instant::now();
+                self.window.push(now, &state);
+                let energy = self.energy.observe(now, &state);
+                if let Some(meter) = &mut self.accounting {
+                    meter.observe(now, &state.users_utilization);
+                }
+                let teams = self
+                    .teams
+                    .as_mut()
+                    .map(|mapper| mapper.usage(&state.users_utilization));
+                let violations = self
+                    .quotas
+                    .as_mut()
+                    .map(|evaluator| evaluator.evaluate(&state.users_utilization));
+                if let Some(violations) = &violations {
+                    if let Some(notifier) = &mut self.notifier {
+                        notifier.notify_quota(now, violations);
+                    }
+                    if let Some(enforcer) = &mut self.enforcer {
+                        enforcer.enforce(now, violations, &state.processes);
+                    }
+                }
+                let workloads = self.workloads.usage(&state.processes);
+                let processes = self
+                    .processes
+                    .as_ref()
+                    .map(|selector| selector.select(&state.processes));
+                let holds = self.idle.as_mut().map(|detector| {
+                    let mut holds = detector.observe(now, &state);
+                    for hold in holds.iter_mut() {
+                        hold.command = self.commands.command(&hold.command);
+                    }
+                    holds
+                });
+                if let (Some(holds), Some(notifier)) = (&holds, &mut self.notifier) {
+                    notifier.notify_idle(now, holds);
+                }
+
+                // Families are rebuilt while scrapes wait, so that a scrape
+                // sees either the previous collection or this one
+                let _update = self.metrics.lock_update();
+                self.metrics.set_reinit_count(reinit_count);
+                self.metrics.update(&state);
+                self.metrics.update_window(&self.window);
+                self.metrics.add_energy(&energy);
+                if let Some(teams) = teams {
+                    self.metrics.update_teams(&teams);
+                }
+                if let (Some(evaluator), Some(violations)) = (&self.quotas, violations) {
+                    evaluator.publish(&violations);
+                }
+                self.metrics.update_workloads(&workloads);
+                if let Some((processes, dropped)) = processes {
+                    self.metrics.update_processes(&processes, dropped);
+                }
+                if let Some(holds) = holds {
+                    self.metrics.update_idle(holds);
+                }
+            }
+            Err(e) => {
+                eprintln!("Metric update failed: {:#}", e);
+                self.clear();
+                // Idle holds are kept, a failed collection says nothing
+                // about the processes
+                let field_errors = self.source.take_field_errors();
+                let _update = self.metrics.lock_update();
+                self.metrics.set_reinit_count(reinit_count);
+                self.metrics.set_failed();
+                self.metrics.add_field_errors(&field_errors);
+                if let Some(evaluator) = &self.quotas {
+                    evaluator.set_failed();
+                }
+            }
+        }
+        *self.status.last_sample.lock().unwrap() = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workloads::WorkloadConfig;
+
+    /// Panics on the first collection and fails on the next ones
+    struct PanickingSource {
+        calls: usize,
+    }
+
+    impl GpuSource for PanickingSource {
+        fn now(&mut self) -> anyhow::Result<NvmlMetrics> {
+            self.calls += 1;
+            if self.calls == 1 {
+                panic!("collection panicked");
+            }
+            anyhow::bail!("collection failed")
+        }
+    }
+
+    #[test]
+    fn sampler_recovers_from_panics() {
+        let mut sampler = Sampler::new(
+            &SamplerConfig::default(),
+            WorkloadClassifier::new(&WorkloadConfig::default()),
+            Box::new(PanickingSource { calls: 0 }),
+            Arc::new(Metrics::new()),
+        );
+        let status = sampler.status();
+        let started = *status.last_sample.lock().unwrap();
+        sampler.sample_or_recover();
+        assert_eq!(*status.last_sample.lock().unwrap(), started);
+        sampler.sample_or_recover();
+        assert!(*status.last_sample.lock().unwrap() > started);
+    }
+}
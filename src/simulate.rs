@@ -0,0 +1,1437 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::nvml_metrics::{
+    ClockSpeed, EccErrors, FieldFailure, FieldReader, GpuSource, MigDevice, MigInstance,
+    NvLinkState, NvmlDevice, NvmlMetrics, NvmlProcess, NvmlUserUtilization, PcieLink, RemappedRows,
+    RetiredPages, CLOCK_DOMAINS, ECC_COUNTERS, MEMORY_ERROR_TYPES, MEMORY_LOCATIONS,
+    NVLINK_ERROR_COUNTERS, NVLINK_MAX_LINKS, THROTTLE_REASONS,
+};
+
+/// Fields whose reading can fail, named as in the NVML collector. ECC
+/// counters and NVLink links are named by `EccErrors::field` and
+/// `NvLinkState::field`.
+const FIELDS: [&str; 34] = [
+    "pci_info",
+    "temperature",
+    "power_usage",
+    "energy_consumption",
+    "fan_speed",
+    "memory_info",
+    "utilization_rates",
+    "compute_processes",
+    "graphics_processes",
+    "process_utilization",
+    "clock_graphics",
+    "clock_sm",
+    "clock_memory",
+    "max_clock_graphics",
+    "max_clock_sm",
+    "max_clock_memory",
+    "throttle_reasons",
+    "enforced_power_limit",
+    "default_power_limit",
+    "performance_state",
+    "retired_pages",
+    "remapped_rows",
+    "pcie_link_gen",
+    "pcie_link_width",
+    "max_pcie_link_gen",
+    "max_pcie_link_width",
+    "pcie_tx_throughput",
+    "pcie_rx_throughput",
+    "pcie_replay_counter",
+    "mig_devices",
+    "mig_memory_info",
+    "mig_utilization_rates",
+    "mig_compute_processes",
+    "mig_graphics_processes",
+];
+
+const NVLINK_FIELDS: [&str; 3] = ["state", "utilization", "errors"];
+
+/// A value which may change between collections
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "UncheckedValueSpec")]
+pub enum ValueSpec {
+    Static(f64),
+    Ramp { ramp: Ramp },
+    RandomWalk { random_walk: RandomWalk },
+}
+
+/// A `ValueSpec` as written in the scenario, before its bounds are checked
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UncheckedValueSpec {
+    Static(f64),
+    Ramp { ramp: Ramp },
+    RandomWalk { random_walk: RandomWalk },
+}
+
+impl TryFrom<UncheckedValueSpec> for ValueSpec {
+    type Error = String;
+
+    fn try_from(spec: UncheckedValueSpec) -> Result<ValueSpec, String> {
+        let (values, spec) = match spec {
+            UncheckedValueSpec::Static(value) => (vec![value], ValueSpec::Static(value)),
+            UncheckedValueSpec::Ramp { ramp } => {
+                if ramp.period <= 0. {
+                    return Err("Scenario error: ramp.period should be larger than 0".to_string());
+                }
+                (
+                    vec![ramp.from, ramp.to, ramp.period],
+                    ValueSpec::Ramp { ramp },
+                )
+            }
+            UncheckedValueSpec::RandomWalk { random_walk } => {
+                if random_walk.min > random_walk.max {
+                    return Err(
+                        "Scenario error: random_walk.min should not be larger than random_walk.max"
+                            .to_string(),
+                    );
+                }
+                if random_walk.step < 0. {
+                    return Err(
+                        "Scenario error: random_walk.step should not be negative".to_string()
+                    );
+                }
+                let values = vec![
+                    random_walk.start,
+                    random_walk.min,
+                    random_walk.max,
+                    random_walk.step,
+                ];
+                (values, ValueSpec::RandomWalk { random_walk })
+            }
+        };
+        if !values.iter().all(|x| x.is_finite()) {
+            return Err("Scenario error: values should be finite".to_string());
+        }
+        Ok(spec)
+    }
+}
+
+/// Rises linearly from `from` to `to` within `period` seconds, then starts over
+#[derive(Deserialize, Debug, Clone)]
+pub struct Ramp {
+    pub from: f64,
+    pub to: f64,
+    pub period: f64,
+}
+
+/// Moves by at most `step` on each collection, staying within `[min, max]`
+#[derive(Deserialize, Debug, Clone)]
+pub struct RandomWalk {
+    pub start: f64,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SimulatedDevice {
+    pub name: String,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub minor_number: Option<u32>,
+    /// Defaults to a bus numbered after the index
+    #[serde(default)]
+    pub pci_bus_id: Option<String>,
+    pub memory_total: u64,
+    /// Defaults to the memory used by the processes on the device
+    #[serde(default)]
+    pub memory_used: Option<ValueSpec>,
+    #[serde(default = "default_temperature")]
+    pub temperature: ValueSpec,
+    #[serde(default = "default_zero")]
+    pub power_usage: ValueSpec,
+    /// Report a total energy counter integrated from the simulated power.
+    /// Without it, syswatch integrates the power itself.
+    #[serde(default)]
+    pub energy_counter: bool,
+    #[serde(default = "default_zero")]
+    pub fan_speed: ValueSpec,
+    #[serde(default = "default_zero")]
+    pub utilization_gpu: ValueSpec,
+    #[serde(default = "default_zero")]
+    pub utilization_memory: ValueSpec,
+    /// Current clocks in MHz, keyed by domain. Missing domains are reported
+    /// as not supported, as are the optional fields below.
+    #[serde(default)]
+    pub clocks: BTreeMap<String, ValueSpec>,
+    #[serde(default)]
+    pub max_clocks: BTreeMap<String, u32>,
+    #[serde(default)]
+    pub throttle_reasons: Option<Vec<String>>,
+    #[serde(default)]
+    pub power_limit: Option<u32>,
+    #[serde(default)]
+    pub power_limit_default: Option<u32>,
+    #[serde(default)]
+    pub performance_state: Option<u32>,
+    #[serde(default)]
+    pub ecc_errors: Vec<SimulatedEccErrors>,
+    #[serde(default)]
+    pub retired_pages: Option<RetiredPages>,
+    #[serde(default)]
+    pub remapped_rows: Option<RemappedRows>,
+    #[serde(default)]
+    pub pcie: SimulatedPcie,
+    /// NVLink links, numbered by their position
+    #[serde(default)]
+    pub nvlink: Vec<SimulatedNvLink>,
+    /// MIG devices, the GPU is in MIG mode when any is listed
+    #[serde(default)]
+    pub mig: Vec<SimulatedMigDevice>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SimulatedMigDevice {
+    pub gpu_instance_id: u32,
+    #[serde(default)]
+    pub compute_instance_id: u32,
+    pub profile: String,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    pub memory_total: u64,
+    /// Defaults to the memory used by the processes on the instance
+    #[serde(default)]
+    pub memory_used: Option<ValueSpec>,
+    /// Utilization is not supported when absent, as on most MIG devices
+    #[serde(default)]
+    pub utilization_gpu: Option<ValueSpec>,
+    #[serde(default = "default_zero")]
+    pub utilization_memory: ValueSpec,
+}
+
+impl SimulatedMigDevice {
+    fn instance(&self) -> MigInstance {
+        MigInstance {
+            gpu_instance_id: self.gpu_instance_id,
+            compute_instance_id: self.compute_instance_id,
+        }
+    }
+}
+
+/// PCIe link of a device. Missing values are reported as not supported.
+#[derive(Deserialize, Debug, Default)]
+pub struct SimulatedPcie {
+    #[serde(default)]
+    pub generation: Option<u32>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub max_generation: Option<u32>,
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    /// Throughput in KB/s
+    #[serde(default)]
+    pub tx_throughput: Option<ValueSpec>,
+    #[serde(default)]
+    pub rx_throughput: Option<ValueSpec>,
+    #[serde(default)]
+    pub replay_count: Option<ValueSpec>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SimulatedNvLink {
+    #[serde(default = "default_true")]
+    pub active: bool,
+    /// Utilization counters, not supported unless both are set
+    #[serde(default)]
+    pub rx: Option<ValueSpec>,
+    #[serde(default)]
+    pub tx: Option<ValueSpec>,
+    /// Error counts by counter name. Counters are not supported when absent.
+    #[serde(default)]
+    pub errors: Option<BTreeMap<String, u64>>,
+}
+
+/// An ECC error counter of a device. Counters which are not listed are
+/// reported as not supported.
+#[derive(Deserialize, Debug)]
+pub struct SimulatedEccErrors {
+    pub counter: String,
+    pub error_type: String,
+    pub location: String,
+    pub count: ValueSpec,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SimulatedProcess {
+    /// Index of the device the process runs on
+    pub device: u32,
+    pub user: String,
+    /// Defaults to 1000 plus the position of the process in the scenario
+    #[serde(default)]
+    pub pid: Option<u32>,
+    #[serde(default = "default_command")]
+    pub command: String,
+    /// `compute` or `graphics`
+    #[serde(default = "default_process_type")]
+    pub process_type: String,
+    pub used_memory: ValueSpec,
+    /// Resident set size on the host in bytes
+    #[serde(default = "default_zero")]
+    pub rss: ValueSpec,
+    /// SM utilization of the process in percent
+    #[serde(default = "default_zero")]
+    pub utilization: ValueSpec,
+    /// Encoder utilization of the process in percent, e.g. for transcoding
+    #[serde(default = "default_zero")]
+    pub encoder_utilization: ValueSpec,
+    /// MIG device the process runs on, required on GPUs in MIG mode
+    #[serde(default)]
+    pub gpu_instance_id: Option<u32>,
+    #[serde(default)]
+    pub compute_instance_id: u32,
+}
+
+impl SimulatedProcess {
+    fn mig(&self) -> Option<MigInstance> {
+        self.gpu_instance_id.map(|gpu_instance_id| MigInstance {
+            gpu_instance_id,
+            compute_instance_id: self.compute_instance_id,
+        })
+    }
+}
+
+/// Makes a collection fail, either periodically or randomly. The failure
+/// covers a single field when `field` is set, a single device when only
+/// `device` is set, and the whole collection otherwise.
+#[derive(Deserialize, Debug)]
+pub struct SimulatedFailure {
+    #[serde(default)]
+    pub device: Option<u32>,
+    #[serde(default)]
+    pub field: Option<String>,
+    /// Report the field as not supported instead of failing
+    #[serde(default)]
+    pub not_supported: bool,
+    /// Fail every n-th collection
+    #[serde(default)]
+    pub every: Option<u64>,
+    /// Fail with the given probability
+    #[serde(default)]
+    pub probability: Option<f64>,
+    #[serde(default = "default_failure_message")]
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Scenario {
+    #[serde(default = "default_version")]
+    pub version: String,
+    #[serde(default = "default_seed")]
+    pub seed: u64,
+    /// Seconds the simulated clock advances on each collection. The real
+    /// clock is used when absent.
+    #[serde(default)]
+    pub time_step: Option<f64>,
+    #[serde(default)]
+    pub device: Vec<SimulatedDevice>,
+    #[serde(default)]
+    pub process: Vec<SimulatedProcess>,
+    #[serde(default)]
+    pub failure: Vec<SimulatedFailure>,
+}
+
+fn default_temperature() -> ValueSpec {
+    ValueSpec::Static(40.)
+}
+
+fn default_zero() -> ValueSpec {
+    ValueSpec::Static(0.)
+}
+
+fn default_command() -> String {
+    "python train.py".to_string()
+}
+
+fn default_process_type() -> String {
+    "compute".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_failure_message() -> String {
+    "Simulated failure".to_string()
+}
+
+fn default_version() -> String {
+    "simulated".to_string()
+}
+
+fn default_seed() -> u64 {
+    1
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Scenario> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading scenario {}", path.to_string_lossy()))?;
+        Scenario::parse(&content)
+            .with_context(|| format!("Parsing scenario {}", path.to_string_lossy()))
+    }
+
+    fn parse(content: &str) -> Result<Scenario> {
+        let scenario: Scenario = toml::from_str(content)?;
+        if scenario
+            .time_step
+            .is_some_and(|x| !(x.is_finite() && x >= 0.))
+        {
+            anyhow::bail!("Scenario error: time_step should be finite and not negative");
+        }
+        for process in scenario.process.iter() {
+            if process.device as usize >= scenario.device.len() {
+                anyhow::bail!(
+                    "Scenario error: process of {} runs on missing device {}",
+                    process.user,
+                    process.device
+                );
+            }
+            if !["compute", "graphics"].contains(&process.process_type.as_str()) {
+                anyhow::bail!(
+                    "Scenario error: unknown process type {}",
+                    process.process_type
+                );
+            }
+            let device = &scenario.device[process.device as usize];
+            let on_mig_device = match process.mig() {
+                Some(mig) => device.mig.iter().any(|x| x.instance() == mig),
+                None => device.mig.is_empty(),
+            };
+            if !on_mig_device {
+                anyhow::bail!(
+                    "Scenario error: process of {} should run on a MIG device of device {} \
+                     if and only if it is in MIG mode",
+                    process.user,
+                    process.device
+                );
+            }
+        }
+        for device in scenario.device.iter() {
+            for domain in device.clocks.keys().chain(device.max_clocks.keys()) {
+                if !CLOCK_DOMAINS.iter().any(|x| x.0 == domain) {
+                    anyhow::bail!("Scenario error: unknown clock domain {}", domain);
+                }
+            }
+            for ecc in device.ecc_errors.iter() {
+                if !ECC_COUNTERS.contains(&ecc.counter.as_str())
+                    || !MEMORY_ERROR_TYPES.contains(&ecc.error_type.as_str())
+                    || !MEMORY_LOCATIONS.contains(&ecc.location.as_str())
+                {
+                    anyhow::bail!(
+                        "Scenario error: unknown ECC counter {}/{}/{}",
+                        ecc.counter,
+                        ecc.error_type,
+                        ecc.location
+                    );
+                }
+            }
+            if device.nvlink.len() > NVLINK_MAX_LINKS as usize {
+                anyhow::bail!(
+                    "Scenario error: a device has at most {} NVLink links",
+                    NVLINK_MAX_LINKS
+                );
+            }
+            for errors in device.nvlink.iter().filter_map(|x| x.errors.as_ref()) {
+                for counter in errors.keys() {
+                    if !NVLINK_ERROR_COUNTERS.contains(&counter.as_str()) {
+                        anyhow::bail!("Scenario error: unknown NVLink error counter {}", counter);
+                    }
+                }
+            }
+            for reason in device.throttle_reasons.iter().flatten() {
+                if !THROTTLE_REASONS.iter().any(|x| x.0 == reason) {
+                    anyhow::bail!("Scenario error: unknown throttle reason {}", reason);
+                }
+            }
+        }
+        for failure in scenario.failure.iter() {
+            if let Some(field) = &failure.field {
+                if !FIELDS.contains(&field.as_str())
+                    && !is_ecc_field(field)
+                    && !is_nvlink_field(field)
+                {
+                    anyhow::bail!(
+                        "Scenario error: unknown field {}, expected one of {:?}",
+                        field,
+                        FIELDS
+                    );
+                }
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+fn is_ecc_field(field: &str) -> bool {
+    ECC_COUNTERS.iter().any(|counter| {
+        MEMORY_ERROR_TYPES.iter().any(|error_type| {
+            MEMORY_LOCATIONS
+                .iter()
+                .any(|location| EccErrors::field(counter, error_type, location) == field)
+        })
+    })
+}
+
+fn is_nvlink_field(field: &str) -> bool {
+    (0..NVLINK_MAX_LINKS).any(|link| {
+        NVLINK_FIELDS
+            .iter()
+            .any(|property| NvLinkState::field(link, property) == field)
+    })
+}
+
+/// xorshift64*, good enough for generating noise
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    /// Uniform value in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Random state of the simulation, kept apart from the scenario so that both
+/// can be borrowed at once
+struct SimulationState {
+    rng: Rng,
+    /// Current values of the random walks, keyed by their position in the scenario
+    walks: BTreeMap<(usize, String), f64>,
+    /// Elapsed seconds at the last reading and millijoules consumed, by device
+    energy: BTreeMap<usize, (f64, f64)>,
+}
+
+/// Usage of the processes of a user on a device
+#[derive(Default, Clone)]
+struct SimulatedUsage {
+    used_gpu_memory: u64,
+    sm_utilization: u32,
+    utilization: u32,
+}
+
+/// Usage by user, MIG device and process type, as the types are listed
+/// separately
+type UserUsage = BTreeMap<(String, Option<MigInstance>, &'static str), SimulatedUsage>;
+
+impl SimulationState {
+    fn sample(&mut self, spec: &ValueSpec, key: (usize, &str), elapsed: f64) -> f64 {
+        let value = match spec {
+            ValueSpec::Static(value) => *value,
+            ValueSpec::Ramp { ramp } => {
+                let phase = (elapsed % ramp.period) / ramp.period;
+                ramp.from + (ramp.to - ramp.from) * phase
+            }
+            ValueSpec::RandomWalk { random_walk } => {
+                let noise = (self.rng.next_f64() * 2. - 1.) * random_walk.step;
+                let value = self
+                    .walks
+                    .entry((key.0, key.1.to_string()))
+                    .or_insert(random_walk.start);
+                *value = (*value + noise).clamp(random_walk.min, random_walk.max);
+                *value
+            }
+        };
+        value.max(0.)
+    }
+}
+
+pub struct SimulatedGpuSource {
+    scenario: Scenario,
+    state: SimulationState,
+    fields: FieldReader,
+    started: Instant,
+    /// Unix time of the start, reported as the start time of the processes
+    started_at: f64,
+    collections: u64,
+}
+
+impl SimulatedGpuSource {
+    pub fn new(scenario: Scenario) -> SimulatedGpuSource {
+        SimulatedGpuSource {
+            state: SimulationState {
+                rng: Rng::new(scenario.seed),
+                walks: BTreeMap::new(),
+                energy: BTreeMap::new(),
+            },
+            fields: FieldReader::default(),
+            scenario,
+            started: Instant::now(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            collections: 0,
+        }
+    }
+
+    fn elapsed(&self) -> f64 {
+        match self.scenario.time_step {
+            Some(step) => step * self.collections as f64,
+            None => self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Returns the failures triggered in the given collection
+fn triggered_failures<'a>(
+    failures: &'a [SimulatedFailure],
+    rng: &mut Rng,
+    collections: u64,
+) -> Vec<&'a SimulatedFailure> {
+    let mut triggered = Vec::new();
+    for failure in failures.iter() {
+        let periodic = failure
+            .every
+            .map(|every| collections.checked_rem(every) == Some(0))
+            .unwrap_or(false);
+        let random = failure
+            .probability
+            .map(|probability| rng.next_f64() < probability)
+            .unwrap_or(false);
+        if periodic || random {
+            triggered.push(failure);
+        }
+    }
+    triggered
+}
+
+fn field_failure(
+    triggered: &[&SimulatedFailure],
+    index: u32,
+    field: &str,
+) -> Result<(), FieldFailure> {
+    let failure = triggered.iter().find(|x| {
+        x.field.as_deref() == Some(field) && x.device.map(|x| x == index).unwrap_or(true)
+    });
+    match failure {
+        Some(failure) if failure.not_supported => Err(FieldFailure::NotSupported),
+        Some(failure) => Err(FieldFailure::Failed(failure.message.clone())),
+        None => Ok(()),
+    }
+}
+
+impl GpuSource for SimulatedGpuSource {
+    fn now(&mut self) -> Result<NvmlMetrics> {
+        self.collections += 1;
+        let elapsed = self.elapsed();
+        let triggered = triggered_failures(
+            &self.scenario.failure,
+            &mut self.state.rng,
+            self.collections,
+        );
+        if let Some(failure) = triggered
+            .iter()
+            .find(|x| x.device.is_none() && x.field.is_none())
+        {
+            anyhow::bail!("{}", failure.message);
+        }
+
+        let state = &mut self.state;
+        let fields = &mut self.fields;
+
+        let mut users: Vec<UserUsage> = vec![BTreeMap::new(); self.scenario.device.len()];
+        let mut device_processes: Vec<Vec<(Option<MigInstance>, NvmlProcess)>> =
+            vec![Vec::new(); self.scenario.device.len()];
+        for (i, process) in self.scenario.process.iter().enumerate() {
+            let used = state.sample(&process.used_memory, (i, "process.used_memory"), elapsed);
+            let rss = state.sample(&process.rss, (i, "process.rss"), elapsed);
+            let utilization =
+                state.sample(&process.utilization, (i, "process.utilization"), elapsed);
+            let encoder_utilization = state.sample(
+                &process.encoder_utilization,
+                (i, "process.encoder_utilization"),
+                elapsed,
+            );
+            let busiest = utilization.max(encoder_utilization).min(100.) as u32;
+            let device = process.device as usize;
+            let process_type = if process.process_type == "graphics" {
+                "graphics"
+            } else {
+                "compute"
+            };
+            let nvml_process = NvmlProcess {
+                pid: process.pid.unwrap_or(1000 + i as u32),
+                minor_number: self.scenario.device[device]
+                    .minor_number
+                    .unwrap_or(process.device),
+                process_type,
+                user_name: Some(process.user.clone()),
+                command: process.command.clone(),
+                start_time: Some(self.started_at),
+                used_gpu_memory: Some(used as u64),
+                utilization: Some(busiest),
+                rss: Some(rss as u64),
+            };
+            device_processes[device].push((process.mig(), nvml_process));
+            let usage = users[process.device as usize]
+                .entry((process.user.clone(), process.mig(), process_type))
+                .or_default();
+            usage.used_gpu_memory += used as u64;
+            usage.sm_utilization += utilization.min(100.) as u32;
+            usage.utilization += busiest;
+        }
+
+        let mut devices = Vec::with_capacity(self.scenario.device.len());
+        let mut users_utilization = Vec::new();
+        let mut listed_processes = Vec::new();
+        for (i, spec) in self.scenario.device.iter().enumerate() {
+            let index = i as u32;
+            let minor_number = spec.minor_number.unwrap_or(index);
+            if let Some(failure) = triggered
+                .iter()
+                .find(|x| x.device == Some(index) && x.field.is_none())
+            {
+                fields.device_failed(index, Some(minor_number), &failure.message, false);
+                continue;
+            }
+            let uuid = spec
+                .uuid
+                .clone()
+                .unwrap_or_else(|| format!("GPU-00000000-0000-0000-0000-{:012}", index));
+
+            let pci_bus_id = fields.read(&uuid, minor_number, "pci_info", || {
+                field_failure(&triggered, index, "pci_info")?;
+                Ok(spec
+                    .pci_bus_id
+                    .clone()
+                    .unwrap_or_else(|| format!("00000000:{:02X}:00.0", index + 1)))
+            });
+            let temperature = fields.read(&uuid, minor_number, "temperature", || {
+                field_failure(&triggered, index, "temperature")?;
+                Ok(state.sample(&spec.temperature, (i, "device.temperature"), elapsed) as u32)
+            });
+            let power_usage = fields.read(&uuid, minor_number, "power_usage", || {
+                field_failure(&triggered, index, "power_usage")?;
+                Ok(state.sample(&spec.power_usage, (i, "device.power_usage"), elapsed) as u32)
+            });
+            let energy_consumption = fields.read(&uuid, minor_number, "energy_consumption", || {
+                field_failure(&triggered, index, "energy_consumption")?;
+                if !spec.energy_counter {
+                    return Err(FieldFailure::NotSupported);
+                }
+                let power = power_usage.unwrap_or(0) as f64;
+                let (last, total) = state.energy.entry(i).or_insert((elapsed, 0.));
+                *total += power * (elapsed - *last);
+                *last = elapsed;
+                Ok(*total as u64)
+            });
+            let fan_speed = fields.read(&uuid, minor_number, "fan_speed", || {
+                field_failure(&triggered, index, "fan_speed")?;
+                Ok(state.sample(&spec.fan_speed, (i, "device.fan_speed"), elapsed) as u32)
+            });
+            let memory_used = fields.read(&uuid, minor_number, "memory_info", || {
+                field_failure(&triggered, index, "memory_info")?;
+                let used = match &spec.memory_used {
+                    Some(memory_used) => {
+                        state.sample(memory_used, (i, "device.memory_used"), elapsed) as u64
+                    }
+                    None => users[i].values().map(|x| x.used_gpu_memory).sum(),
+                };
+                Ok(used.min(spec.memory_total))
+            });
+            let utilization = fields.read(&uuid, minor_number, "utilization_rates", || {
+                field_failure(&triggered, index, "utilization_rates")?;
+                let gpu = state.sample(
+                    &spec.utilization_gpu,
+                    (i, "device.utilization_gpu"),
+                    elapsed,
+                );
+                let memory = state.sample(
+                    &spec.utilization_memory,
+                    (i, "device.utilization_memory"),
+                    elapsed,
+                );
+                Ok((gpu.min(100.) as u32, memory.min(100.) as u32))
+            });
+            let mut clocks = Vec::with_capacity(CLOCK_DOMAINS.len());
+            for (domain, field, max_field) in CLOCK_DOMAINS {
+                let current = fields.read(&uuid, minor_number, field, || {
+                    field_failure(&triggered, index, field)?;
+                    let spec = spec.clocks.get(domain).ok_or(FieldFailure::NotSupported)?;
+                    Ok(state.sample(spec, (i, field), elapsed) as u32)
+                });
+                let max = fields.read(&uuid, minor_number, max_field, || {
+                    field_failure(&triggered, index, max_field)?;
+                    spec.max_clocks
+                        .get(domain)
+                        .copied()
+                        .ok_or(FieldFailure::NotSupported)
+                });
+                clocks.push(ClockSpeed {
+                    domain,
+                    current,
+                    max,
+                });
+            }
+            let throttle_reasons = fields.read(&uuid, minor_number, "throttle_reasons", || {
+                field_failure(&triggered, index, "throttle_reasons")?;
+                let reasons = spec
+                    .throttle_reasons
+                    .as_ref()
+                    .ok_or(FieldFailure::NotSupported)?;
+                Ok(THROTTLE_REASONS
+                    .iter()
+                    .filter(|(name, _)| reasons.iter().any(|x| x == name))
+                    .fold(0, |mask, (_, bit)| mask | bit))
+            });
+            let power_limit_enforced =
+                fields.read(&uuid, minor_number, "enforced_power_limit", || {
+                    field_failure(&triggered, index, "enforced_power_limit")?;
+                    spec.power_limit.ok_or(FieldFailure::NotSupported)
+                });
+            let power_limit_default =
+                fields.read(&uuid, minor_number, "default_power_limit", || {
+                    field_failure(&triggered, index, "default_power_limit")?;
+                    spec.power_limit_default.ok_or(FieldFailure::NotSupported)
+                });
+            let performance_state = fields.read(&uuid, minor_number, "performance_state", || {
+                field_failure(&triggered, index, "performance_state")?;
+                spec.performance_state.ok_or(FieldFailure::NotSupported)
+            });
+            let mut ecc_errors = Vec::new();
+            for counter in ECC_COUNTERS {
+                for error_type in MEMORY_ERROR_TYPES {
+                    for location in MEMORY_LOCATIONS {
+                        let field = EccErrors::field(counter, error_type, location);
+                        let count = fields.read(&uuid, minor_number, &field, || {
+                            field_failure(&triggered, index, &field)?;
+                            let ecc = spec
+                                .ecc_errors
+                                .iter()
+                                .find(|x| {
+                                    x.counter == counter
+                                        && x.error_type == error_type
+                                        && x.location == location
+                                })
+                                .ok_or(FieldFailure::NotSupported)?;
+                            Ok(state.sample(&ecc.count, (i, &field), elapsed) as u64)
+                        });
+                        if let Some(count) = count {
+                            ecc_errors.push(EccErrors {
+                                counter,
+                                error_type,
+                                location,
+                                count,
+                            });
+                        }
+                    }
+                }
+            }
+            let retired_pages = fields.read(&uuid, minor_number, "retired_pages", || {
+                field_failure(&triggered, index, "retired_pages")?;
+                spec.retired_pages.ok_or(FieldFailure::NotSupported)
+            });
+            let remapped_rows = fields.read(&uuid, minor_number, "remapped_rows", || {
+                field_failure(&triggered, index, "remapped_rows")?;
+                spec.remapped_rows.ok_or(FieldFailure::NotSupported)
+            });
+            let pcie_spec = &spec.pcie;
+            let mut read_pcie = |field: &str, value: Option<f64>| {
+                fields.read(&uuid, minor_number, field, || {
+                    field_failure(&triggered, index, field)?;
+                    value.ok_or(FieldFailure::NotSupported)
+                })
+            };
+            let pcie = PcieLink {
+                generation: read_pcie("pcie_link_gen", pcie_spec.generation.map(f64::from))
+                    .map(|x| x as u32),
+                width: read_pcie("pcie_link_width", pcie_spec.width.map(f64::from))
+                    .map(|x| x as u32),
+                max_generation: read_pcie(
+                    "max_pcie_link_gen",
+                    pcie_spec.max_generation.map(f64::from),
+                )
+                .map(|x| x as u32),
+                max_width: read_pcie("max_pcie_link_width", pcie_spec.max_width.map(f64::from))
+                    .map(|x| x as u32),
+                tx_throughput: read_pcie(
+                    "pcie_tx_throughput",
+                    pcie_spec
+                        .tx_throughput
+                        .as_ref()
+                        .map(|x| state.sample(x, (i, "pcie.tx_throughput"), elapsed)),
+                )
+                .map(|x| x as u32),
+                rx_throughput: read_pcie(
+                    "pcie_rx_throughput",
+                    pcie_spec
+                        .rx_throughput
+                        .as_ref()
+                        .map(|x| state.sample(x, (i, "pcie.rx_throughput"), elapsed)),
+                )
+                .map(|x| x as u32),
+                replay_count: read_pcie(
+                    "pcie_replay_counter",
+                    pcie_spec
+                        .replay_count
+                        .as_ref()
+                        .map(|x| state.sample(x, (i, "pcie.replay_count"), elapsed)),
+                )
+                .map(|x| x as u64),
+            };
+
+            let mut nvlinks = Vec::with_capacity(spec.nvlink.len());
+            for (link, nvlink) in spec.nvlink.iter().enumerate() {
+                let link = link as u32;
+                let field = NvLinkState::field(link, "state");
+                let Some(active) = fields.read(&uuid, minor_number, &field, || {
+                    field_failure(&triggered, index, &field)?;
+                    Ok(nvlink.active)
+                }) else {
+                    continue;
+                };
+                let mut link_state = NvLinkState {
+                    link,
+                    active,
+                    utilization: None,
+                    errors: Vec::new(),
+                };
+                if active {
+                    let field = NvLinkState::field(link, "utilization");
+                    link_state.utilization = fields.read(&uuid, minor_number, &field, || {
+                        field_failure(&triggered, index, &field)?;
+                        let (Some(rx), Some(tx)) = (&nvlink.rx, &nvlink.tx) else {
+                            return Err(FieldFailure::NotSupported);
+                        };
+                        let rx = state.sample(rx, (i, &format!("{}.rx", field)), elapsed);
+                        let tx = state.sample(tx, (i, &format!("{}.tx", field)), elapsed);
+                        Ok((rx as u64, tx as u64))
+                    });
+                    let field = NvLinkState::field(link, "errors");
+                    link_state.errors = fields
+                        .read(&uuid, minor_number, &field, || {
+                            field_failure(&triggered, index, &field)?;
+                            let errors =
+                                nvlink.errors.as_ref().ok_or(FieldFailure::NotSupported)?;
+                            Ok(NVLINK_ERROR_COUNTERS
+                                .iter()
+                                .map(|name| (*name, errors.get(*name).copied().unwrap_or(0)))
+                                .collect())
+                        })
+                        .unwrap_or_default();
+                }
+                nvlinks.push(link_state);
+            }
+
+            let mig_specs = fields
+                .read(&uuid, minor_number, "mig_devices", || {
+                    field_failure(&triggered, index, "mig_devices")?;
+                    if spec.mig.is_empty() {
+                        return Err(FieldFailure::NotSupported);
+                    }
+                    Ok(spec.mig.as_slice())
+                })
+                .unwrap_or_default();
+            let mut mig_devices = Vec::with_capacity(mig_specs.len());
+            let mut mig_processes_failed = false;
+            // Process types listed on the GPU, or on each MIG device
+            let mut listed_types: Vec<(Option<MigInstance>, &str)> = Vec::new();
+            for (j, mig) in mig_specs.iter().enumerate() {
+                let instance = mig.instance();
+                let mig_uuid = mig.uuid.clone().unwrap_or_else(|| {
+                    format!(
+                        "MIG-00000000-0000-0000-{:04}-{:06}{:06}",
+                        index, instance.gpu_instance_id, instance.compute_instance_id
+                    )
+                });
+                let key = format!("mig.{}", j);
+                let memory_used = fields.read(&mig_uuid, minor_number, "mig_memory_info", || {
+                    field_failure(&triggered, index, "mig_memory_info")?;
+                    let used = match &mig.memory_used {
+                        Some(memory_used) => {
+                            state.sample(memory_used, (i, &format!("{}.memory_used", key)), elapsed)
+                                as u64
+                        }
+                        None => users[i]
+                            .iter()
+                            .filter(|((_, mig, _), _)| *mig == Some(instance))
+                            .map(|(_, usage)| usage.used_gpu_memory)
+                            .sum(),
+                    };
+                    Ok(used.min(mig.memory_total))
+                });
+                let utilization =
+                    fields.read(&mig_uuid, minor_number, "mig_utilization_rates", || {
+                        field_failure(&triggered, index, "mig_utilization_rates")?;
+                        let gpu = mig
+                            .utilization_gpu
+                            .as_ref()
+                            .ok_or(FieldFailure::NotSupported)?;
+                        let gpu =
+                            state.sample(gpu, (i, &format!("{}.utilization_gpu", key)), elapsed);
+                        let memory = state.sample(
+                            &mig.utilization_memory,
+                            (i, &format!("{}.utilization_memory", key)),
+                            elapsed,
+                        );
+                        Ok((gpu.min(100.) as u32, memory.min(100.) as u32))
+                    });
+                let listed =
+                    fields.read_processes(&mig_uuid, minor_number, "mig_", |process_type| {
+                        field_failure(
+                            &triggered,
+                            index,
+                            &format!("mig_{}_processes", process_type),
+                        )?;
+                        Ok(vec![()])
+                    });
+                match listed {
+                    Some(listed) => {
+                        listed_types.extend(listed.into_iter().map(|(_, x)| (Some(instance), x)))
+                    }
+                    None => mig_processes_failed = true,
+                }
+                mig_devices.push(MigDevice {
+                    instance,
+                    profile: mig.profile.clone(),
+                    uuid: mig_uuid,
+                    memory_total: memory_used.map(|_| mig.memory_total),
+                    memory_used,
+                    utilization_gpu: utilization.map(|x| x.0),
+                    utilization_memory: utilization.map(|x| x.1),
+                });
+            }
+
+            // Like NVML, processes of a GPU in MIG mode are listed for each
+            // MIG device, without utilization
+            let mut process_utilization = None;
+            let processes = if mig_specs.is_empty() {
+                let processes = fields.read_processes(&uuid, minor_number, "", |process_type| {
+                    field_failure(&triggered, index, &format!("{}_processes", process_type))?;
+                    Ok(vec![()])
+                });
+                if let Some(processes) = &processes {
+                    listed_types.extend(processes.iter().map(|(_, x)| (None, *x)));
+                }
+                process_utilization = processes.as_ref().and_then(|_| {
+                    fields.read(&uuid, minor_number, "process_utilization", || {
+                        field_failure(&triggered, index, "process_utilization")
+                    })
+                });
+                processes.is_some()
+            } else {
+                !mig_processes_failed
+            };
+
+            let mut process_sm_utilization = None;
+            let mut process_used_memory = None;
+            if processes {
+                let mut listed_users: BTreeMap<(String, Option<MigInstance>), SimulatedUsage> =
+                    BTreeMap::new();
+                for ((user_name, mig, process_type), usage) in std::mem::take(&mut users[i]) {
+                    if listed_types.contains(&(mig, process_type)) {
+                        let listed = listed_users.entry((user_name, mig)).or_default();
+                        listed.used_gpu_memory += usage.used_gpu_memory;
+                        listed.sm_utilization += usage.sm_utilization;
+                        listed.utilization += usage.utilization;
+                    }
+                }
+                for ((user_name, mig), usage) in listed_users {
+                    let sm_utilization = process_utilization.map(|_| usage.sm_utilization);
+                    if let Some(sm_utilization) = sm_utilization {
+                        *process_sm_utilization.get_or_insert(0) += sm_utilization;
+                    }
+                    *process_used_memory.get_or_insert(0) += usage.used_gpu_memory;
+                    users_utilization.push(NvmlUserUtilization {
+                        index,
+                        user_name,
+                        used_gpu_memory: usage.used_gpu_memory,
+                        sm_utilization,
+                        utilization: process_utilization.map(|_| usage.utilization),
+                        mig,
+                    });
+                }
+                if process_utilization.is_some() {
+                    process_sm_utilization.get_or_insert(0);
+                }
+                process_used_memory.get_or_insert(0);
+                for (mig, mut process) in std::mem::take(&mut device_processes[i]) {
+                    if !listed_types.contains(&(mig, process.process_type)) {
+                        continue;
+                    }
+                    if process_utilization.is_none() {
+                        process.utilization = None;
+                    }
+                    listed_processes.push(process);
+                }
+            }
+            devices.push(NvmlDevice {
+                index,
+                minor_number,
+                name: spec.name.clone(),
+                uuid,
+                pci_bus_id,
+                temperature,
+                power_usage,
+                energy_consumption,
+                fan_speed,
+                memory_total: memory_used.map(|_| spec.memory_total),
+                memory_used,
+                utilization_memory: utilization.map(|x| x.1),
+                utilization_gpu: utilization.map(|x| x.0),
+                clocks,
+                throttle_reasons,
+                power_limit_enforced,
+                power_limit_default,
+                performance_state,
+                process_sm_utilization,
+                process_used_memory,
+                ecc_errors,
+                retired_pages,
+                remapped_rows,
+                pcie,
+                nvlinks,
+                mig_devices,
+            });
+        }
+
+        Ok(NvmlMetrics {
+            version: self.scenario.version.clone(),
+            devices,
+            users_utilization,
+            processes: listed_processes,
+            field_errors: fields.take_errors(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::EnergyMeter;
+    use crate::idle::{IdleConfig, IdleDetector};
+    use crate::metrics::{
+        AliveStatus, Metrics, QuotaStatus, ScrapeStatus, TextfileStatus, XidStatus,
+    };
+    use actix_web::web;
+    use prometheus_client::encoding::text::encode;
+    use std::time::Duration;
+
+    fn expose(metrics: &web::Data<Metrics>) -> String {
+        let registry = crate::build_registry(
+            metrics,
+            &web::Data::new(AliveStatus::default()),
+            &web::Data::new(ScrapeStatus::default()),
+            &web::Data::new(TextfileStatus::default()),
+            &web::Data::new(XidStatus::default()),
+            &web::Data::new(QuotaStatus::default()),
+            None,
+        );
+        let mut body = String::new();
+        encode(&mut body, &registry).unwrap();
+        body
+    }
+
+    fn series(body: &str, name: &str) -> Vec<String> {
+        body.lines()
+            .filter(|x| {
+                x.starts_with(&format!("{}{{", name)) || x.starts_with(&format!("{} ", name))
+            })
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn example_scenario_is_exposed() {
+        let scenario = Scenario::load(Path::new("scenarios/example.toml")).unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let metrics = web::Data::new(Metrics::new());
+        metrics.update(&source.now().unwrap());
+        let body = expose(&metrics);
+
+        assert!(body.contains("node_nvidia_driver_status 1\n"));
+        assert!(body.contains("node_nvidia_driver_version{version=\"535.104.05\"} 1\n"));
+        assert_eq!(series(&body, "node_nvidia_device_info").len(), 3);
+        assert!(body.contains("node_nvidia_health_ok{minor_number=\"1\"} 0\n"));
+        assert!(body.contains(
+            "node_nvidia_user_used_memory_bytes{index=\"1\",user_name=\"bob\"} 21474836480\n"
+        ));
+        assert!(body.contains(
+            "node_nvidia_user_used_memory_bytes{index=\"2\",user_name=\"alice\"} 12884901888\n"
+        ));
+        assert!(body.contains(
+            "node_nvidia_mig_user_used_memory_bytes{index=\"2\",gi_id=\"6\",ci_id=\"0\",\
+             user_name=\"bob\"} 6442450944\n"
+        ));
+        assert!(body.contains("node_nvidia_user_cards{user_name=\"alice\"} 2\n"));
+        assert!(body.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn failed_collection_drops_the_series() {
+        let scenario = Scenario::parse(
+            r#"
+            [[device]]
+            name = "GPU"
+            memory_total = 1000
+            [[process]]
+            device = 0
+            user = "alice"
+            used_memory = 100
+            [[failure]]
+            every = 2
+            "#,
+        )
+        .unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let metrics = web::Data::new(Metrics::new());
+
+        metrics.update(&source.now().unwrap());
+        let body = expose(&metrics);
+        assert!(body.contains("node_nvidia_total_memory_bytes{minor_number=\"0\"} 1000\n"));
+        assert!(body
+            .contains("node_nvidia_user_used_memory_bytes{index=\"0\",user_name=\"alice\"} 100\n"));
+
+        assert!(source.now().is_err());
+        metrics.set_failed();
+        let body = expose(&metrics);
+        assert!(body.contains("node_nvidia_driver_status 0\n"));
+        assert!(series(&body, "node_nvidia_total_memory_bytes").is_empty());
+        assert!(series(&body, "node_nvidia_user_used_memory_bytes").is_empty());
+
+        metrics.update(&source.now().unwrap());
+        let body = expose(&metrics);
+        assert!(body.contains("node_nvidia_driver_status 1\n"));
+        assert_eq!(series(&body, "node_nvidia_user_used_memory_bytes").len(), 1);
+    }
+
+    #[test]
+    fn user_utilization_is_capped() {
+        let scenario = Scenario::parse(
+            r#"
+            [[device]]
+            name = "GPU"
+            memory_total = 1000
+            [[process]]
+            device = 0
+            user = "alice"
+            used_memory = 100
+            utilization = 80
+            [[process]]
+            device = 0
+            user = "alice"
+            used_memory = 100
+            encoder_utilization = 60
+            "#,
+        )
+        .unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let metrics = web::Data::new(Metrics::new());
+        metrics.update(&source.now().unwrap());
+        let body = expose(&metrics);
+        assert!(body
+            .contains("node_nvidia_user_utilization_ratio{index=\"0\",user_name=\"alice\"} 1.0\n"));
+    }
+
+    #[test]
+    fn process_listed_twice_stays_idle() {
+        let scenario = Scenario::parse(
+            r#"
+            [[device]]
+            name = "GPU"
+            memory_total = 10000
+            [[process]]
+            device = 0
+            pid = 4242
+            user = "alice"
+            used_memory = 2000
+            [[process]]
+            device = 0
+            pid = 4242
+            user = "alice"
+            process_type = "graphics"
+            used_memory = 2000
+            "#,
+        )
+        .unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let mut detector = IdleDetector::new(&IdleConfig {
+            min_memory: 1000,
+            max_utilization: 0.05,
+            min_duration: 10.,
+        });
+        let start = Instant::now();
+        let mut holds = Vec::new();
+        for seconds in [0, 5, 10] {
+            let state = source.now().unwrap();
+            assert_eq!(state.processes.len(), 2);
+            holds = detector.observe(start + Duration::from_secs(seconds), &state);
+        }
+        assert_eq!(holds.len(), 1);
+        assert_eq!(holds[0].pid, 4242);
+        assert_eq!(holds[0].duration, 10.);
+    }
+
+    #[test]
+    fn energy_is_kept_across_failures() {
+        let scenario = Scenario::parse(
+            r#"
+            time_step = 1.0
+            [[device]]
+            name = "GPU"
+            memory_total = 1000
+            power_usage = 100000
+            energy_counter = true
+            [[process]]
+            device = 0
+            user = "alice"
+            used_memory = 100
+            [[failure]]
+            every = 3
+            "#,
+        )
+        .unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let mut meter = EnergyMeter::default();
+        let metrics = web::Data::new(Metrics::new());
+        for _ in 0..5 {
+            match source.now() {
+                Ok(state) => {
+                    metrics.update(&state);
+                    metrics.add_energy(&meter.observe(Instant::now(), &state));
+                }
+                Err(_) => {
+                    metrics.set_failed();
+                    meter.clear();
+                }
+            }
+        }
+        let body = expose(&metrics);
+        assert!(body.contains("node_nvidia_energy_joules_total{minor_number=\"0\"} 200.0\n"));
+        assert!(body.contains(
+            "node_nvidia_user_energy_joules_total{index=\"0\",user_name=\"alice\"} 200.0\n"
+        ));
+    }
+
+    #[test]
+    fn unsupported_field_is_left_out() {
+        let scenario = Scenario::parse(
+            r#"
+            [[device]]
+            name = "GPU"
+            memory_total = 1000
+            power_usage = 100000
+            [[device]]
+            name = "GPU"
+            memory_total = 1000
+            power_usage = 200000
+            [[failure]]
+            device = 1
+            field = "power_usage"
+            not_supported = true
+            every = 1
+            "#,
+        )
+        .unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let metrics = web::Data::new(Metrics::new());
+        metrics.update(&source.now().unwrap());
+        let body = expose(&metrics);
+        assert_eq!(
+            series(&body, "node_nvidia_power_usage"),
+            ["node_nvidia_power_usage{minor_number=\"0\"} 100000"]
+        );
+        assert!(series(&body, "syswatch_nvml_field_errors_total").is_empty());
+    }
+
+    #[test]
+    fn compute_processes_are_listed_without_graphics_processes() {
+        let scenario = Scenario::parse(
+            r#"
+            [[device]]
+            name = "GPU"
+            memory_total = 1000
+            [[process]]
+            device = 0
+            user = "alice"
+            used_memory = 100
+            [[process]]
+            device = 0
+            user = "bob"
+            process_type = "graphics"
+            used_memory = 200
+            [[failure]]
+            field = "graphics_processes"
+            not_supported = true
+            every = 1
+            "#,
+        )
+        .unwrap();
+        let mut source = SimulatedGpuSource::new(scenario);
+        let metrics = web::Data::new(Metrics::new());
+        for _ in 0..2 {
+            metrics.update(&source.now().unwrap());
+            let body = expose(&metrics);
+            assert_eq!(
+                series(&body, "node_nvidia_user_used_memory_bytes"),
+                ["node_nvidia_user_used_memory_bytes{index=\"0\",user_name=\"alice\"} 100"]
+            );
+        }
+    }
+
+    #[test]
+    fn scenario_errors_are_reported() {
+        let error = Scenario::parse(
+            r#"
+            [[process]]
+            device = 0
+            user = "alice"
+            used_memory = 100
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("missing device 0"));
+    }
+
+    fn value_error(value: &str) -> String {
+        let content = format!(
+            "[[device]]\nname = \"GPU\"\nmemory_total = 1000\ntemperature = {}\n",
+            value
+        );
+        Scenario::parse(&content).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn random_walk_bounds_are_checked() {
+        let error = value_error("{ random_walk = { start = 50, min = 80, max = 20, step = 1 } }");
+        assert!(error.contains("random_walk.min should not be larger than random_walk.max"));
+    }
+
+    #[test]
+    fn random_walk_step_is_checked() {
+        let error = value_error("{ random_walk = { start = 50, min = 20, max = 80, step = -1 } }");
+        assert!(error.contains("random_walk.step should not be negative"));
+    }
+
+    #[test]
+    fn ramp_period_is_checked() {
+        let error = value_error("{ ramp = { from = 20, to = 80, period = 0 } }");
+        assert!(error.contains("ramp.period should be larger than 0"));
+    }
+
+    #[test]
+    fn values_are_finite() {
+        assert!(value_error("nan").contains("values should be finite"));
+        let error = value_error("{ random_walk = { start = 50, min = 20, max = inf, step = 1 } }");
+        assert!(error.contains("values should be finite"));
+        let error = Scenario::parse("time_step = -1.0\n").unwrap_err();
+        assert!(error.to_string().contains("time_step"));
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::nvml_metrics::NvmlUserUtilization;
+use crate::utils::{self, GroupCache};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TeamSource {
+    /// The primary Unix group of the user
+    #[default]
+    Primary,
+    /// Every Unix group of the user, the primary one included
+    Supplementary,
+    /// The `members` table of the configuration
+    Static,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TeamConfig {
+    pub source: TeamSource,
+    /// Unix groups which are teams, every group when empty
+    pub groups: Vec<String>,
+    /// User names by team, with the static source
+    pub members: BTreeMap<String, Vec<String>>,
+    /// Team of the users in no team, who are left out when unset
+    pub default_team: Option<String>,
+}
+
+/// GPU memory held by the members of a team on a device
+pub struct TeamUsage {
+    pub index: u32,
+    pub team: String,
+    pub used_gpu_memory: u64,
+}
+
+/// Maps users to their teams
+pub struct TeamMapper {
+    source: TeamSource,
+    groups: HashSet<String>,
+    /// Teams by user name, with the static source
+    members: HashMap<String, Vec<String>>,
+    default_team: Option<String>,
+    /// Teams of the users, with a Unix group source
+    cache: GroupCache,
+}
+
+impl TeamMapper {
+    pub fn new(config: &TeamConfig) -> TeamMapper {
+        let mut members: HashMap<String, Vec<String>> = HashMap::new();
+        for (team, user_names) in config.members.iter() {
+            for user_name in user_names.iter() {
+                members
+                    .entry(user_name.clone())
+                    .or_default()
+                    .push(team.clone());
+            }
+        }
+        TeamMapper {
+            source: config.source,
+            groups: config.groups.iter().cloned().collect(),
+            members,
+            default_team: config.default_team.clone(),
+            cache: GroupCache::new(match config.source {
+                TeamSource::Supplementary => utils::user_groups,
+                TeamSource::Primary | TeamSource::Static => primary_group,
+            }),
+        }
+    }
+
+    /// Sums the memory of the users by device and team. A user in several
+    /// teams is counted in each.
+    pub fn usage(&mut self, users: &[NvmlUserUtilization]) -> Vec<TeamUsage> {
+        let mut usage: HashMap<(u32, String), u64> = HashMap::new();
+        for user in users.iter().filter(|x| x.used_gpu_memory != 0) {
+            for team in self.teams(&user.user_name) {
+                *usage.entry((user.index, team)).or_default() += user.used_gpu_memory;
+            }
+        }
+        usage
+            .into_iter()
+            .map(|((index, team), used_gpu_memory)| TeamUsage {
+                index,
+                team,
+                used_gpu_memory,
+            })
+            .collect()
+    }
+
+    fn teams(&mut self, user_name: &str) -> Vec<String> {
+        let mut teams = match self.source {
+            TeamSource::Static => self.members.get(user_name).cloned().unwrap_or_default(),
+            TeamSource::Primary | TeamSource::Supplementary => self
+                .cache
+                .get(user_name)
+                .iter()
+                .filter(|x| self.groups.is_empty() || self.groups.contains(*x))
+                .cloned()
+                .collect(),
+        };
+        if teams.is_empty() {
+            teams.extend(self.default_team.clone());
+        }
+        teams
+    }
+}
+
+fn primary_group(user_name: &str) -> Vec<String> {
+    users::get_user_by_name(user_name)
+        .and_then(|user| users::get_group_by_gid(user.primary_group_id()))
+        .map(|group| vec![group.name().to_string_lossy().into_owned()])
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(index: u32, user_name: &str, used_gpu_memory: u64) -> NvmlUserUtilization {
+        NvmlUserUtilization {
+            index,
+            user_name: user_name.to_string(),
+            used_gpu_memory,
+            sm_utilization: None,
+            utilization: None,
+            mig: None,
+        }
+    }
+
+    fn fake_groups(user_name: &str) -> Vec<String> {
+        match user_name {
+            "alice" => vec!["alice".to_string(), "vision".to_string()],
+            "bob" => vec!["bob".to_string(), "vision".to_string(), "nlp".to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn mapper(config: &str) -> TeamMapper {
+        let config: TeamConfig = toml::from_str(config).unwrap();
+        let mut mapper = TeamMapper::new(&config);
+        mapper.cache = GroupCache::new(fake_groups);
+        mapper
+    }
+
+    fn team_usage(
+        mapper: &mut TeamMapper,
+        users: &[NvmlUserUtilization],
+    ) -> Vec<(u32, String, u64)> {
+        let mut usage: Vec<_> = mapper
+            .usage(users)
+            .into_iter()
+            .map(|x| (x.index, x.team, x.used_gpu_memory))
+            .collect();
+        usage.sort();
+        usage
+    }
+
+    #[test]
+    fn static_members_are_teams() {
+        let mut mapper = mapper(
+            "source = \"static\"\n[members]\nvision = [\"alice\", \"carol\"]\nnlp = [\"bob\"]\n",
+        );
+        let users = [
+            usage(0, "alice", 100),
+            usage(0, "carol", 50),
+            usage(1, "bob", 200),
+            usage(1, "dave", 400),
+            usage(1, "carol", 0),
+        ];
+        assert_eq!(
+            team_usage(&mut mapper, &users),
+            [(0, "vision".to_string(), 150), (1, "nlp".to_string(), 200)]
+        );
+    }
+
+    #[test]
+    fn groups_are_filtered() {
+        let mut mapper = mapper("source = \"supplementary\"\ngroups = [\"vision\", \"nlp\"]\n");
+        let users = [usage(0, "alice", 100), usage(0, "dave", 400)];
+        assert_eq!(
+            team_usage(&mut mapper, &users),
+            [(0, "vision".to_string(), 100)]
+        );
+    }
+
+    #[test]
+    fn users_in_no_team_get_the_default_team() {
+        let mut mapper =
+            mapper("source = \"supplementary\"\ngroups = [\"nlp\"]\ndefault_team = \"others\"\n");
+        let users = [
+            usage(0, "alice", 100),
+            usage(0, "bob", 200),
+            usage(0, "dave", 400),
+        ];
+        assert_eq!(
+            team_usage(&mut mapper, &users),
+            [(0, "nlp".to_string(), 200), (0, "others".to_string(), 500)]
+        );
+    }
+
+    #[test]
+    fn users_are_counted_in_each_of_their_teams() {
+        let mut mapper = mapper("source = \"supplementary\"\n");
+        let users = [usage(0, "alice", 100), usage(0, "bob", 200)];
+        assert_eq!(
+            team_usage(&mut mapper, &users),
+            [
+                (0, "alice".to_string(), 100),
+                (0, "bob".to_string(), 200),
+                (0, "nlp".to_string(), 200),
+                (0, "vision".to_string(), 300)
+            ]
+        );
+    }
+
+    #[test]
+    fn primary_group_is_the_team() {
+        let config: TeamConfig = toml::from_str("").unwrap();
+        let mut mapper = TeamMapper::new(&config);
+        assert_eq!(
+            team_usage(&mut mapper, &[usage(0, "root", 100)]),
+            [(0, "root".to_string(), 100)]
+        );
+    }
+}
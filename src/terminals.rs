@@ -0,0 +1,68 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use users::uid_t;
+
+pub const UTMP_PATH: &str = "/var/run/utmp";
+
+/// Terminals the user is logged in on, from the utmp records
+pub fn user_terminals(utmp: &Path, user_name: &str) -> std::io::Result<Vec<PathBuf>> {
+    let content = std::fs::read(utmp)?;
+    let mut terminals = Vec::new();
+    for record in content.chunks_exact(std::mem::size_of::<libc::utmpx>()) {
+        // SAFETY: utmpx is plain data and the record has its size
+        let entry: libc::utmpx = unsafe { std::ptr::read_unaligned(record.as_ptr().cast()) };
+        if entry.ut_type != libc::USER_PROCESS || c_string(&entry.ut_user) != user_name {
+            continue;
+        }
+        let line = c_string(&entry.ut_line);
+        if line.is_empty() || line.split('/').any(|x| x == "..") {
+            continue;
+        }
+        let terminal = Path::new("/dev").join(line);
+        if !terminals.contains(&terminal) {
+            terminals.push(terminal);
+        }
+    }
+    Ok(terminals)
+}
+
+/// Writes the message to every terminal of the user, like `write`, and
+/// returns the count of terminals it was delivered to. Terminals not owned by
+/// `uid`, e.g. left behind in utmp by a previous login, are skipped.
+pub fn write_to_user(
+    utmp: &Path,
+    user_name: &str,
+    uid: uid_t,
+    message: &str,
+) -> std::io::Result<usize> {
+    let message = format!("\r\n{}\r\n", message.replace('\n', "\r\n"));
+    let mut delivered = 0;
+    for terminal in user_terminals(utmp, user_name)? {
+        // A stuck terminal must not block the sampler
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&terminal);
+        let Ok(mut file) = file else {
+            continue;
+        };
+        if file.metadata().is_ok_and(|x| x.uid() == uid)
+            && file.write_all(message.as_bytes()).is_ok()
+        {
+            delivered += 1;
+        }
+    }
+    Ok(delivered)
+}
+
+fn c_string(chars: &[libc::c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .take_while(|x| **x != 0)
+        .map(|x| *x as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,347 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::exposition::{self, MetricFamily};
+use crate::metrics::{TextfileResult, TextfileStatus};
+
+const METRIC_TYPES: [&str; 5] = ["counter", "gauge", "histogram", "summary", "untyped"];
+/// Prefixes of the families of syswatch, including those not exported yet
+const RESERVED_PREFIXES: [&str; 2] = ["node_nvidia_", "syswatch_"];
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TextfileConfig {
+    /// Directory containing `*.prom` files
+    pub directory: PathBuf,
+}
+
+struct CachedFile {
+    mtime: SystemTime,
+    families: Result<Vec<MetricFamily>, String>,
+}
+
+/// Collects metrics written to `*.prom` files by other programs. Files are
+/// parsed again only when their modification time changes.
+pub struct TextfileCollector {
+    directory: PathBuf,
+    /// Families exported by syswatch itself, which files may not define
+    reserved: Vec<MetricFamily>,
+    cache: Mutex<HashMap<PathBuf, CachedFile>>,
+}
+
+impl TextfileCollector {
+    pub fn new(config: &TextfileConfig, reserved: Vec<MetricFamily>) -> TextfileCollector {
+        TextfileCollector {
+            directory: config.directory.clone(),
+            reserved,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Returns the merged families of all valid files. A broken file is
+    /// reported through `status` and left out of the result.
+    pub fn collect(&self, status: &TextfileStatus) -> Vec<MetricFamily> {
+        let paths = match self.list_files() {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("Textfile collector: {:?}", e);
+                status.update(true, &[]);
+                return Vec::new();
+            }
+        };
+
+        let mut results = Vec::with_capacity(paths.len());
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|path, _| paths.contains(path));
+
+        let mut merged: Vec<MetricFamily> = Vec::new();
+        let mut series = HashSet::new();
+        for path in paths.iter() {
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let mtime = match std::fs::metadata(path).and_then(|x| x.modified()) {
+                Ok(mtime) => mtime,
+                Err(e) => {
+                    eprintln!("Textfile collector: {}: {}", file_name, e);
+                    results.push(TextfileResult {
+                        file: file_name,
+                        mtime: None,
+                        error: true,
+                    });
+                    continue;
+                }
+            };
+
+            let cached = match cache.get(path) {
+                Some(cached) if cached.mtime == mtime => cached,
+                _ => {
+                    let families = read_file(path).map_err(|e| format!("{:#}", e));
+                    if let Err(e) = &families {
+                        eprintln!("Textfile collector: {}: {}", file_name, e);
+                    }
+                    cache.insert(path.clone(), CachedFile { mtime, families });
+                    &cache[path]
+                }
+            };
+            let mtime = mtime
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs_f64())
+                .unwrap_or_default();
+
+            let error = match &cached.families {
+                Ok(families) => match merge(&mut merged, &mut series, &self.reserved, families) {
+                    Ok(()) => false,
+                    Err(e) => {
+                        eprintln!("Textfile collector: {}: {}", file_name, e);
+                        true
+                    }
+                },
+                Err(_) => true,
+            };
+            results.push(TextfileResult {
+                file: file_name,
+                mtime: Some(mtime),
+                error,
+            });
+        }
+        status.update(false, &results);
+        merged
+    }
+
+    fn list_files(&self) -> Result<Vec<PathBuf>> {
+        let entries = std::fs::read_dir(&self.directory)
+            .with_context(|| format!("Reading {}", self.directory.to_string_lossy()))?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map(|x| x == "prom").unwrap_or(false) && path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<MetricFamily>> {
+    let content = std::fs::read_to_string(path)?;
+    let families = exposition::parse(&content)?;
+
+    let mut names = HashSet::new();
+    for family in families.iter() {
+        if !names.insert(family.name.as_str()) {
+            anyhow::bail!("metric family {} is split in several parts", family.name);
+        }
+        if let Some(metric_type) = &family.metric_type {
+            if !METRIC_TYPES.contains(&metric_type.as_str()) {
+                anyhow::bail!("unknown type {:?} of {}", metric_type, family.name);
+            }
+        }
+        if family.samples.iter().any(|x| x.timestamp.is_some()) {
+            anyhow::bail!(
+                "{} contains unsupported client-side timestamps",
+                family.name
+            );
+        }
+    }
+    Ok(families)
+}
+
+/// Merges families of a file into `merged`, unless they conflict with
+/// families or series of previous files, or with families of syswatch.
+fn merge(
+    merged: &mut Vec<MetricFamily>,
+    series: &mut HashSet<String>,
+    reserved: &[MetricFamily],
+    families: &[MetricFamily],
+) -> Result<()> {
+    let mut new_series = HashSet::new();
+    for family in families.iter() {
+        let collides = |x: &MetricFamily| {
+            x.name == family.name || family.samples.iter().any(|sample| x.owns(&sample.name))
+        };
+        if reserved.iter().any(collides)
+            || RESERVED_PREFIXES.iter().any(|x| family.name.starts_with(x))
+        {
+            anyhow::bail!("{} conflicts with a family of syswatch", family.name);
+        }
+        if let Some(existing) = merged.iter().find(|x| x.name == family.name) {
+            if existing.metric_type != family.metric_type {
+                anyhow::bail!("type of {} conflicts with another file", family.name);
+            }
+        }
+        for sample in family.samples.iter() {
+            let mut labels = sample.labels.clone();
+            labels.sort();
+            let key = format!("{}{:?}", sample.name, labels);
+            if series.contains(&key) || !new_series.insert(key) {
+                anyhow::bail!("duplicate series of {}", sample.name);
+            }
+        }
+    }
+
+    series.extend(new_series);
+    for family in families.iter() {
+        match merged.iter_mut().find(|x| x.name == family.name) {
+            Some(existing) => existing.samples.extend(family.samples.iter().cloned()),
+            None => merged.push(family.clone()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::FileLabel;
+
+    /// A directory of textfiles, removed when dropped
+    struct Directory(PathBuf);
+
+    impl Directory {
+        fn new(name: &str, files: &[(&str, &str)]) -> Directory {
+            let path = std::env::temp_dir().join(format!(
+                "syswatch-textfile-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            for (file, content) in files {
+                std::fs::write(path.join(file), content).unwrap();
+            }
+            Directory(path)
+        }
+    }
+
+    impl Drop for Directory {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn collect(directory: &Directory) -> (Vec<MetricFamily>, TextfileStatus) {
+        let reserved = exposition::parse(
+            "# HELP node_nvidia_driver_status NVML is functional\n\
+             # TYPE node_nvidia_driver_status gauge\n\
+             # HELP nvml_reinit Reinitializations\n\
+             # TYPE nvml_reinit counter\n",
+        )
+        .unwrap();
+        let config = TextfileConfig {
+            directory: directory.0.clone(),
+        };
+        let status = TextfileStatus::default();
+        let families = TextfileCollector::new(&config, reserved).collect(&status);
+        (families, status)
+    }
+
+    fn parse_error(status: &TextfileStatus, file: &str) -> i64 {
+        let label = FileLabel {
+            file: file.to_string(),
+        };
+        status.parse_error.get_or_create(&label).get()
+    }
+
+    #[test]
+    fn good_files_are_merged() {
+        let directory = Directory::new(
+            "good",
+            &[
+                (
+                    "a.prom",
+                    "# TYPE backup_age_seconds gauge\nbackup_age_seconds{job=\"db\"} 60\n",
+                ),
+                (
+                    "b.prom",
+                    "# TYPE backup_age_seconds gauge\nbackup_age_seconds{job=\"web\"} 30\n",
+                ),
+                ("c.txt", "ignored 1\n"),
+            ],
+        );
+        let (families, status) = collect(&directory);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].samples.len(), 2);
+        assert_eq!(parse_error(&status, "a.prom"), 0);
+        assert_eq!(parse_error(&status, "b.prom"), 0);
+        assert_eq!(status.directory_error.get(), 0);
+    }
+
+    #[test]
+    fn bad_file_is_left_out() {
+        let directory = Directory::new(
+            "bad",
+            &[
+                ("a.prom", "backup_age_seconds{job=\"db\" 60\n"),
+                ("b.prom", "backup_ok 1\n"),
+            ],
+        );
+        let (families, status) = collect(&directory);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name, "backup_ok");
+        assert_eq!(parse_error(&status, "a.prom"), 1);
+        assert_eq!(parse_error(&status, "b.prom"), 0);
+    }
+
+    #[test]
+    fn type_conflict_is_rejected() {
+        let directory = Directory::new(
+            "conflict",
+            &[
+                ("a.prom", "# TYPE jobs gauge\njobs{queue=\"a\"} 1\n"),
+                ("b.prom", "# TYPE jobs counter\njobs{queue=\"b\"} 1\n"),
+            ],
+        );
+        let (families, status) = collect(&directory);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].samples.len(), 1);
+        assert_eq!(parse_error(&status, "a.prom"), 0);
+        assert_eq!(parse_error(&status, "b.prom"), 1);
+    }
+
+    #[test]
+    fn syswatch_families_are_rejected() {
+        let directory = Directory::new(
+            "reserved",
+            &[
+                (
+                    "a.prom",
+                    "# TYPE node_nvidia_driver_status gauge\nnode_nvidia_driver_status 1\n",
+                ),
+                ("b.prom", "nvml_reinit_total 3\n"),
+                ("c.prom", "node_nvidia_custom 1\n"),
+                ("d.prom", "node_custom 1\n"),
+            ],
+        );
+        let (families, status) = collect(&directory);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name, "node_custom");
+        assert_eq!(parse_error(&status, "a.prom"), 1);
+        assert_eq!(parse_error(&status, "b.prom"), 1);
+        assert_eq!(parse_error(&status, "c.prom"), 1);
+    }
+
+    #[test]
+    fn removed_files_leave_the_status() {
+        let directory = Directory::new("removed", &[("a.prom", "backup_ok 1\n")]);
+        let config = TextfileConfig {
+            directory: directory.0.clone(),
+        };
+        let status = TextfileStatus::default();
+        let collector = TextfileCollector::new(&config, Vec::new());
+        collector.collect(&status);
+        std::fs::remove_file(directory.0.join("a.prom")).unwrap();
+        collector.collect(&status);
+        let mut text = String::new();
+        let mut registry = prometheus_client::registry::Registry::default();
+        registry.register("parse_error", "", status.parse_error.clone());
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        assert!(!text.contains("a.prom"));
+    }
+}
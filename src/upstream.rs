@@ -0,0 +1,169 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use anyhow::{Context, Result};
+use awc::Client;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// An upstream exporter reachable over TCP (`http://host:port`) or a Unix
+/// domain socket (`unix:///path/to/socket`)
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    Http(String),
+    Unix(PathBuf),
+}
+
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+impl Upstream {
+    pub fn parse(url: &str) -> Result<Upstream> {
+        if let Some(path) = url.strip_prefix("unix://") {
+            if !path.starts_with('/') {
+                anyhow::bail!("Unix socket path of upstream {} should be absolute", url);
+            }
+            return Ok(Upstream::Unix(PathBuf::from(path)));
+        }
+        if !url.starts_with("http://") {
+            anyhow::bail!("Unsupported upstream {}", url);
+        }
+        let _uri: actix_web::http::Uri = url
+            .parse()
+            .with_context(|| format!("Parsing upstream {}", url))?;
+        Ok(Upstream::Http(url.trim_end_matches('/').to_string()))
+    }
+
+    /// Fetches a page of the upstream. The timeout bounds the request, as the
+    /// default of the client is shorter than most scrape timeouts.
+    pub async fn get(
+        &self,
+        client: &Client,
+        path: &str,
+        timeout: Duration,
+    ) -> Result<UpstreamResponse> {
+        match self {
+            Upstream::Http(base) => {
+                let mut response = client
+                    .get(format!("{}/{}", base, path))
+                    .timeout(timeout)
+                    .send()
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{}", e))?;
+                let content_type = response
+                    .headers()
+                    .get(actix_web::http::header::CONTENT_TYPE)
+                    .and_then(|x| x.to_str().ok())
+                    .map(|x| x.to_string());
+                let body = response
+                    .body()
+                    .await
+                    .with_context(|| "Reading upstream response")?;
+                Ok(UpstreamResponse {
+                    status: response.status(),
+                    content_type,
+                    body,
+                })
+            }
+            Upstream::Unix(socket) => tokio::time::timeout(timeout, get_unix(socket, path))
+                .await
+                .with_context(|| format!("Timed out reading {}", socket.to_string_lossy()))?,
+        }
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::Http(base) => write!(f, "{}", base),
+            Upstream::Unix(socket) => write!(f, "unix://{}", socket.to_string_lossy()),
+        }
+    }
+}
+
+/// Sends a plain HTTP/1.0 request over a Unix domain socket, so that the
+/// server closes the connection after the response.
+async fn get_unix(socket: &Path, path: &str) -> Result<UpstreamResponse> {
+    if path.bytes().any(|x| x.is_ascii_control() || x == b' ') {
+        anyhow::bail!("Invalid upstream path {:?}", path);
+    }
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Connecting to {}", socket.to_string_lossy()))?;
+    let request = format!(
+        "GET /{} HTTP/1.0\r\nHost: localhost\r\nUser-Agent: syswatch/{}\r\n\r\n",
+        path,
+        env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let head_end = response
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .with_context(|| "Malformed upstream response")?;
+    let head = std::str::from_utf8(&response[..head_end])?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_ascii_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .with_context(|| "Malformed upstream status line")?;
+
+    let mut content_type = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let body = &response[head_end + 4..];
+    let body = if chunked {
+        decode_chunked(body)?
+    } else {
+        body.to_vec()
+    };
+    Ok(UpstreamResponse {
+        status,
+        content_type,
+        body: body.into(),
+    })
+}
+
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(data.len());
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|x| x == b"\r\n")
+            .with_context(|| "Malformed chunk")?;
+        let size = std::str::from_utf8(&data[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).with_context(|| "Malformed chunk size")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size + 2 {
+            anyhow::bail!("Truncated chunk");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
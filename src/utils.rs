@@ -1,53 +1,259 @@
 use actix_web::http::StatusCode;
-use std::collections::HashMap;
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use users::os::unix::UserExt;
-use users::{uid_t, User};
-
-fn get_users() -> (Vec<User>, Vec<User>) {
-    let linux_logindefs = fs::read_to_string("/etc/login.defs")
-        .expect("Cannot find /etc/login.defs. Your linux may be corrputed.");
-    let linux_logindefs = whitespace_conf::parse(&linux_logindefs);
-    let uid_min = linux_logindefs
-        .get("UID_MIN")
-        .expect("Cannot get UID_MIN")
-        .parse::<u32>()
-        .unwrap();
-    let uid_max = linux_logindefs
-        .get("UID_MAX")
-        .expect("Cannot get UID_MAX")
-        .parse::<u32>()
-        .unwrap();
-
-    let nologin_path = Path::new("/sbin/nologin");
-
-    // Critical section
-    static MUTEX: Mutex<bool> = Mutex::new(false);
-    {
-        let mut _data = MUTEX.lock().expect("Failed to get users");
+use std::time::{Duration, Instant};
+use users::os::unix::{GroupExt, UserExt};
+use users::{gid_t, uid_t, User};
 
-        let users = unsafe { users::all_users() };
-        users.into_iter().partition(|user| {
-            (uid_min..uid_max).contains(&user.uid()) && user.shell() != nologin_path
-        })
+/// Groups of a user are looked up again after this delay
+const GROUP_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UserPolicyConfig {
+    /// UID_MIN and UID_MAX are read from this file when `uid_ranges` is empty
+    pub login_defs: PathBuf,
+    /// Inclusive UID ranges of regular users
+    pub uid_ranges: Vec<[uid_t; 2]>,
+    /// Used when `login_defs` is missing or lacks UID_MIN or UID_MAX
+    pub fallback_uid_range: [uid_t; 2],
+    /// Users with one of these shells cannot log in and are hidden
+    pub denied_shells: Vec<PathBuf>,
+    /// Always shown
+    pub allow_users: Vec<String>,
+    /// Always hidden
+    pub deny_users: Vec<String>,
+    /// Members are shown, unless denied by name
+    pub allow_groups: Vec<String>,
+    /// Members are hidden, unless allowed by name
+    pub deny_groups: Vec<String>,
+}
+
+impl Default for UserPolicyConfig {
+    fn default() -> Self {
+        UserPolicyConfig {
+            login_defs: PathBuf::from("/etc/login.defs"),
+            uid_ranges: Vec::new(),
+            fallback_uid_range: [1000, 60000],
+            denied_shells: [
+                "/sbin/nologin",
+                "/usr/sbin/nologin",
+                "/bin/false",
+                "/usr/bin/false",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+            allow_users: Vec::new(),
+            deny_users: Vec::new(),
+            allow_groups: Vec::new(),
+            deny_groups: Vec::new(),
+        }
     }
 }
 
-pub fn get_users_map() -> (HashMap<uid_t, User>, HashMap<uid_t, User>) {
-    let mut known_user_map: HashMap<uid_t, User> = HashMap::new();
-    let mut blocked_user_map = HashMap::new();
+/// Tells regular users, shown in the per-user metrics, from system accounts
+#[derive(Clone, Debug)]
+pub struct UserPolicy {
+    uid_ranges: Vec<RangeInclusive<uid_t>>,
+    denied_shells: HashSet<PathBuf>,
+    allow_users: HashSet<String>,
+    deny_users: HashSet<String>,
+    allow_groups: HashSet<String>,
+    deny_groups: HashSet<String>,
+}
+
+impl UserPolicy {
+    /// Resolves the UID ranges. A missing or incomplete login.defs is
+    /// reported and the fallback range used instead.
+    pub fn new(config: &UserPolicyConfig) -> UserPolicy {
+        let mut uid_ranges = config.uid_ranges.clone();
+        if uid_ranges.is_empty() {
+            let range = read_login_defs(&config.login_defs).unwrap_or_else(|e| {
+                eprintln!(
+                    "{:#}, regular users have UIDs {} to {}",
+                    e, config.fallback_uid_range[0], config.fallback_uid_range[1]
+                );
+                config.fallback_uid_range
+            });
+            uid_ranges.push(range);
+        }
+        for group in config.allow_groups.iter().chain(config.deny_groups.iter()) {
+            if users::get_group_by_name(group).is_none() {
+                eprintln!("Cannot find group {} of the user policy, ignored", group);
+            }
+        }
+        UserPolicy {
+            uid_ranges: uid_ranges.iter().map(|[min, max]| *min..=*max).collect(),
+            denied_shells: config.denied_shells.iter().cloned().collect(),
+            allow_users: config.allow_users.iter().cloned().collect(),
+            deny_users: config.deny_users.iter().cloned().collect(),
+            allow_groups: config.allow_groups.iter().cloned().collect(),
+            deny_groups: config.deny_groups.iter().cloned().collect(),
+        }
+    }
 
-    let (known_users, blocked_users) = get_users();
-    for user in known_users {
-        known_user_map.entry(user.uid()).or_insert(user);
+    /// Splits the users of the system into regular users and hidden ones
+    pub fn get_users_map(&self) -> (HashMap<uid_t, User>, HashMap<uid_t, User>) {
+        let groups = resolve_groups(self.allow_groups.iter().chain(self.deny_groups.iter()));
+        let mut known_user_map: HashMap<uid_t, User> = HashMap::new();
+        let mut blocked_user_map = HashMap::new();
+        for user in all_users() {
+            let name = user.name().to_string_lossy();
+            let known = self.is_known(&name, user.uid(), user.shell(), &member_of(&user, &groups));
+            let map = if known {
+                &mut known_user_map
+            } else {
+                &mut blocked_user_map
+            };
+            map.entry(user.uid()).or_insert(user);
+        }
+        (known_user_map, blocked_user_map)
     }
-    for user in blocked_users {
-        blocked_user_map.entry(user.uid()).or_insert(user);
+
+    /// Tells whether a user is a regular one, given the policy groups they
+    /// belong to. Users named in a list come first, then groups, then the UID
+    /// and shell.
+    fn is_known(&self, name: &str, uid: uid_t, shell: &Path, groups: &[&str]) -> bool {
+        if self.deny_users.contains(name) {
+            false
+        } else if self.allow_users.contains(name) {
+            true
+        } else if groups.iter().any(|x| self.deny_groups.contains(*x)) {
+            false
+        } else if groups.iter().any(|x| self.allow_groups.contains(*x)) {
+            true
+        } else {
+            self.uid_ranges.iter().any(|x| x.contains(&uid)) && !self.denied_shells.contains(shell)
+        }
     }
+}
+
+fn read_login_defs(path: &Path) -> anyhow::Result<[uid_t; 2]> {
+    let path_name = path.to_string_lossy();
+    let content = fs::read_to_string(path).with_context(|| format!("Cannot read {}", path_name))?;
+    let login_defs = whitespace_conf::parse(&content);
+    let value = |key: &str| -> anyhow::Result<uid_t> {
+        login_defs
+            .get(key)
+            .with_context(|| format!("{} lacks {}", path_name, key))?
+            .parse()
+            .with_context(|| format!("{} has an invalid {}", path_name, key))
+    };
+    Ok([value("UID_MIN")?, value("UID_MAX")?])
+}
+
+fn all_users() -> Vec<User> {
+    // The passwd enumeration of libc is not thread safe
+    static MUTEX: Mutex<()> = Mutex::new(());
+    let _guard = MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+    unsafe { users::all_users() }.collect()
+}
+
+/// Names, gids and member names of the groups, unknown groups are skipped
+fn resolve_groups<'a>(
+    names: impl Iterator<Item = &'a String>,
+) -> Vec<(&'a str, gid_t, HashSet<String>)> {
+    names
+        .filter_map(|name| {
+            let group = users::get_group_by_name(name)?;
+            let members = group
+                .members()
+                .iter()
+                .map(|x| x.to_string_lossy().into_owned())
+                .collect();
+            Some((name.as_str(), group.gid(), members))
+        })
+        .collect()
+}
 
-    (known_user_map, blocked_user_map)
+/// Names of the groups which the user belongs to, as primary group or member
+fn member_of<'a>(user: &User, groups: &[(&'a str, gid_t, HashSet<String>)]) -> Vec<&'a str> {
+    let name = user.name().to_string_lossy();
+    groups
+        .iter()
+        .filter(|(_, gid, members)| {
+            user.primary_group_id() == *gid || members.contains(name.as_ref())
+        })
+        .map(|(group, _, _)| *group)
+        .collect()
+}
+
+/// Names of the Unix groups of a user, including the primary one. Unknown
+/// users have none.
+pub fn user_groups(user_name: &str) -> Vec<String> {
+    let Some(user) = users::get_user_by_name(user_name) else {
+        return Vec::new();
+    };
+    users::get_user_groups(user_name, user.primary_group_id())
+        .unwrap_or_default()
+        .iter()
+        .map(|group| group.name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Groups of the users, looked up again once they are older than
+/// `GROUP_CACHE_TTL`
+pub struct GroupCache {
+    lookup: fn(&str) -> Vec<String>,
+    groups: HashMap<String, (Instant, Vec<String>)>,
+}
+
+impl GroupCache {
+    pub fn new(lookup: fn(&str) -> Vec<String>) -> GroupCache {
+        GroupCache {
+            lookup,
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, user_name: &str) -> &[String] {
+        let lookup = self.lookup;
+        let (fetched, groups) = self
+            .groups
+            .entry(user_name.to_string())
+            .or_insert_with(|| (Instant::now(), lookup(user_name)));
+        if fetched.elapsed() > GROUP_CACHE_TTL {
+            *fetched = Instant::now();
+            *groups = lookup(user_name);
+        }
+        groups
+    }
+}
+
+/// Resolves `user[:group]`, given as names or numeric ids, into a uid and gid
+pub fn parse_owner(owner: &str) -> anyhow::Result<(Option<uid_t>, Option<gid_t>)> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+
+    let uid = if user.is_empty() {
+        None
+    } else if let Ok(uid) = user.parse::<uid_t>() {
+        Some(uid)
+    } else {
+        let user = users::get_user_by_name(user)
+            .ok_or_else(|| anyhow::anyhow!("Cannot find user {}", user))?;
+        Some(user.uid())
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(group) => match group.parse::<gid_t>() {
+            Ok(gid) => Some(gid),
+            Err(_) => {
+                let group = users::get_group_by_name(group)
+                    .ok_or_else(|| anyhow::anyhow!("Cannot find group {}", group))?;
+                Some(group.gid())
+            }
+        },
+    };
+    Ok((uid, gid))
 }
 
 pub trait IntoHttpError<T> {
@@ -80,3 +286,123 @@ impl<T, E: std::fmt::Debug> IntoHttpError<T> for core::result::Result<T, E> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(config: &str) -> UserPolicy {
+        let config: UserPolicyConfig = toml::from_str(config).unwrap();
+        UserPolicy::new(&config)
+    }
+
+    fn known(policy: &UserPolicy, name: &str, uid: uid_t, shell: &str, groups: &[&str]) -> bool {
+        policy.is_known(name, uid, Path::new(shell), groups)
+    }
+
+    #[test]
+    fn uid_ranges_are_inclusive() {
+        let policy = policy("uid_ranges = [[1000, 1999], [5000, 5000]]\n");
+        assert!(!known(&policy, "a", 999, "/bin/bash", &[]));
+        assert!(known(&policy, "a", 1000, "/bin/bash", &[]));
+        assert!(known(&policy, "a", 1999, "/bin/bash", &[]));
+        assert!(!known(&policy, "a", 2000, "/bin/bash", &[]));
+        assert!(known(&policy, "a", 5000, "/bin/bash", &[]));
+    }
+
+    #[test]
+    fn shells_which_cannot_log_in_are_denied() {
+        let policy = policy("uid_ranges = [[1000, 60000]]\n");
+        assert!(!known(&policy, "a", 1000, "/usr/sbin/nologin", &[]));
+        assert!(!known(&policy, "a", 1000, "/bin/false", &[]));
+        assert!(known(&policy, "a", 1000, "/bin/sh", &[]));
+    }
+
+    #[test]
+    fn names_come_before_groups_and_groups_before_uids() {
+        let policy = policy(
+            "uid_ranges = [[1000, 60000]]\nallow_users = [\"svc\", \"both\"]\n\
+             deny_users = [\"alice\", \"both\"]\nallow_groups = [\"gpu\", \"mixed\"]\n\
+             deny_groups = [\"interns\", \"mixed\"]\n",
+        );
+        // Denied names win over everything
+        assert!(!known(&policy, "alice", 1000, "/bin/bash", &["gpu"]));
+        assert!(!known(&policy, "both", 1000, "/bin/bash", &[]));
+        // Allowed names win over groups, UIDs and shells
+        assert!(known(
+            &policy,
+            "svc",
+            100,
+            "/usr/sbin/nologin",
+            &["interns"]
+        ));
+        // Denied groups win over allowed groups and UIDs
+        assert!(!known(&policy, "bob", 1000, "/bin/bash", &["interns"]));
+        assert!(!known(&policy, "bob", 1000, "/bin/bash", &["mixed"]));
+        assert!(!known(
+            &policy,
+            "bob",
+            1000,
+            "/bin/bash",
+            &["gpu", "interns"]
+        ));
+        // Allowed groups win over UIDs and shells
+        assert!(known(&policy, "bob", 100, "/bin/false", &["gpu"]));
+        // Other groups change nothing
+        assert!(!known(&policy, "bob", 100, "/bin/bash", &["users"]));
+        assert!(known(&policy, "bob", 1000, "/bin/bash", &["users"]));
+    }
+
+    fn login_defs(name: &str, content: Option<&str>) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "syswatch-login-defs-{}-{}",
+            std::process::id(),
+            name
+        ));
+        if let Some(content) = content {
+            fs::write(&path, content).unwrap();
+        }
+        path
+    }
+
+    fn policy_uid_ranges(path: &Path) -> Vec<RangeInclusive<uid_t>> {
+        let config = UserPolicyConfig {
+            login_defs: path.to_path_buf(),
+            fallback_uid_range: [1000, 2000],
+            ..Default::default()
+        };
+        UserPolicy::new(&config).uid_ranges
+    }
+
+    #[test]
+    fn missing_login_defs_falls_back() {
+        let path = login_defs("missing", None);
+        let error = read_login_defs(&path).unwrap_err();
+        assert!(error.to_string().starts_with("Cannot read"));
+        assert_eq!(policy_uid_ranges(&path), [1000..=2000]);
+    }
+
+    #[test]
+    fn incomplete_login_defs_falls_back() {
+        let path = login_defs("incomplete", Some("UID_MIN 500\nGID_MIN 500\n"));
+        let error = read_login_defs(&path).unwrap_err();
+        assert!(error.to_string().ends_with("lacks UID_MAX"));
+        assert_eq!(policy_uid_ranges(&path), [1000..=2000]);
+        fs::write(&path, "UID_MIN 500\nUID_MAX many\n").unwrap();
+        let error = read_login_defs(&path).unwrap_err();
+        assert!(error.to_string().ends_with("has an invalid UID_MAX"));
+        assert_eq!(policy_uid_ranges(&path), [1000..=2000]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn login_defs_gives_the_uid_range() {
+        let path = login_defs(
+            "valid",
+            Some("# Comment\nMAIL_DIR /var/mail\nUID_MIN 500\nUID_MAX 30000\n"),
+        );
+        assert_eq!(read_login_defs(&path).unwrap(), [500, 30000]);
+        assert_eq!(policy_uid_ranges(&path), [500..=30000]);
+        fs::remove_file(&path).unwrap();
+    }
+}
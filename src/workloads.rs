@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::nvml_metrics::NvmlProcess;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WorkloadConfig {
+    /// Where `<pid>/cgroup` files are read, a fake tree may be used for testing
+    pub procfs: PathBuf,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        WorkloadConfig {
+            procfs: PathBuf::from("/proc"),
+        }
+    }
+}
+
+/// The container, job or service a process belongs to
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Workload {
+    /// `kubernetes`, `docker`, `slurm` or `systemd`
+    pub workload_type: &'static str,
+    /// Pod UID, container ID, job ID (or `uid_<uid>` outside a job) or unit name
+    pub workload_id: String,
+}
+
+/// GPU memory used by a workload on a device
+pub struct WorkloadUsage {
+    pub minor_number: u32,
+    pub workload: Workload,
+    pub used_gpu_memory: u64,
+}
+
+/// Classifies GPU processes by their cgroup
+pub struct WorkloadClassifier {
+    procfs: PathBuf,
+}
+
+impl WorkloadClassifier {
+    pub fn new(config: &WorkloadConfig) -> WorkloadClassifier {
+        WorkloadClassifier {
+            procfs: config.procfs.clone(),
+        }
+    }
+
+    /// Sums the memory of the processes by device and workload. Processes
+    /// which exited or belong to no workload are left out.
+    pub fn usage(&self, processes: &[NvmlProcess]) -> Vec<WorkloadUsage> {
+        let mut usage: HashMap<(u32, Workload), u64> = HashMap::new();
+        for process in processes.iter() {
+            let path = self.procfs.join(process.pid.to_string()).join("cgroup");
+            let Ok(cgroup) = std::fs::read_to_string(path) else {
+                continue;
+            };
+            let Some(workload) = classify(&cgroup) else {
+                continue;
+            };
+            *usage.entry((process.minor_number, workload)).or_default() +=
+                process.used_gpu_memory.unwrap_or(0);
+        }
+        usage
+            .into_iter()
+            .map(
+                |((minor_number, workload), used_gpu_memory)| WorkloadUsage {
+                    minor_number,
+                    workload,
+                    used_gpu_memory,
+                },
+            )
+            .collect()
+    }
+}
+
+/// Classifies the content of `/proc/<pid>/cgroup`, with one
+/// `id:controllers:path` line per hierarchy on cgroup v1 and a single line
+/// on cgroup v2. The most specific workload over all paths wins, since pods
+/// run in containers and containers in systemd scopes.
+fn classify(cgroup: &str) -> Option<Workload> {
+    let paths: Vec<&str> = cgroup
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .collect();
+    let classifiers: [fn(&str) -> Option<Workload>; 4] =
+        [kubernetes_pod, docker_container, slurm_job, systemd_unit];
+    classifiers
+        .iter()
+        .find_map(|classify| paths.iter().find_map(|path| classify(path)))
+}
+
+/// `/kubepods/burstable/pod<uid>/...` with the cgroupfs driver, or
+/// `.../kubepods-burstable-pod<uid>.slice/...` with underscores for dashes
+/// with the systemd driver
+fn kubernetes_pod(path: &str) -> Option<Workload> {
+    if !path.contains("kubepods") {
+        return None;
+    }
+    path.split('/').find_map(|component| {
+        let component = component.strip_suffix(".slice").unwrap_or(component);
+        let (_, uid) = component.rsplit_once("pod")?;
+        let uid = uid.replace('_', "-");
+        if !is_uuid(&uid) {
+            return None;
+        }
+        Some(Workload {
+            workload_type: "kubernetes",
+            workload_id: uid,
+        })
+    })
+}
+
+/// `/docker/<id>` with the cgroupfs driver, `docker-<id>.scope` with the
+/// systemd driver
+fn docker_container(path: &str) -> Option<Workload> {
+    let mut components = path.split('/').peekable();
+    while let Some(component) = components.next() {
+        let id = match component.strip_prefix("docker-") {
+            Some(scope) => scope.strip_suffix(".scope"),
+            None if component == "docker" => components.peek().copied(),
+            None => None,
+        };
+        if let Some(id) = id.filter(|x| is_container_id(x)) {
+            return Some(Workload {
+                workload_type: "docker",
+                workload_id: id.to_string(),
+            });
+        }
+    }
+    None
+}
+
+/// `.../uid_<uid>/job_<id>/step_<step>/...` below a `slurm` or
+/// `slurmstepd.scope` cgroup, as created by the Slurm cgroup plugins
+fn slurm_job(path: &str) -> Option<Workload> {
+    if !path.contains("slurm") {
+        return None;
+    }
+    let numbered = |prefix: &str| {
+        path.split('/').find_map(|component| {
+            let id = component.strip_prefix(prefix)?;
+            id.parse::<u64>().ok().map(|_| id.to_string())
+        })
+    };
+    let workload_id = match numbered("job_") {
+        Some(job) => job,
+        None => format!("uid_{}", numbered("uid_")?),
+    };
+    Some(Workload {
+        workload_type: "slurm",
+        workload_id,
+    })
+}
+
+/// The innermost `.service` or `.scope` unit. Units of user sessions are
+/// left out, as each login would create new series.
+fn systemd_unit(path: &str) -> Option<Workload> {
+    if path.split('/').any(|component| component == "user.slice") {
+        return None;
+    }
+    path.rsplit('/')
+        .find(|component| component.ends_with(".service") || component.ends_with(".scope"))
+        .map(|unit| Workload {
+            workload_type: "systemd",
+            workload_id: unit.to_string(),
+        })
+}
+
+fn is_container_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|x| x.is_ascii_hexdigit())
+}
+
+fn is_uuid(id: &str) -> bool {
+    id.len() == 36
+        && id.bytes().enumerate().all(|(i, x)| {
+            matches!(i, 8 | 13 | 18 | 23) == (x == b'-') && (x == b'-' || x.is_ascii_hexdigit())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, used_gpu_memory: u64) -> NvmlProcess {
+        NvmlProcess {
+            pid,
+            minor_number: 0,
+            process_type: "compute",
+            user_name: None,
+            command: String::new(),
+            start_time: None,
+            used_gpu_memory: Some(used_gpu_memory),
+            utilization: None,
+            rss: None,
+        }
+    }
+
+    fn workload(pid: u32) -> Option<Workload> {
+        let classifier = WorkloadClassifier::new(&WorkloadConfig {
+            procfs: PathBuf::from("scenarios/procfs"),
+        });
+        let usage = classifier.usage(&[process(pid, 100)]);
+        assert!(usage.len() <= 1);
+        usage.into_iter().next().map(|x| x.workload)
+    }
+
+    fn expected(workload_type: &'static str, workload_id: &str) -> Option<Workload> {
+        Some(Workload {
+            workload_type,
+            workload_id: workload_id.to_string(),
+        })
+    }
+
+    #[test]
+    fn kubernetes_pod_wins_over_its_container() {
+        assert_eq!(
+            workload(1002),
+            expected("kubernetes", "0b7c8d2e-4f1a-4c3b-9a8e-1d2c3b4a5f6e")
+        );
+    }
+
+    #[test]
+    fn docker_container_is_classified() {
+        assert_eq!(
+            workload(4242),
+            expected(
+                "docker",
+                "3f4e5d6c7b8a99887766554433221100ffeeddccbbaa99887766554433221100"
+            )
+        );
+    }
+
+    #[test]
+    fn slurm_job_wins_over_the_service_of_another_hierarchy() {
+        assert_eq!(workload(1001), expected("slurm", "48213"));
+    }
+
+    #[test]
+    fn systemd_service_is_classified() {
+        assert_eq!(
+            workload(1005),
+            expected("systemd", "triton-inference.service")
+        );
+    }
+
+    #[test]
+    fn sessions_and_root_cgroup_are_left_out() {
+        assert_eq!(workload(1003), None);
+        assert_eq!(workload(1004), None);
+        // Exited
+        assert_eq!(workload(1), None);
+    }
+
+    #[test]
+    fn memory_is_summed_by_workload() {
+        let classifier = WorkloadClassifier::new(&WorkloadConfig {
+            procfs: PathBuf::from("scenarios/procfs"),
+        });
+        let usage = classifier.usage(&[process(1001, 100), process(1001, 50), process(1003, 10)]);
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].used_gpu_memory, 150);
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{Metrics, XidStatus};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+const FIRST_SAMPLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct XidConfig {
+    /// Kernel log to follow, a plain file is polled for appended lines
+    pub path: PathBuf,
+    /// Number of events kept for the JSON endpoint
+    pub history: usize,
+}
+
+impl Default for XidConfig {
+    fn default() -> Self {
+        XidConfig {
+            path: PathBuf::from("/dev/kmsg"),
+            history: 100,
+        }
+    }
+}
+
+/// An Xid error reported by the NVIDIA driver
+#[derive(Serialize, Clone, Debug)]
+pub struct XidEvent {
+    /// Unix time in seconds
+    pub timestamp: f64,
+    /// Unset when no known device has the PCI address
+    pub minor_number: Option<u32>,
+    pub pci_bus_id: String,
+    pub xid: u32,
+    pub message: String,
+}
+
+/// Where the reading stopped, so that a reopened log is not counted again
+#[derive(Default, Debug)]
+struct Cursor {
+    /// Bytes read from a plain file
+    offset: u64,
+    /// Sequence number of the last `/dev/kmsg` record, whose buffer is
+    /// replayed on each open
+    sequence: Option<u64>,
+}
+
+/// Follows the kernel log on a dedicated thread and records the Xid errors
+pub struct XidCollector {
+    path: PathBuf,
+    history: usize,
+    pattern: Regex,
+    boot_time: Option<f64>,
+    metrics: Arc<Metrics>,
+    status: Arc<XidStatus>,
+}
+
+impl XidCollector {
+    pub fn new(config: &XidConfig, metrics: Arc<Metrics>, status: Arc<XidStatus>) -> XidCollector {
+        XidCollector {
+            path: config.path.clone(),
+            history: config.history,
+            pattern: Regex::new(r"NVRM: Xid \(PCI:([0-9A-Fa-f:.]+)\): (\d+)(?:, (.*))?").unwrap(),
+            boot_time: procfs::boot_time_secs().ok().map(|x| x as f64),
+            metrics,
+            status,
+        }
+    }
+
+    pub fn spawn(self) -> std::io::Result<()> {
+        std::thread::Builder::new()
+            .name("xid".to_string())
+            .spawn(move || self.run())?;
+        Ok(())
+    }
+
+    fn run(self) {
+        // The kernel replays its buffer first, wait for the devices to map it
+        let started = Instant::now();
+        while self.metrics.devices().is_empty() && started.elapsed() < FIRST_SAMPLE_TIMEOUT {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let mut cursor = Cursor::default();
+        loop {
+            if let Err(e) = self.tail(&mut cursor) {
+                eprintln!("XID collector: {:#}", e);
+            }
+            std::thread::sleep(RETRY_DELAY);
+        }
+    }
+
+    fn tail(&self, cursor: &mut Cursor) -> Result<()> {
+        let mut reader = self.open(cursor)?;
+        loop {
+            self.read(&mut reader, cursor)?;
+            std::thread::sleep(POLL_INTERVAL);
+            let metadata = reader.get_ref().metadata()?;
+            if metadata.is_file() && metadata.len() < cursor.offset {
+                // Truncated, e.g. by log rotation
+                reader.seek(SeekFrom::Start(0))?;
+                cursor.offset = 0;
+            }
+        }
+    }
+
+    /// Opens the log where the previous reading of a plain file stopped
+    fn open(&self, cursor: &mut Cursor) -> Result<BufReader<File>> {
+        let path = self.path.to_string_lossy();
+        let file = File::open(&self.path).with_context(|| format!("Opening {}", path))?;
+        let metadata = file.metadata()?;
+        let mut reader = BufReader::new(file);
+        if !metadata.is_file() || metadata.len() < cursor.offset {
+            cursor.offset = 0;
+        }
+        reader.seek(SeekFrom::Start(cursor.offset))?;
+        Ok(reader)
+    }
+
+    /// Records the Xid errors of the complete lines up to the end of the log
+    fn read(&self, reader: &mut BufReader<File>, cursor: &mut Cursor) -> Result<()> {
+        let mut line = Vec::new();
+        loop {
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                // Records of /dev/kmsg were overwritten before being read
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Reading {}", self.path.display()))
+                }
+            }
+            if line.last() != Some(&b'\n') {
+                // Wait for the rest of a line being written
+                continue;
+            }
+            cursor.offset += line.len() as u64;
+            let line = String::from_utf8_lossy(&std::mem::take(&mut line)).into_owned();
+            if let Some(sequence) = parse_sequence(&line) {
+                if cursor.sequence.is_some_and(|x| sequence <= x) {
+                    continue;
+                }
+                cursor.sequence = Some(sequence);
+            }
+            if let Some(event) = self.parse(&line) {
+                self.status.record(event, self.history);
+            }
+        }
+        // Read again from the start of a partial line
+        if !line.is_empty() {
+            reader.seek_relative(-(line.len() as i64))?;
+        }
+        Ok(())
+    }
+
+    /// Parses a `/dev/kmsg` record or a plain log line. Plain lines carry no
+    /// usable timestamp, so they are stamped with the time they are read.
+    fn parse(&self, line: &str) -> Option<XidEvent> {
+        let line = line.trim_end();
+        let (timestamp, message) = match parse_kmsg(line) {
+            Some((monotonic, message)) => (self.boot_time.map(|x| x + monotonic), message),
+            None => (None, line),
+        };
+        let captures = self.pattern.captures(message)?;
+        let pci_bus_id = captures[1].to_string();
+        let address = parse_pci_address(&pci_bus_id);
+        let minor_number = self
+            .metrics
+            .devices()
+            .into_iter()
+            .find(|device| {
+                address.is_some()
+                    && device.pci_bus_id.as_deref().and_then(parse_pci_address) == address
+            })
+            .map(|device| device.minor_number);
+        Some(XidEvent {
+            timestamp: timestamp.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64()
+            }),
+            minor_number,
+            pci_bus_id,
+            xid: captures[2].parse().ok()?,
+            message: captures
+                .get(3)
+                .map(|x| x.as_str().to_string())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Splits a `priority,sequence,microseconds,flags;message` record into the
+/// seconds since boot and the message
+fn parse_kmsg(line: &str) -> Option<(f64, &str)> {
+    let (header, message) = line.split_once(';')?;
+    let microseconds: u64 = header.split(',').nth(2)?.parse().ok()?;
+    Some((microseconds as f64 / 1e6, message))
+}
+
+/// Sequence number of a `/dev/kmsg` record
+fn parse_sequence(line: &str) -> Option<u64> {
+    let (header, _) = line.split_once(';')?;
+    header.split(',').nth(1)?.parse().ok()
+}
+
+/// Normalizes `0000:3b:00` as printed by the driver and `00000000:3B:00.0` as
+/// reported by NVML into domain, bus and device numbers
+fn parse_pci_address(address: &str) -> Option<(u32, u32, u32)> {
+    let address = address.split('.').next()?;
+    let mut parts = address.rsplitn(3, ':');
+    let device = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let bus = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let domain = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((domain, bus, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collector(path: PathBuf) -> XidCollector {
+        let config = XidConfig { path, history: 10 };
+        let metrics = Arc::new(Metrics::new());
+        XidCollector::new(&config, metrics, Arc::new(XidStatus::default()))
+    }
+
+    #[test]
+    fn reopened_file_is_not_counted_again() {
+        let path = std::env::temp_dir().join(format!("syswatch-xid-{}.log", std::process::id()));
+        std::fs::copy("scenarios/kmsg.log", &path).unwrap();
+        let collector = collector(path.clone());
+        let mut cursor = Cursor::default();
+        for _ in 0..2 {
+            let mut reader = collector.open(&mut cursor).unwrap();
+            collector.read(&mut reader, &mut cursor).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+        let xids: Vec<u32> = collector.status.recent().iter().map(|x| x.xid).collect();
+        assert_eq!(xids, vec![13, 48, 79]);
+    }
+
+    #[test]
+    fn replayed_records_are_skipped() {
+        let collector = collector(PathBuf::from("scenarios/kmsg.log"));
+        let mut cursor = Cursor::default();
+        for _ in 0..2 {
+            // As /dev/kmsg, which replays its buffer from the start
+            let file = File::open("scenarios/kmsg.log").unwrap();
+            collector
+                .read(&mut BufReader::new(file), &mut cursor)
+                .unwrap();
+        }
+        let xids: Vec<u32> = collector.status.recent().iter().map(|x| x.xid).collect();
+        assert_eq!(xids, vec![13, 48, 79]);
+        assert_eq!(cursor.sequence, Some(1733));
+    }
+}